@@ -0,0 +1,96 @@
+//! Benchmarks for the MidiStore queries used while drawing and snapping.
+//!
+//! The draw path only looks at the visible window, so its cost should stay flat no matter how
+//! many events were captured before, even with a note held over the whole history.
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkGroup, BenchmarkId, Criterion, criterion_group, criterion_main};
+use criterion::measurement::WallTime;
+use midly::num::{u4, u7};
+use mucap::midistore::{Bar, ExpressionKind, MidiStore};
+
+const SAMPLE_RATE: f32 = 48000.;
+
+/// Store with `n_events` events of steady 16th notes at 120 BPM, alternating between keys.
+///
+/// Every note bends once, the sustain pedal is held for the first half of every bar. With
+/// `drone`, one note is held from the start to the end of the capture.
+fn filled_store(n_events: usize, drone: bool) -> MidiStore {
+    let mut store = MidiStore::new();
+    store.set_sample_rate(0, SAMPLE_RATE);
+    let step = (SAMPLE_RATE / 8.) as i64;
+    if drone {
+        store.add(0, [0x90, 24, 100]).unwrap();
+    }
+    for idx in 0..(n_events / 2) as i64 {
+        let key = 36 + (idx % 48) as u8;
+        let time = idx * step;
+        if idx % 16 == 0 {
+            store.add(time, [0xB0, 64, 127]).unwrap();
+            store.bars.push_back(Bar {
+                bar_number: (idx / 16) as i32,
+                t: time,
+            });
+        }
+        store.add(time, [0x90, key, 100]).unwrap();
+        store.add_expression(time + 1, None, u4::new(0), u7::new(key), ExpressionKind::Tuning, 0.5);
+        store.add(time + step / 2, [0x80, key, 0]).unwrap();
+        if idx % 16 == 7 {
+            store.add(time + step / 2, [0xB0, 64, 0]).unwrap();
+        }
+    }
+    if drone {
+        store.add((n_events / 2) as i64 * step, [0x80, 24, 0]).unwrap();
+    }
+    store
+}
+
+/// Benchmarks the queries of a frame showing [t0, t1] in seconds.
+fn bench_view(group: &mut BenchmarkGroup<WallTime>, name: &str, store: &MidiStore, (t0, t1): (f32, f32)) {
+    group.bench_with_input(BenchmarkId::new("notes_in_time", name), store, |b, store| {
+        b.iter(|| store.notes_in_time(black_box(t0), black_box(t1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("notes_sounding_in_time", name), store, |b, store| {
+        b.iter(|| store.notes_sounding_in_time(black_box(t0), black_box(t1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("pedals_in_time", name), store, |b, store| {
+        b.iter(|| store.pedals_in_time(black_box(t0), black_box(t1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("expressions_in_time", name), store, |b, store| {
+        b.iter(|| store.expressions_in_time(black_box(t0), black_box(t1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("bars_in_time", name), store, |b, store| {
+        b.iter(|| store.bars_in_time(black_box(t0), black_box(t1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("nearest_bar", name), store, |b, store| {
+        b.iter(|| store.nearest_bar(black_box((t0 + t1) / 2.), black_box(1)))
+    });
+    let (s0, s1) = (store.samples_at(t0 as f64), store.samples_at(t1 as f64));
+    group.bench_with_input(BenchmarkId::new("ghost_bars_in_samples", name), store, |b, store| {
+        let grid = store.ghost_grid();
+        b.iter(|| store.ghost_bars_in_samples(grid, black_box(s0), black_box(s1)).count())
+    });
+    group.bench_with_input(BenchmarkId::new("midi_events_in_samples", name), store, |b, store| {
+        b.iter(|| store.midi_events_in_samples(black_box(s0), black_box(s1)).count())
+    });
+}
+
+fn bench_queries(c: &mut Criterion) {
+    let mut group = c.benchmark_group("draw");
+    for n_events in [100_000, 1_000_000, 5_000_000] {
+        for drone in [false, true] {
+            let store = filled_store(n_events, drone);
+            let (t_start, t_end) = store.time_range().unwrap();
+            let kind = if drone { "drone" } else { "steady" };
+            // A typical view of the last 10 seconds, and one scrolled back into the history
+            bench_view(&mut group, &format!("{}/end/{}", kind, n_events), &store, (t_end - 10., t_end));
+            let middle = (t_start + t_end) / 2.;
+            bench_view(&mut group, &format!("{}/middle/{}", kind, n_events), &store, (middle - 5., middle + 5.));
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_queries);
+criterion_main!(benches);
@@ -1,27 +1,193 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use directories::ProjectDirs;
 use miniserde::{Deserialize, Serialize, json};
 use nih_plug::{debug::nih_log, nih_warn};
 
+use crate::exports::{self, Cleanup, ExportLibrary};
+use crate::host_profile::HostProfile;
+use crate::midistore::{MpeMode, NotePairing, Retention};
+use crate::ui::miditransfer::{
+    ChaseOptions, ClipPadding, ExportOptions, NoteOffQuantize, QuantizeGrid, QuantizeOptions,
+    DEFAULT_PPQN,
+};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub scale_factor: f32,
+    /// Maximum age of the captured history in seconds, 0 keeps everything.
+    pub retention_seconds: f32,
+    /// Maximum memory used by the captured history in megabytes, 0 means no limit.
+    pub retention_megabytes: f32,
+    /// Store the captured MIDI in the DAW project together with the plugin state.
+    pub persist_capture: bool,
+    /// Maximum size of the capture stored in the project in kilobytes.
+    pub persist_capture_kilobytes: f32,
+    /// Journal the capture to disk so it can be recovered after a crash.
+    pub journal_capture: bool,
+    /// How stacked NoteOns on the same key and channel are paired with NoteOffs.
+    pub note_pairing: NotePairing,
+    /// How MPE zones are set up, `Auto` follows the configuration the controller sends.
+    pub mpe_mode: MpeMode,
+    /// Draw notes until the end of their sound instead of key-up, including the sustain pedal.
+    pub show_sounding_length: bool,
+    /// DAW the exported clips are made for, see [`crate::host_profile`]. Profiles other than
+    /// `Custom` override the resolution, tempo map, channel splitting and padding below.
+    pub host_profile: HostProfile,
+    /// Resolution of exported clips in ticks per quarter note.
+    pub export_ppqn: u16,
+    /// Where exported clips end.
+    pub export_padding: ClipPadding,
+    /// Bake the sustain pedal into the note lengths of exported clips.
+    pub export_bake_pedal: bool,
+    /// Export per-note expressions on MPE member channels.
+    pub export_expressions: bool,
+    /// Write the recorded tempo and time signature changes into exported clips.
+    pub export_tempo_map: bool,
+    /// Export a Format 1 file with one track per MIDI channel.
+    pub export_split_channels: bool,
+    /// Move the events of every exported track to channel 1 when splitting channels.
+    pub export_channel_one: bool,
+    /// Start exported clips with the bank and program in effect at the selection start.
+    pub export_chase_programs: bool,
+    /// Start exported clips with the pitch bend in effect at the selection start.
+    pub export_chase_pitch_bend: bool,
+    /// Start exported clips with the pedals (CC64 to CC69) in effect at the selection start.
+    pub export_chase_pedals: bool,
+    /// Start exported clips with the other controllers in effect at the selection start.
+    pub export_chase_controllers: bool,
+    /// Start exported clips with the channel pressure in effect at the selection start.
+    pub export_chase_pressure: bool,
+    /// Grid exported notes are quantized to, `Off` exports them as played.
+    pub export_quantize: QuantizeGrid,
+    /// How far exported notes are moved towards the grid in percent.
+    pub export_quantize_strength: f32,
+    /// How far every second grid point is delayed in percent of half a grid step.
+    pub export_quantize_swing: f32,
+    /// What happens to the NoteOffs of quantized notes.
+    pub export_quantize_note_offs: NoteOffQuantize,
+    /// Folder exported clips are written to, relative to the data directory unless absolute.
+    pub export_folder: String,
+    /// Name of exported clips, see [`crate::exports`] for the placeholders.
+    pub export_file_name: String,
+    /// Maximum age of exported clips in days before they are removed, 0 keeps them.
+    pub export_keep_days: f32,
+    /// Maximum number of exported clips kept, 0 means no limit.
+    pub export_keep_files: u32,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self { scale_factor: 1.0 }
+        Self {
+            scale_factor: 1.0,
+            // Like before retention was configurable, the whole capture is kept by default
+            retention_seconds: 0.,
+            retention_megabytes: 0.,
+            persist_capture: false,
+            persist_capture_kilobytes: 4096.,
+            // The journal keeps writing to disk, so it is only written when asked for
+            journal_capture: false,
+            note_pairing: NotePairing::default(),
+            mpe_mode: MpeMode::default(),
+            show_sounding_length: false,
+            host_profile: HostProfile::default(),
+            export_ppqn: DEFAULT_PPQN,
+            export_padding: ClipPadding::default(),
+            export_bake_pedal: false,
+            export_expressions: false,
+            export_tempo_map: false,
+            export_split_channels: false,
+            export_channel_one: false,
+            export_chase_programs: false,
+            export_chase_pitch_bend: false,
+            export_chase_pedals: false,
+            export_chase_controllers: false,
+            export_chase_pressure: false,
+            export_quantize: QuantizeGrid::default(),
+            export_quantize_strength: 100.,
+            export_quantize_swing: 0.,
+            export_quantize_note_offs: NoteOffQuantize::default(),
+            export_folder: String::from(exports::DEFAULT_FOLDER),
+            export_file_name: String::from(exports::DEFAULT_TEMPLATE),
+            export_keep_days: 7.,
+            export_keep_files: 200,
+        }
+    }
+}
+
+impl Config {
+    /// Retention policy for the MidiStore as configured.
+    pub fn retention(&self) -> Retention {
+        Retention {
+            max_age: (self.retention_seconds > 0.).then_some(self.retention_seconds),
+            max_bytes: (self.retention_megabytes > 0.)
+                .then_some((self.retention_megabytes * 1024. * 1024.) as usize),
+        }
+    }
+
+    /// Options for exporting selections as configured, adjusted to the host profile.
+    pub fn export_options(&self) -> ExportOptions {
+        let mut options = ExportOptions {
+            ppqn: self.export_ppqn,
+            bake_pedal: self.export_bake_pedal,
+            expressions: self.export_expressions,
+            tempo_map: self.export_tempo_map,
+            split_channels: self.export_split_channels,
+            channel_one: self.export_channel_one,
+            chase: ChaseOptions {
+                programs: self.export_chase_programs,
+                pitch_bend: self.export_chase_pitch_bend,
+                pedals: self.export_chase_pedals,
+                controllers: self.export_chase_controllers,
+                pressure: self.export_chase_pressure,
+            },
+            quantize: QuantizeOptions {
+                grid: self.export_quantize,
+                strength: self.export_quantize_strength as f64 / 100.,
+                swing: self.export_quantize_swing as f64 / 100.,
+                note_offs: self.export_quantize_note_offs,
+            },
+            padding: self.export_padding,
+        };
+        self.host_profile.resolve().apply(&mut options);
+        options
+    }
+
+    /// Library exported clips are written to as configured, `None` without a data directory.
+    pub fn export_library(&self) -> Option<ExportLibrary> {
+        let cleanup = Cleanup {
+            max_age: (self.export_keep_days > 0.)
+                .then(|| Duration::from_secs_f32(self.export_keep_days * 24. * 60. * 60.)),
+            max_files: (self.export_keep_files > 0).then_some(self.export_keep_files as usize),
+        };
+        exports::export_dir(&self.export_folder)
+            .map(|dir| ExportLibrary::new(dir, &self.export_file_name, cleanup))
+    }
+
+    /// Size limit for the capture stored in the plugin state, `None` if it is not stored.
+    pub fn persist_capture_limit(&self) -> Option<usize> {
+        self.persist_capture
+            .then_some((self.persist_capture_kilobytes * 1024.) as usize)
     }
 }
 
+fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("matelab", "matelab", "mucap")
+}
+
+/// Directory for data written by the plugin, like capture journals.
+pub fn data_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.data_dir().to_path_buf())
+}
+
 pub struct ConfigStore {
     config_file: PathBuf,
 }
 
 impl ConfigStore {
     pub fn new() -> Self {
-        let cfg_dir = ProjectDirs::from("matelab", "matelab", "mucap")
+        let cfg_dir = project_dirs()
             .expect("Failed to get project directories");
 
         let config_dir = cfg_dir.config_dir();
@@ -35,7 +201,7 @@ impl ConfigStore {
     pub fn get_config(&self) -> Config {
         std::fs::read_to_string(&self.config_file)
             .ok()
-            .and_then(|content| json::from_str(&content).ok())
+            .map(|content| parse_config(&content))
             .unwrap_or_default()
     }
 
@@ -52,3 +218,55 @@ impl ConfigStore {
         }
     }
 }
+
+/// Parses a config file, fields that are missing or invalid keep their default.
+///
+/// Config files written by older versions lack the fields added since, which must not reset
+/// the fields they have.
+fn parse_config(content: &str) -> Config {
+    let Ok(stored) = json::from_str::<json::Object>(content) else {
+        nih_warn!("Failed to parse config, using the defaults");
+        return Config::default();
+    };
+    let Ok(mut merged) = json::from_str::<json::Object>(&json::to_string(&Config::default()))
+    else {
+        return Config::default();
+    };
+    for (key, value) in stored {
+        let default = merged.insert(key.clone(), value);
+        if json::from_str::<Config>(&json::to_string(&merged)).is_ok() {
+            continue;
+        }
+        nih_warn!("Ignoring invalid config value for {}", key);
+        match default {
+            Some(default) => merged.insert(key, default),
+            None => merged.remove(&key),
+        };
+    }
+    json::from_str(&json::to_string(&merged)).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        // A config file from before the first setting was added besides the scale factor
+        let config = parse_config(r#"{"scale_factor":1.5}"#);
+        assert_eq!(config.scale_factor, 1.5);
+        assert_eq!(config.export_folder, exports::DEFAULT_FOLDER);
+        assert_eq!(config.export_quantize, QuantizeGrid::Off);
+
+        // Invalid values keep their default, unknown fields are ignored
+        let config = parse_config(
+            r#"{"scale_factor":2.0,"export_quantize":"Fifth","export_keep_files":-1,"old":true,"persist_capture":true}"#,
+        );
+        assert_eq!(config.scale_factor, 2.0);
+        assert_eq!(config.export_quantize, QuantizeGrid::Off);
+        assert_eq!(config.export_keep_files, 200);
+        assert!(config.persist_capture);
+
+        assert_eq!(parse_config("not json").scale_factor, 1.0);
+    }
+}
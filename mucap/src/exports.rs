@@ -0,0 +1,317 @@
+//! Library of exported clips on disk.
+//!
+//! Every exported selection is written to the export folder, `exports` in the data directory by
+//! default, and stays there, so a DAW that reads a pasted or dropped file late still finds it.
+//! Files are named after a template and old exports are cleaned up by age and count after every
+//! export.
+//!
+//! The export folder can be any folder, also one with MIDI files of the user. Every export is
+//! therefore listed in a manifest in the folder, and the cleanup only ever removes files from
+//! that list.
+//!
+//! Templates can contain these placeholders:
+//!
+//! - `{date}`: date of the export as `YYYY-MM-DD` (UTC)
+//! - `{time}`: time of the export as `HH-MM-SS` (UTC)
+//! - `{tempo}`: tempo at the start of the clip in BPM
+//! - `{bars}`: captured bars covered by the clip as `first-last`
+//!
+//! Placeholders without a value are left out together with the separator before them.
+//!
+//! There is no placeholder for the name of the host track. CLAP and VST3 can tell plugins about
+//! the track they are on, but nih-plug does not pass that on to the plugin.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use nih_plug::{nih_dbg, nih_warn};
+
+use crate::config;
+use crate::journal;
+
+pub const DEFAULT_FOLDER: &str = "exports";
+pub const DEFAULT_TEMPLATE: &str = "mucap_{date}_{time}_{tempo}bpm_{bars}";
+
+const EXPORT_EXTENSION: &str = "mid";
+
+/// Names of the files written to the export folder, one per line.
+const MANIFEST: &str = ".mucap-exports";
+
+/// Resolves the configured export folder, relative folders are placed in the data directory.
+pub fn export_dir(folder: &str) -> Option<PathBuf> {
+    let folder = Path::new(if folder.is_empty() { DEFAULT_FOLDER } else { folder });
+    if folder.is_absolute() {
+        Some(folder.to_path_buf())
+    } else {
+        config::data_dir().map(|dir| dir.join(folder))
+    }
+}
+
+/// What the name of an exported clip is made of.
+#[derive(Clone, Copy, Debug)]
+pub struct ClipInfo {
+    /// Time of the export.
+    pub time: SystemTime,
+    /// Tempo at the start of the clip in BPM.
+    pub tempo: f64,
+    /// Numbers of the first and the last captured bar in the clip.
+    pub bars: Option<(i32, i32)>,
+}
+
+/// Which old exports are removed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cleanup {
+    /// Exports older than this are removed, `None` keeps them regardless of their age.
+    pub max_age: Option<Duration>,
+    /// Only this many of the newest exports are kept, `None` means no limit.
+    pub max_files: Option<usize>,
+}
+
+pub struct ExportLibrary {
+    dir: PathBuf,
+    template: String,
+    cleanup: Cleanup,
+}
+
+impl ExportLibrary {
+    pub fn new(dir: PathBuf, template: &str, cleanup: Cleanup) -> Self {
+        Self {
+            dir,
+            template: if template.is_empty() { DEFAULT_TEMPLATE } else { template }.to_string(),
+            cleanup,
+        }
+    }
+
+    /// Directory the exports are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes an exported clip and removes old exports, returns the path of the new file.
+    ///
+    /// A number is appended to the name if a file with the same name exists already.
+    pub fn save(&self, data: &[u8], info: &ClipInfo) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)?;
+        let stem = file_stem(&self.template, info);
+        for n in 1.. {
+            let name = match n {
+                1 => format!("{}.{}", stem, EXPORT_EXTENSION),
+                n => format!("{}_{}.{}", stem, n, EXPORT_EXTENSION),
+            };
+            let path = self.dir.join(&name);
+            match File::options().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(data)?;
+                    nih_dbg!("Saved MIDI file: {:?}", &path);
+                    // Without an entry the export is fine, it is just never cleaned up
+                    if let Err(e) = self.add_to_manifest(&name) {
+                        nih_warn!("Failed to add {:?} to the export manifest: {}", &path, e);
+                    }
+                    self.clean_up(&path);
+                    return Ok(path);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Lists a written file in the manifest.
+    fn add_to_manifest(&self, name: &str) -> io::Result<()> {
+        // Lines are appended in one write, so instances sharing the folder do not mix them up
+        let mut manifest = File::options()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(MANIFEST))?;
+        manifest.write_all(format!("{}\n", name).as_bytes())
+    }
+
+    /// Removes exports beyond the cleanup limits, newest first, never `keep`.
+    ///
+    /// Only files listed in the manifest are removed, files the user put into the export folder
+    /// are left alone. Returns the number of removed files.
+    pub fn clean_up(&self, keep: &Path) -> usize {
+        let Ok(manifest) = std::fs::read_to_string(self.dir.join(MANIFEST)) else {
+            return 0;
+        };
+        // Only plain names, an edited manifest can not reach out of the folder
+        let names: BTreeSet<&str> = manifest
+            .lines()
+            .filter(|name| Path::new(name).file_name().is_some_and(|file_name| file_name == *name))
+            .collect();
+        let mut exports: Vec<(SystemTime, PathBuf)> = names
+            .iter()
+            .map(|name| self.dir.join(name))
+            .filter(|path| path.extension().is_some_and(|ext| ext == EXPORT_EXTENSION))
+            .filter(|path| path != keep)
+            .filter_map(|path| {
+                let meta = std::fs::symlink_metadata(&path).ok()?;
+                meta.is_file().then_some((meta.modified().ok()?, path))
+            })
+            .collect();
+        exports.sort_by(|(t1, _), (t2, _)| t2.cmp(t1));
+
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for (idx, (modified, path)) in exports.iter().enumerate() {
+            // The kept file counts towards the limit
+            let too_many = self.cleanup.max_files.is_some_and(|max| idx + 1 >= max);
+            let too_old = self.cleanup.max_age.is_some_and(|max_age| {
+                now.duration_since(*modified).is_ok_and(|age| age > max_age)
+            });
+            if !too_many && !too_old {
+                continue;
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => removed += 1,
+                Err(e) => nih_warn!("Failed to remove old export {:?}: {}", path, e),
+            }
+        }
+        if removed > 0 {
+            nih_dbg!("Removed {} old exports", removed);
+        }
+
+        // Names of files that are gone, by the cleanup or otherwise, leave the manifest
+        let remaining: String = names
+            .iter()
+            .filter(|name| self.dir.join(name).exists())
+            .map(|name| format!("{}\n", name))
+            .collect();
+        if remaining.lines().count() == manifest.lines().count() {
+            return removed;
+        }
+        if let Err(e) = std::fs::write(self.dir.join(MANIFEST), remaining) {
+            nih_warn!("Failed to update the export manifest: {}", e);
+        }
+        removed
+    }
+}
+
+/// Expands the placeholders of `template`, characters not allowed in file names are replaced.
+fn file_stem(template: &str, info: &ClipInfo) -> String {
+    let (year, month, day, hour, minute, second) = journal::utc_fields(info.time);
+    let date = format!("{:04}-{:02}-{:02}", year, month, day);
+    let time = format!("{:02}-{:02}-{:02}", hour, minute, second);
+    let tempo = format!("{}", (info.tempo * 10.).round() / 10.);
+    let bars = info
+        .bars
+        .map_or(String::new(), |(first, last)| format!("{}-{}", first, last));
+
+    let mut stem = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let value = match &rest[start + 1..start + len] {
+            "date" => &date,
+            "time" => &time,
+            "tempo" => &tempo,
+            "bars" => &bars,
+            _ => &rest[start..=start + len],
+        };
+        stem.push_str(&rest[..start]);
+        if value.is_empty() {
+            // Leave out the separator before the missing value as well
+            let trimmed = stem.trim_end_matches(['_', '-', ' ']).len();
+            stem.truncate(trimmed);
+        }
+        stem.push_str(value);
+        rest = &rest[start + len + 1..];
+    }
+    stem.push_str(rest);
+
+    let stem: String = stem
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    if stem.is_empty() { String::from("mucap") } else { stem }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn info() -> ClipInfo {
+        ClipInfo {
+            // 2024-02-29 12:34:56 UTC
+            time: UNIX_EPOCH + Duration::from_secs(1709210096),
+            tempo: 92.5,
+            bars: Some((5, 8)),
+        }
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(
+            file_stem(DEFAULT_TEMPLATE, &info()),
+            "mucap_2024-02-29_12-34-56_92.5bpm_5-8"
+        );
+
+        // Missing values take their separator with them, unknown placeholders stay
+        let untimed = ClipInfo { bars: None, tempo: 120., ..info() };
+        assert_eq!(file_stem(DEFAULT_TEMPLATE, &untimed), "mucap_2024-02-29_12-34-56_120bpm");
+        assert_eq!(file_stem("{track}_{tempo}", &untimed), "{track}_120");
+        assert_eq!(file_stem("{bars}", &untimed), "mucap");
+
+        // Path separators cannot escape the export folder
+        assert_eq!(file_stem("../{date}/x:y", &info()), ".._2024-02-29_x_y");
+    }
+
+    #[test]
+    fn test_save_and_clean_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = ExportLibrary::new(dir.path().join("exports"), "clip", Cleanup::default());
+
+        let paths: Vec<PathBuf> = (0..5)
+            .map(|n| {
+                let path = library.save(&[n], &info()).unwrap();
+                // Modification times need to differ for the order of the cleanup
+                let time = UNIX_EPOCH + Duration::from_secs(1_000_000 + n as u64);
+                File::options().write(true).open(&path).unwrap().set_modified(time).unwrap();
+                path
+            })
+            .collect();
+        assert_eq!(paths[0].file_name().unwrap(), "clip.mid");
+        assert_eq!(paths[1].file_name().unwrap(), "clip_2.mid");
+        assert_eq!(std::fs::read(&paths[4]).unwrap(), vec![4]);
+
+        // Only the newest files remain, files Mucap did not write are left alone
+        std::fs::write(dir.path().join("exports/notes.txt"), "keep").unwrap();
+        let own = dir.path().join("exports/own.mid");
+        std::fs::write(&own, "keep").unwrap();
+        File::options().write(true).open(&own).unwrap().set_modified(UNIX_EPOCH).unwrap();
+        let library = ExportLibrary::new(
+            dir.path().join("exports"),
+            "clip",
+            Cleanup { max_age: None, max_files: Some(3) },
+        );
+        assert_eq!(library.clean_up(&paths[4]), 2);
+        let exists: Vec<bool> = paths.iter().map(|path| path.exists()).collect();
+        assert_eq!(exists, vec![false, false, true, true, true]);
+        assert!(dir.path().join("exports/notes.txt").exists());
+        assert!(own.exists());
+        let manifest = std::fs::read_to_string(dir.path().join("exports").join(MANIFEST)).unwrap();
+        assert_eq!(manifest, "clip_3.mid\nclip_4.mid\nclip_5.mid\n");
+
+        // Old files are removed regardless of the count
+        let library = ExportLibrary::new(
+            dir.path().join("exports"),
+            "clip",
+            Cleanup { max_age: Some(Duration::from_secs(3600)), max_files: None },
+        );
+        assert_eq!(library.clean_up(&paths[4]), 2);
+        assert!(paths[4].exists());
+        assert!(own.exists());
+    }
+}
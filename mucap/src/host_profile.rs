@@ -0,0 +1,129 @@
+//! Export settings for the DAWs Mucap runs in.
+//!
+//! DAWs disagree on what a pasted or dropped clip should look like. Bitwig asks whether to
+//! import the tempo of every clip that contains one, while Reaper and Ardour use it to lay out
+//! the clip. A host profile decides the resolution, the file format, whether tempo and time
+//! signature are written, how far the clip is padded and whether channels get tracks of their
+//! own.
+//!
+//! nih-plug does not tell the plugin which host loaded it, so [`HostProfile::Auto`] goes by the
+//! name of the process the plugin runs in. Hosts that run plugins in a separate process, like
+//! Bitwig and Reaper, name that process after themselves. Plugins running in a bridge or
+//! sandbox of another vendor get `Custom`. The process is looked at once, the plugin logs the
+//! resolved profile when it starts.
+
+use std::sync::OnceLock;
+
+use miniserde::{Deserialize, Serialize};
+
+use crate::ui::miditransfer::{ClipPadding, ExportOptions};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostProfile {
+    /// Picks the profile of the running host, `Custom` for unknown hosts.
+    #[default]
+    Auto,
+    /// The export settings of the config.
+    Custom,
+    Bitwig,
+    Reaper,
+    Ardour,
+}
+
+impl HostProfile {
+    /// Resolves `Auto` to the profile of the running host, other profiles stay as they are.
+    pub fn resolve(self) -> Self {
+        // The host does not change while the plugin is loaded
+        static DETECTED: OnceLock<HostProfile> = OnceLock::new();
+        if self != HostProfile::Auto {
+            return self;
+        }
+        *DETECTED.get_or_init(|| {
+            std::env::current_exe()
+                .ok()
+                .and_then(|exe| Some(Self::detect(exe.file_name()?.to_str()?)))
+                .unwrap_or(HostProfile::Custom)
+        })
+    }
+
+    /// Profile for a host process named `name`, `Custom` if the host is unknown.
+    pub fn detect(name: &str) -> Self {
+        let name = name.to_lowercase();
+        if name.contains("bitwig") {
+            HostProfile::Bitwig
+        } else if name.contains("reaper") {
+            HostProfile::Reaper
+        } else if name.contains("ardour") || name.contains("mixbus") {
+            HostProfile::Ardour
+        } else {
+            HostProfile::Custom
+        }
+    }
+
+    /// Overrides the options the profile decides, `Auto` and `Custom` leave them as they are.
+    ///
+    /// Channels are left to [`ExportOptions::channel_one`] in every profile.
+    pub fn apply(self, options: &mut ExportOptions) {
+        let (ppqn, tempo_map, split_channels, padding) = match self {
+            HostProfile::Auto | HostProfile::Custom => return,
+            // A plain clip without tempo, Bitwig fits it to the project tempo on its own
+            HostProfile::Bitwig => (480, false, false, ClipPadding::SelectionEnd),
+            // Reaper creates items of the full length and loops them at the item end
+            HostProfile::Reaper => (960, true, false, ClipPadding::NextBar),
+            // Ardour works at 1920 ticks internally and imports every track as a track of its own
+            HostProfile::Ardour => (1920, true, true, ClipPadding::NextBar),
+        };
+        options.ppqn = ppqn;
+        options.tempo_map = tempo_map;
+        options.split_channels = split_channels;
+        options.padding = padding;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(HostProfile::detect("BitwigPluginHost-X64-SSE41"), HostProfile::Bitwig);
+        assert_eq!(HostProfile::detect("reaper.exe"), HostProfile::Reaper);
+        assert_eq!(HostProfile::detect("ardour-8.4.0"), HostProfile::Ardour);
+        assert_eq!(HostProfile::detect("Mixbus10"), HostProfile::Ardour);
+        assert_eq!(HostProfile::detect("carla-bridge-native"), HostProfile::Custom);
+        assert_eq!(HostProfile::Reaper.resolve(), HostProfile::Reaper);
+        // The test runner is no known host
+        assert_eq!(HostProfile::Auto.resolve(), HostProfile::Custom);
+    }
+
+    #[test]
+    fn test_apply() {
+        let configured = ExportOptions {
+            ppqn: 96,
+            bake_pedal: true,
+            tempo_map: true,
+            split_channels: true,
+            channel_one: true,
+            ..Default::default()
+        };
+
+        let mut options = configured;
+        HostProfile::Custom.apply(&mut options);
+        assert_eq!(options.ppqn, 96);
+        assert!(options.channel_one);
+
+        let mut options = configured;
+        HostProfile::Bitwig.apply(&mut options);
+        assert_eq!(options.ppqn, 480);
+        assert!(!options.tempo_map && !options.split_channels);
+        assert_eq!(options.padding, ClipPadding::SelectionEnd);
+        // Options outside of the profile are kept
+        assert!(options.bake_pedal && options.channel_one);
+
+        let mut options = configured;
+        HostProfile::Ardour.apply(&mut options);
+        assert_eq!(options.ppqn, 1920);
+        assert!(options.tempo_map && options.split_channels);
+        assert_eq!(options.padding, ClipPadding::NextBar);
+    }
+}
@@ -0,0 +1,668 @@
+//! Crash-safe journal of the capture on disk.
+//!
+//! The store delivery thread appends every event, bar and sample rate change to a journal file
+//! in the data directory and flushes it periodically. When the journal grows too large, it is
+//! rewritten into a new file from a snapshot of the store and the old file is removed. The
+//! journal is removed when the plugin shuts down cleanly. A running session keeps its journal
+//! locked, so journals that are not locked by anyone were left behind by a crash and can be
+//! recovered.
+//!
+//! Next to every journal, a small summary file holds the number of events flushed so far. It is
+//! all the recovery bar needs to list a journal, the journal itself is only read on recovery.
+//!
+//! Journals are plain text with one record per line:
+//!
+//! - `R <start> <sample rate>`: sample rate in effect from sample `start` on
+//! - `E <time> <hex bytes>`: MIDI event at sample `time`
+//! - `B <bar number> <time>`: bar starting at sample `time`
+//! - `T <time> <tempo> <numerator> <denominator>`: tempo and time signature from sample `time` on
+//! - `X <time> <channel> <key> <kind> <value>`: per-note expression sent by the host, expressions
+//!   derived from MPE messages come back with their events
+//!
+//! Journals written before the tempo map was recorded have `T` records without a time.
+//!
+//! A last line cut short by the crash is ignored on recovery.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use nih_plug::{nih_dbg, nih_log, nih_warn};
+
+use crate::Samples;
+use crate::config;
+use crate::midistore::{self, Bar, ExpressionKind, MidiStore, TempoChange};
+use crate::persist::{self, CaptureSnapshot};
+use crate::ui::miditransfer;
+
+/// How often the journal is flushed to disk.
+const JOURNAL_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Size after which the journal is rewritten from the store, dropping what retention evicted.
+const JOURNAL_COMPACT_BYTES: u64 = 64 * 1024 * 1024;
+
+const JOURNAL_EXTENSION: &str = "journal";
+
+/// Extension of the summary next to a journal, see [`summary_path`].
+const SUMMARY_EXTENSION: &str = "summary";
+
+/// Tells apart journals of several plugin instances started in the same second.
+static JOURNAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Directory journals are written to.
+pub fn journal_dir() -> Option<PathBuf> {
+    config::data_dir().map(|dir| dir.join("journals"))
+}
+
+/// Append-only journal of the running session.
+pub struct Journal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Size of the journal in bytes.
+    written: u64,
+    /// Size of the journal right after the last compaction.
+    compacted: u64,
+    last_flush: Instant,
+    /// Restore count of the store the journal last saw, see [`MidiStore::restore_count`].
+    restore_count: usize,
+    /// Number of MIDI events in the journal.
+    events: usize,
+    /// Number of events the summary was last written with.
+    summarized: Option<usize>,
+}
+
+impl Journal {
+    /// Creates and locks a new journal in `dir`.
+    pub fn create(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let (path, file) = create_file(dir)?;
+        nih_dbg!("Created journal {:?}", &path);
+
+        let mut journal = Self {
+            path,
+            writer: BufWriter::new(file),
+            written: 0,
+            compacted: 0,
+            last_flush: Instant::now(),
+            restore_count: 0,
+            events: 0,
+            summarized: None,
+        };
+        journal.write_summary()?;
+        Ok(journal)
+    }
+
+    /// Records a MIDI event.
+    pub fn event(&mut self, time: Samples, data: &[u8]) -> io::Result<()> {
+        let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.events += 1;
+        self.line(format_args!("E {} {}", time, hex))
+    }
+
+    /// Records a bar.
+    pub fn bar(&mut self, bar: &Bar) -> io::Result<()> {
+        self.line(format_args!("B {} {}", bar.bar_number, bar.t))
+    }
+
+    /// Records a sample rate change.
+    pub fn sample_rate(&mut self, start: Samples, sample_rate: f32) -> io::Result<()> {
+        self.line(format_args!("R {} {}", start, sample_rate))
+    }
+
+    /// Records a tempo change.
+    pub fn tempo(&mut self, change: &TempoChange) -> io::Result<()> {
+        self.line(format_args!(
+            "T {} {} {} {}",
+            change.t, change.tempo, change.time_sig.0, change.time_sig.1
+        ))
+    }
+
+    /// Records a per-note expression, given as in [`CaptureSnapshot::expressions`].
+    pub fn expression(
+        &mut self,
+        (time, channel, key, kind, value): (Samples, u8, u8, ExpressionKind, f32),
+    ) -> io::Result<()> {
+        self.line(format_args!("X {} {} {} {:?} {}", time, channel, key, kind, value))
+    }
+
+    fn line(&mut self, args: std::fmt::Arguments) -> io::Result<()> {
+        let mut line = args.to_string();
+        line.push('\n');
+        self.written += line.len() as u64;
+        self.writer.write_all(line.as_bytes())
+    }
+
+    /// Flushes the journal if the last flush is long enough ago.
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.last_flush.elapsed() < JOURNAL_FLUSH_INTERVAL {
+            return Ok(());
+        }
+        self.last_flush = Instant::now();
+        self.writer.flush()?;
+        // The summary never counts events that are not on disk yet
+        if self.summarized != Some(self.events) {
+            self.write_summary()?;
+        }
+        Ok(())
+    }
+
+    fn write_summary(&mut self) -> io::Result<()> {
+        std::fs::write(summary_path(&self.path), self.events.to_string())?;
+        self.summarized = Some(self.events);
+        Ok(())
+    }
+
+    /// Snapshot of `store` to rewrite the journal from, `None` unless the journal grew too large
+    /// or the store restored a capture.
+    ///
+    /// Retention keeps the store bounded but the journal only ever grows, rewriting it drops
+    /// everything the store evicted. Restored captures are not part of the journal until it is
+    /// rewritten. Taking the snapshot is quick, [`Journal::compact`] writes it once the store is
+    /// unlocked again.
+    pub fn compaction(&mut self, store: &MidiStore) -> Option<CaptureSnapshot> {
+        // The store itself may be large, so wait for it to double before rewriting again
+        let limit = JOURNAL_COMPACT_BYTES.max(2 * self.compacted);
+        if self.written < limit && self.restore_count == store.restore_count() {
+            return None;
+        }
+        self.restore_count = store.restore_count();
+
+        Some(store.snapshot(usize::MAX))
+    }
+
+    /// Rewrites the journal from a snapshot taken by [`Journal::compaction`].
+    ///
+    /// The snapshot goes into a new journal file. The old file is removed while it is still
+    /// locked, so other sessions never take it for an orphan, and closed afterwards.
+    pub fn compact(&mut self, snapshot: &CaptureSnapshot) -> io::Result<()> {
+        let events =
+            persist::decode_events(snapshot.first, &snapshot.events).map_err(io::Error::other)?;
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let (path, file) = create_file(dir).map_err(io::Error::other)?;
+        let old_path = std::mem::replace(&mut self.path, path);
+        let old = std::mem::replace(&mut self.writer, BufWriter::new(file));
+        self.written = 0;
+        self.events = 0;
+
+        for (start, _, sample_rate) in snapshot.sample_rates.iter() {
+            self.sample_rate(*start, *sample_rate)?;
+        }
+        for (t, tempo, time_sig) in snapshot.tempo_map.iter() {
+            self.tempo(&TempoChange {
+                t: *t,
+                tempo: *tempo,
+                time_sig: *time_sig,
+            })?;
+        }
+        for (time, data) in events.iter() {
+            self.event(*time, data)?;
+        }
+        for (bar_number, t) in snapshot.bars.iter() {
+            self.bar(&Bar {
+                bar_number: *bar_number,
+                t: *t,
+            })?;
+        }
+        for expression in snapshot.expressions.iter() {
+            self.expression(*expression)?;
+        }
+        self.writer.flush()?;
+        self.compacted = self.written;
+        self.write_summary()?;
+
+        // The old journal holds nothing the new one does not
+        std::fs::remove_file(&old_path)?;
+        drop(old);
+        let _ = std::fs::remove_file(summary_path(&old_path));
+        nih_dbg!("Compacted journal {:?} into {:?}, {} bytes", &old_path, &self.path, self.written);
+        Ok(())
+    }
+
+    /// Closes and removes the journal after a clean shutdown.
+    pub fn close(self) {
+        let Self { path, writer, .. } = self;
+        drop(writer);
+        if let Err(e) = remove(&path) {
+            nih_warn!("Failed to remove journal {:?}: {}", &path, e);
+        }
+    }
+}
+
+/// Path of the summary of the journal at `path`.
+fn summary_path(path: &Path) -> PathBuf {
+    path.with_extension(SUMMARY_EXTENSION)
+}
+
+/// Removes the journal at `path` together with its summary.
+fn remove(path: &Path) -> io::Result<()> {
+    std::fs::remove_file(path)?;
+    // Journals of older versions have no summary
+    let _ = std::fs::remove_file(summary_path(path));
+    Ok(())
+}
+
+/// Creates and locks a new journal file in `dir`.
+fn create_file(dir: &Path) -> Result<(PathBuf, File)> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = dir.join(format!(
+        "{}-{}-{}.{}",
+        secs,
+        std::process::id(),
+        JOURNAL_COUNTER.fetch_add(1, Ordering::Relaxed),
+        JOURNAL_EXTENSION
+    ));
+    let file = File::options().create_new(true).append(true).open(&path)?;
+    file.try_lock()?;
+    Ok((path, file))
+}
+
+/// Runs `f` on the journal, giving up on journaling if it fails so the capture goes on.
+pub fn write(journal: &mut Option<Journal>, f: impl FnOnce(&mut Journal) -> io::Result<()>) {
+    let Some(j) = journal.as_mut() else {
+        return;
+    };
+    if let Err(e) = f(j) {
+        nih_warn!("Journal {:?} failed, stopping to journal: {}", &j.path, e);
+        *journal = None;
+    }
+}
+
+/// A journal left behind by a crashed session.
+#[derive(Clone, Debug)]
+pub struct OrphanedJournal {
+    pub path: PathBuf,
+    /// Time of the last write to the journal.
+    pub modified: SystemTime,
+    /// Number of MIDI events in the journal, `None` if it has no summary.
+    pub events: Option<usize>,
+}
+
+impl OrphanedJournal {
+    /// Short description for the user.
+    pub fn describe(&self) -> String {
+        match self.events {
+            Some(events) => format!(
+                "Unsaved capture from {} ({} events)",
+                format_utc(self.modified),
+                events
+            ),
+            None => format!("Unsaved capture from {}", format_utc(self.modified)),
+        }
+    }
+}
+
+/// Lists journals in `dir` that are not locked by a running session, newest first.
+///
+/// Journals without any events are removed right away.
+pub fn orphaned(dir: &Path) -> Vec<OrphanedJournal> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut orphans: Vec<OrphanedJournal> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == JOURNAL_EXTENSION))
+        .filter_map(|path| {
+            let file = File::open(&path).ok()?;
+            // Running sessions hold the lock on their journal
+            file.try_lock().ok()?;
+            let meta = file.metadata().ok()?;
+            let modified = meta.modified().ok()?;
+            let mut events = std::fs::read_to_string(summary_path(&path))
+                .ok()
+                .and_then(|summary| summary.trim().parse().ok());
+            if events == Some(0) {
+                // Events written before the first flush are not summarized, but there are few
+                let count = BufReader::new(&file)
+                    .lines()
+                    .map_while(|line| line.ok())
+                    .filter(|line| line.starts_with("E "))
+                    .count();
+                events = Some(count);
+            }
+            drop(file);
+            if events == Some(0) || meta.len() == 0 {
+                nih_log!("Removing empty journal {:?}", &path);
+                let _ = remove(&path);
+                return None;
+            }
+            Some(OrphanedJournal {
+                path,
+                modified,
+                events,
+            })
+        })
+        .collect();
+    orphans.sort_by_key(|orphan| std::cmp::Reverse(orphan.modified));
+    orphans
+}
+
+/// Reads a journal into a snapshot that can be restored with [`MidiStore::restore`].
+pub fn load(path: &Path) -> Result<CaptureSnapshot> {
+    let content = std::fs::read_to_string(path)?;
+    parse(&content)
+}
+
+fn parse(content: &str) -> Result<CaptureSnapshot> {
+    let mut snapshot = CaptureSnapshot::default();
+    let mut events: Vec<(Samples, Vec<u8>)> = Vec::new();
+
+    // Only complete lines, the last one may have been cut short by the crash
+    let complete = content.rfind('\n').map_or("", |end| &content[..end]);
+    for line in complete.lines() {
+        let mut fields = line.split(' ');
+        let kind = fields.next();
+        let fields: Vec<&str> = fields.collect();
+        let parsed = match (kind, fields.as_slice()) {
+            (Some("E"), [time, hex]) => parse_event(time, hex).map(|event| events.push(event)),
+            (Some("B"), [bar_number, t]) => (|| {
+                snapshot.bars.push((bar_number.parse()?, t.parse()?));
+                Ok(())
+            })(),
+            (Some("R"), [start, sample_rate]) => (|| {
+                let start: Samples = start.parse()?;
+                let sample_rate: f32 = sample_rate.parse()?;
+                snapshot.sample_rates.retain(|(s, _, _)| *s < start);
+                // Seconds are only informative, restoring chains the epochs anew
+                let seconds = snapshot.sample_rates.last().map_or(0.0, |(s, secs, rate)| {
+                    secs + (start - s) as f64 / *rate as f64
+                });
+                snapshot.sample_rates.push((start, seconds, sample_rate));
+                Ok(())
+            })(),
+            (Some("T"), [tempo, num, den]) => (|| {
+                snapshot.tempo = tempo.parse()?;
+                snapshot.time_sig = (num.parse()?, den.parse()?);
+                Ok(())
+            })(),
+            (Some("T"), [t, tempo, num, den]) => (|| {
+                snapshot.tempo = tempo.parse()?;
+                snapshot.time_sig = (num.parse()?, den.parse()?);
+                snapshot.tempo_map.push((t.parse()?, snapshot.tempo, snapshot.time_sig));
+                Ok(())
+            })(),
+            (Some("X"), [time, channel, key, kind, value]) => (|| {
+                let channel: u8 = channel.parse()?;
+                let key: u8 = key.parse()?;
+                if channel > 15 || key > 127 {
+                    anyhow::bail!("Channel or key out of range");
+                }
+                let kind = parse_expression_kind(kind)?;
+                snapshot
+                    .expressions
+                    .push((time.parse()?, channel, key, kind, value.parse()?));
+                Ok(())
+            })(),
+            _ => Err(anyhow::anyhow!("Unknown record")),
+        };
+        if let Err(e) = parsed {
+            nih_warn!("Skipping journal line {:?}: {}", line, e);
+        }
+    }
+
+    // Bars and events are journaled as they arrive, but compacting writes them separately
+    snapshot.bars.sort_by_key(|(_, t)| *t);
+    snapshot.tempo_map.sort_by_key(|(t, _, _)| *t);
+    snapshot.expressions.sort_by_key(|(t, _, _, _, _)| *t);
+    snapshot.first = events.first().map_or(0, |(time, _)| *time);
+    snapshot.events = persist::encode_events(&events);
+    Ok(snapshot)
+}
+
+fn parse_event(time: &str, hex: &str) -> Result<(Samples, Vec<u8>)> {
+    let time = time.parse()?;
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits");
+    }
+    let data = (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()?;
+    midistore::validate_event(&data)?;
+    Ok((time, data))
+}
+
+fn parse_expression_kind(name: &str) -> Result<ExpressionKind> {
+    use ExpressionKind::*;
+    [Volume, Pan, Tuning, Vibrato, Expression, Brightness, Pressure]
+        .into_iter()
+        .find(|kind| format!("{:?}", kind) == name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown expression kind"))
+}
+
+/// Restores an orphaned journal into `store` and removes it.
+///
+/// The journal is read before the store is locked, so capturing goes on while it is parsed.
+pub fn restore(journal: &OrphanedJournal, store: &RwLock<MidiStore>) -> Result<()> {
+    let snapshot = load(&journal.path)?;
+    store.write().unwrap().restore(&snapshot)?;
+    remove(&journal.path)?;
+    nih_log!("Restored journal {:?}", &journal.path);
+    Ok(())
+}
+
+/// Removes an orphaned journal without restoring it.
+pub fn discard(journal: &OrphanedJournal) -> io::Result<()> {
+    remove(&journal.path)
+}
+
+/// Exports an orphaned journal to a MIDI file next to the journals and returns its path.
+///
+/// Pass the configured export options, see [`crate::config::Config::export_options`]. The
+/// journal is kept, so it can still be restored afterwards.
+pub fn export(
+    journal: &OrphanedJournal,
+    options: &miditransfer::ExportOptions,
+) -> Result<PathBuf> {
+    let snapshot = load(&journal.path)?;
+    let mut store = MidiStore::new();
+    store.restore(&snapshot)?;
+
+    let (Some((s0, _)), Some((s1, _))) = (store.store.front(), store.store.back()) else {
+        anyhow::bail!("Journal contains no events");
+    };
+    let tempo = if snapshot.tempo > 0.0 { snapshot.tempo } else { 120.0 };
+    let Some(smf) = miditransfer::build_smf(&store, *s0, *s1, tempo, options) else {
+        anyhow::bail!("Journal contains no notes");
+    };
+
+    let path = journal.path.with_extension("mid");
+    smf.save(&path)?;
+    nih_log!("Exported journal to {:?}", &path);
+    Ok(path)
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM UTC`.
+fn format_utc(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, _) = utc_fields(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year, month, day, hour, minute
+    )
+}
+
+/// Splits a time into (year, month, day, hour, minute, second) in UTC.
+pub(crate) fn utc_fields(time: SystemTime) -> (i64, i64, i64, i64, i64, i64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64);
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_journal() -> (tempfile::TempDir, Journal) {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::create(dir.path()).unwrap();
+        (dir, journal)
+    }
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let (_dir, mut journal) = temp_journal();
+        journal.sample_rate(0, 48000.).unwrap();
+        journal.tempo(&TempoChange { t: 0, tempo: 120., time_sig: (4, 4) }).unwrap();
+        journal.event(1000, &[0x90, 60, 100]).unwrap();
+        journal.bar(&Bar { bar_number: 1, t: 2000 }).unwrap();
+        journal.tempo(&TempoChange { t: 24000, tempo: 90., time_sig: (3, 4) }).unwrap();
+        journal.event(48000, &[0x80, 60, 0]).unwrap();
+        journal.expression((3000, 0, 60, ExpressionKind::Tuning, 0.5)).unwrap();
+        journal.writer.flush().unwrap();
+
+        let snapshot = load(&journal.path).unwrap();
+        assert_eq!(snapshot.sample_rates, vec![(0, 0.0, 48000.)]);
+        assert_eq!(snapshot.tempo, 90.);
+        assert_eq!(snapshot.time_sig, (3, 4));
+        assert_eq!(snapshot.tempo_map, vec![(0, 120., (4, 4)), (24000, 90., (3, 4))]);
+        assert_eq!(snapshot.bars, vec![(1, 2000)]);
+        assert_eq!(snapshot.expressions, vec![(3000, 0, 60, ExpressionKind::Tuning, 0.5)]);
+        assert_eq!(
+            persist::decode_events(snapshot.first, &snapshot.events).unwrap(),
+            vec![(1000, vec![0x90, 60, 100]), (48000, vec![0x80, 60, 0])]
+        );
+
+        let mut store = MidiStore::new();
+        store.restore(&snapshot).unwrap();
+        assert_eq!(store.notes.len(), 1);
+        assert_eq!(store.tempo_map.len(), 2);
+        assert_eq!(store.expressions.len(), 1);
+        assert_eq!(store.expressions[0].idx_on, store.notes[0].idx_on);
+
+        // Journals without a tempo map still restore their last tempo
+        let snapshot = parse("T 120 4 4\nE 100 903c64\nE 200 803c00\n").unwrap();
+        assert!(snapshot.tempo_map.is_empty());
+        let mut store = MidiStore::new();
+        store.restore(&snapshot).unwrap();
+        assert_eq!(store.tempo_at(0).map(|change| change.tempo), Some(120.));
+    }
+
+    #[test]
+    fn test_parse_truncated_journal() {
+        let content = "R 0 48000\nE 100 903c64\nE 200 803c00\nE 300 90";
+        let snapshot = parse(content).unwrap();
+        let events = persist::decode_events(snapshot.first, &snapshot.events).unwrap();
+        assert_eq!(events.len(), 2);
+
+        // Garbage in complete lines is skipped as well
+        let content = "E 100 903c64\nE x 803c\nE 200 zz\nE 300 803c00\n";
+        let snapshot = parse(content).unwrap();
+        let events = persist::decode_events(snapshot.first, &snapshot.events).unwrap();
+        assert_eq!(events, vec![(100, vec![0x90, 60, 100]), (300, vec![0x80, 60, 0])]);
+    }
+
+    #[test]
+    fn test_orphaned_journals() {
+        let (dir, mut live) = temp_journal();
+        live.event(100, &[0x90, 60, 100]).unwrap();
+        live.writer.flush().unwrap();
+
+        // A crashed session leaves its journal behind without a lock
+        let mut crashed = Journal::create(dir.path()).unwrap();
+        crashed.event(100, &[0x90, 60, 100]).unwrap();
+        crashed.event(200, &[0x80, 60, 0]).unwrap();
+        crashed.last_flush -= JOURNAL_FLUSH_INTERVAL;
+        crashed.flush_if_due().unwrap();
+        // Written after the last flush, so the summary misses it
+        crashed.event(300, &[0x90, 62, 100]).unwrap();
+        let crashed_path = crashed.path.clone();
+        drop(crashed);
+        assert_eq!(std::fs::read_to_string(summary_path(&crashed_path)).unwrap(), "2");
+
+        // Crashed before the first flush
+        let mut early = Journal::create(dir.path()).unwrap();
+        early.event(100, &[0x90, 60, 100]).unwrap();
+        let early_path = early.path.clone();
+        drop(early);
+
+        let empty = Journal::create(dir.path()).unwrap();
+        let empty_path = empty.path.clone();
+        drop(empty);
+
+        let mut orphans = orphaned(dir.path());
+        orphans.sort_by_key(|orphan| orphan.path != crashed_path);
+        assert_eq!(orphans.len(), 2);
+        assert_eq!(orphans[0].path, crashed_path);
+        assert_eq!(orphans[0].events, Some(2));
+        assert_eq!(orphans[1].path, early_path);
+        assert_eq!(orphans[1].events, Some(1));
+        assert!(!empty_path.exists() && !summary_path(&empty_path).exists());
+
+        // Exports follow the options they are given
+        let options = miditransfer::ExportOptions {
+            ppqn: 96,
+            ..Default::default()
+        };
+        let exported = export(&orphans[0], &options).unwrap();
+        let data = std::fs::read(&exported).unwrap();
+        let smf = midly::Smf::parse(&data).unwrap();
+        assert_eq!(smf.header.timing, midly::Timing::Metrical(96.into()));
+        std::fs::remove_file(&exported).unwrap();
+
+        let store = RwLock::new(MidiStore::new());
+        restore(&orphans[0], &store).unwrap();
+        assert_eq!(store.read().unwrap().notes.len(), 1);
+        assert!(!crashed_path.exists() && !summary_path(&crashed_path).exists());
+        discard(&orphans[1]).unwrap();
+
+        live.close();
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_compact_journal() {
+        let (dir, mut journal) = temp_journal();
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        for (time, data) in [(100, [0x90, 60, 100]), (200, [0x80, 60, 0])] {
+            store.add(time, data).unwrap();
+            journal.event(time, &data).unwrap();
+        }
+        assert!(journal.compaction(&store).is_none());
+        journal.written = JOURNAL_COMPACT_BYTES;
+        let old_path = journal.path.clone();
+        let snapshot = journal.compaction(&store).unwrap();
+        journal.compact(&snapshot).unwrap();
+        assert!(journal.written < JOURNAL_COMPACT_BYTES);
+        // The compacted journal replaced the old one
+        assert!(!old_path.exists() && !summary_path(&old_path).exists());
+        assert_eq!(std::fs::read_to_string(summary_path(&journal.path)).unwrap(), "2");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Restoring a capture gets it into the journal
+        let snapshot = store.snapshot(usize::MAX);
+        store.restore(&snapshot).unwrap();
+        let snapshot = journal.compaction(&store).unwrap();
+        journal.compact(&snapshot).unwrap();
+        journal.writer.flush().unwrap();
+        let snapshot = load(&journal.path).unwrap();
+        let events = persist::decode_events(snapshot.first, &snapshot.events).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(snapshot.sample_rates.len(), store.sample_rate_epochs().len());
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01 00:00 UTC");
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(format_utc(time), "2024-02-29 12:34 UTC");
+    }
+}
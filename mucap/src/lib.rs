@@ -1,30 +1,46 @@
-use nih_plug::{midi::MidiResult, prelude::*, nih_dbg};
+use nih_plug::{midi::MidiResult, prelude::*, nih_dbg, nih_log, nih_warn};
+use midly::num::{u4, u7};
 use nih_plug_vizia::ViziaState;
 use rand::Rng;
-use std::sync::{Arc, RwLock, atomic::{AtomicBool, Ordering}, mpsc};
+use std::sync::{Arc, RwLock, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::time::Duration;
 
-mod midistore;
+pub mod midistore;
 mod note_generator;
 mod config;
+mod exports;
+mod host_profile;
+mod journal;
+mod persist;
+mod sysex;
+mod tempo_estimator;
 mod ui;
 
-use midistore::MidiStore;
+use midistore::{ExpressionKind, MidiStore};
 use note_generator::NoteGenerator;
 
 use crate::config::ConfigStore;
+use crate::persist::PersistentCapture;
 
-type Samples = i64;
+/// Time in samples since the plugin started, the fundamental unit of time in Mucap.
+pub type Samples = i64;
 
 /// A plugin that inverts all MIDI note numbers, channels, CCs, velocities, pressures, and
 /// everything else you don't want to be inverted.
 pub struct Mucap {
     params: Arc<MucapParams>,
     samples: Samples,
+    /// Sample rate the current sample count is running at.
+    sample_rate: f32,
+    /// Seconds since the plugin started, accumulated over sample rate changes.
+    seconds: f64,
     time: Arc<AtomicF32>,
     store: Arc<RwLock<MidiStore>>,
     config: Arc<RwLock<ConfigStore>>,
     debug_stop: Arc<AtomicBool>,
-    tx: Option<mpsc::SyncSender<StoreMessage>>,
+    tx: Option<rtrb::Producer<StoreMessage>>,
+    /// Number of captured messages that did not fit into the ring buffer, shared with the MidiStore.
+    dropped_events: Arc<AtomicUsize>,
     store_delivery_thread: Option<std::thread::JoinHandle<()>>,
     generator: NoteGenerator,
 }
@@ -33,22 +49,34 @@ pub struct Mucap {
 struct MucapParams {
     #[persist = "editor-state"]
     editor_state: Arc<ViziaState>,
+    /// Captured MIDI, only filled if enabled in the config.
+    #[persist = "capture"]
+    capture: PersistentCapture,
 }
 
 impl Default for Mucap {
     fn default() -> Self {
-        let store = Arc::new(RwLock::new(MidiStore::new()));
         let config = Arc::new(RwLock::new(ConfigStore::new()));
         let cfg = config.read().unwrap().get_config();
+        nih_log!("Exporting for host profile {:?}", cfg.host_profile.resolve());
+        let mut store = MidiStore::with_retention(cfg.retention());
+        store.set_note_pairing(cfg.note_pairing);
+        store.set_mpe_mode(cfg.mpe_mode);
+        let dropped_events = store.dropped_events_counter();
+        let store = Arc::new(RwLock::new(store));
         Self {
             params: Arc::new(MucapParams {
-                editor_state: ui::default_state(cfg.scale_factor)
+                editor_state: ui::default_state(cfg.scale_factor),
+                capture: PersistentCapture::new(store.clone(), cfg.persist_capture_limit()),
             }),
             samples: 0,
+            sample_rate: 0.0,
+            seconds: 0.0,
             time: Arc::new(AtomicF32::new(0.0)),
             store: store.clone(),
             config,
             tx: None,
+            dropped_events,
             store_delivery_thread: None,
             generator: NoteGenerator::default(),
             debug_stop: Arc::new(AtomicBool::new(false)),
@@ -59,7 +87,8 @@ impl Default for Mucap {
 // TODO: Currently very tweaked for Bitwig
 #[derive(Debug, Default)]
 pub struct TransportInfo {
-    pub time: f32,
+    /// Time of the block start in samples.
+    pub time: Samples,
     pub playing: bool,
     pub sample_rate: f32,
     pub tempo: f64,
@@ -67,10 +96,12 @@ pub struct TransportInfo {
     pub pos_samples: i64,
     pub pos_beats: f64,
     pub bar_start_pos_beats: f64,
+    /// Loop region in quarter notes, if the host reports one.
+    pub loop_range: Option<(f64, f64)>,
 }
 
 impl TransportInfo {
-    pub fn from_transport(transport: &Transport, time: f32) -> Option<Self> {
+    pub fn from_transport(transport: &Transport, time: Samples) -> Option<Self> {
         let Some(tempo) = transport.tempo else {
             return None;
         };
@@ -98,6 +129,7 @@ impl TransportInfo {
             pos_samples,
             pos_beats,
             bar_start_pos_beats,
+            loop_range: transport.loop_range_beats(),
         })
     }
 
@@ -111,34 +143,187 @@ impl TransportInfo {
     }
 }
 
+/// A per-note expression on its way to the store, see [`MidiStore::add_expression`].
+#[derive(Clone, Copy, Debug)]
+pub struct Expression {
+    voice_id: Option<i32>,
+    channel: u8,
+    key: u8,
+    kind: ExpressionKind,
+    value: f32,
+}
+
+impl Expression {
+    /// Extracts the expression from a note event, `None` if it is no per-note expression.
+    fn from_event(event: &NoteEvent<sysex::SysEx>) -> Option<Self> {
+        let (voice_id, channel, key, kind, value) = match *event {
+            NoteEvent::PolyVolume { voice_id, channel, note, gain, .. } => {
+                (voice_id, channel, note, ExpressionKind::Volume, gain)
+            }
+            NoteEvent::PolyPan { voice_id, channel, note, pan, .. } => {
+                (voice_id, channel, note, ExpressionKind::Pan, pan)
+            }
+            NoteEvent::PolyTuning { voice_id, channel, note, tuning, .. } => {
+                (voice_id, channel, note, ExpressionKind::Tuning, tuning)
+            }
+            NoteEvent::PolyVibrato { voice_id, channel, note, vibrato, .. } => {
+                (voice_id, channel, note, ExpressionKind::Vibrato, vibrato)
+            }
+            NoteEvent::PolyExpression { voice_id, channel, note, expression, .. } => {
+                (voice_id, channel, note, ExpressionKind::Expression, expression)
+            }
+            NoteEvent::PolyBrightness { voice_id, channel, note, brightness, .. } => {
+                (voice_id, channel, note, ExpressionKind::Brightness, brightness)
+            }
+            _ => return None,
+        };
+        Some(Self {
+            voice_id,
+            channel,
+            key,
+            kind,
+            value,
+        })
+    }
+}
+
 pub enum StoreMessage {
-    MidiData(f32, [u8; 3]),
+    /// A MIDI message, with the voice id of its note if the host sent one.
+    MidiData(Samples, [u8; 3], Option<i32>),
+    /// A per-note expression.
+    Expression(Samples, Expression),
     TransportInfo(TransportInfo),
+    /// The sample rate changed, effective from the given sample on.
+    SampleRate(Samples, f32),
+    /// Part of a SysEx message, see [`sysex::chunks`].
+    SysEx(sysex::SysExChunk),
 }
 
+/// Number of messages the audio thread can queue up before events get dropped.
+const STORE_QUEUE_CAPACITY: usize = 8192;
+
+/// How long the delivery thread sleeps when the queue is empty.
+const STORE_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
 pub struct StoreDeliveryTask {
-    rx: mpsc::Receiver<StoreMessage>,
+    rx: rtrb::Consumer<StoreMessage>,
     store: Arc<RwLock<MidiStore>>,
+    /// Journal of everything delivered, `None` if journaling is disabled or failed.
+    journal: Option<journal::Journal>,
+    sysex: sysex::SysExAssembler,
+    tempo_estimator: tempo_estimator::TempoEstimator,
+}
+
+/// Times of the last tempo change and bar of the store, to journal the ones added after.
+struct Timing {
+    tempo: Option<Samples>,
+    bar: Option<Samples>,
+}
+
+impl Timing {
+    fn of(store: &MidiStore) -> Self {
+        Self {
+            tempo: store.tempo_map.back().map(|change| change.t),
+            bar: store.bars.back().map(|bar| bar.t),
+        }
+    }
+
+    /// Journals the tempo change and bar the store recorded since.
+    fn journal_changes(&self, journal: &mut Option<journal::Journal>, store: &MidiStore) {
+        journal::write(journal, |j| {
+            match store.tempo_map.back() {
+                Some(change) if Some(change.t) != self.tempo => j.tempo(change)?,
+                _ => (),
+            }
+            match store.bars.back() {
+                Some(bar) if Some(bar.t) != self.bar => j.bar(bar),
+                _ => Ok(()),
+            }
+        });
+    }
 }
 
 impl StoreDeliveryTask {
     fn run(&mut self) {
         nih_dbg!("Hello from the background");
-        while let Ok(msg) = self.rx.recv() {
-            use StoreMessage::*;
-            match msg {
-                MidiData(time, event) => {
-                    self.store
-                        .write()
-                        .unwrap()
-                        .add(time, event)
-                        .expect("Failed to add event");
+        loop {
+            if self.rx.is_empty() {
+                // The audio side is gone once the producer is dropped together with the plugin
+                if self.rx.is_abandoned() {
+                    break;
                 }
-                TransportInfo(trx) => {
-                    self.store.write().unwrap().add_bar(trx);
+                journal::write(&mut self.journal, |j| j.flush_if_due());
+                self.tempo_estimator.update_if_due(&self.store);
+                std::thread::sleep(STORE_POLL_INTERVAL);
+                continue;
+            }
+
+            // Deliver everything that is queued up under a single lock
+            let mut store = self.store.write().unwrap();
+            while let Ok(msg) = self.rx.pop() {
+                use StoreMessage::*;
+                match msg {
+                    MidiData(time, event, voice_id) => {
+                        let timing = Timing::of(&store);
+                        if let Err(e) = store.add_voice(time, event, voice_id) {
+                            nih_warn!("Ignoring MIDI event: {}", e);
+                            continue;
+                        }
+                        if sysex::system_message(&event).is_some() {
+                            // The MIDI clock is not part of the capture, the timing it drives is
+                            timing.journal_changes(&mut self.journal, &store);
+                        } else {
+                            journal::write(&mut self.journal, |j| j.event(time, &event));
+                        }
+                    }
+                    TransportInfo(trx) => {
+                        let timing = Timing::of(&store);
+                        store.add_bar(trx);
+                        timing.journal_changes(&mut self.journal, &store);
+                    }
+                    SampleRate(start, sample_rate) => {
+                        store.set_sample_rate(start, sample_rate);
+                        journal::write(&mut self.journal, |j| j.sample_rate(start, sample_rate));
+                    }
+                    Expression(time, e) => {
+                        // Expressions without a sounding note are dropped by the store
+                        let count = store.expressions.len();
+                        store.add_expression(
+                            time,
+                            e.voice_id,
+                            u4::new(e.channel),
+                            u7::new(e.key),
+                            e.kind,
+                            e.value,
+                        );
+                        let added = store.expressions.range(count..).next_back();
+                        if let Some(record) = added.map(|expression| expression.record()) {
+                            journal::write(&mut self.journal, |j| j.expression(record));
+                        }
+                    }
+                    SysEx(chunk) => {
+                        let Some((time, message)) = self.sysex.push(&chunk) else {
+                            continue;
+                        };
+                        match store.add_sysex(time, message) {
+                            Ok(()) => journal::write(&mut self.journal, |j| j.event(time, message)),
+                            Err(e) => nih_warn!("Ignoring SysEx message: {}", e),
+                        }
+                    }
                 }
             }
+            let compaction = self.journal.as_mut().and_then(|j| j.compaction(&store));
+            drop(store);
+            // Rewriting the journal takes a while, the UI and the queue go on meanwhile
+            if let Some(snapshot) = compaction {
+                journal::write(&mut self.journal, |j| j.compact(&snapshot));
+            }
+        }
+        // Only a clean shutdown gets here, a crash leaves the journal behind for recovery
+        if let Some(journal) = self.journal.take() {
+            journal.close();
         }
+        nih_dbg!("Store delivery finished");
     }
 }
 
@@ -157,7 +342,7 @@ impl Plugin for Mucap {
     const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
+    type SysExMessage = sysex::SysEx;
     type BackgroundTask = StoreDeliveryTask;
 
     fn params(&self) -> Arc<dyn Params> {
@@ -181,10 +366,27 @@ impl Plugin for Mucap {
         _context: &mut impl InitContext<Self>,
     ) -> bool {
         if self.store_delivery_thread.is_none() {
-            let (tx, rx) = mpsc::sync_channel(16);
+            let (tx, rx) = rtrb::RingBuffer::new(STORE_QUEUE_CAPACITY);
             let store = self.store.clone();
+            let journal = if self.config.read().unwrap().get_config().journal_capture {
+                journal::journal_dir().and_then(|dir| match journal::Journal::create(&dir) {
+                    Ok(journal) => Some(journal),
+                    Err(e) => {
+                        nih_warn!("Failed to create capture journal: {}", e);
+                        None
+                    }
+                })
+            } else {
+                None
+            };
             self.store_delivery_thread = Some(std::thread::spawn(|| {
-                let mut task = StoreDeliveryTask { rx, store };
+                let mut task = StoreDeliveryTask {
+                    rx,
+                    store,
+                    journal,
+                    sysex: Default::default(),
+                    tempo_estimator: Default::default(),
+                };
                 task.run();
             }));
             self.tx = Some(tx);
@@ -204,52 +406,109 @@ impl Plugin for Mucap {
             return ProcessStatus::Normal;
         }
 
+        // A change that did not fit into the queue is sent again with the next block
+        let sample_rate = context.transport().sample_rate;
+        if sample_rate != self.sample_rate
+            && self.send(StoreMessage::SampleRate(self.samples, sample_rate))
+        {
+            self.sample_rate = sample_rate;
+        }
+
+        // The transport goes first, so the store can position the events of this block
+        if let Some(ti) = TransportInfo::from_transport(context.transport(), self.samples) {
+            self.send(StoreMessage::TransportInfo(ti));
+        }
+
         while let Some(event) = context.next_event() {
             let ev_samples = self.samples + event.timing() as i64;
-            let ev_time = ev_samples as f32 / context.transport().sample_rate;
-            if let Some(MidiResult::Basic(buf)) = event.as_midi() {
+            let voice_id = match event {
+                NoteEvent::NoteOn { voice_id, .. } | NoteEvent::NoteOff { voice_id, .. } => voice_id,
+                _ => None,
+            };
+            match event.as_midi() {
                 // If event is a MIDI message, store it
-                self.tx
-                    .iter_mut()
-                    .map(|tx| tx.send(StoreMessage::MidiData(ev_time, buf)).unwrap_or(()))
-                    .next()
-                    .unwrap_or(());
+                Some(MidiResult::Basic(buf)) => {
+                    self.send(StoreMessage::MidiData(ev_samples, buf, voice_id));
+                }
+                Some(MidiResult::SysEx(buf, len)) => match sysex::system_message(&buf[..len]) {
+                    // nih-plug hands over the MIDI clock and other system messages as SysEx
+                    Some(message) => {
+                        self.send(StoreMessage::MidiData(ev_samples, message, None));
+                    }
+                    None => {
+                        // A message missing a chunk is discarded, no need to send the rest
+                        for chunk in sysex::chunks(ev_samples, &buf[..len]) {
+                            if !self.send(StoreMessage::SysEx(chunk)) {
+                                break;
+                            }
+                        }
+                    }
+                },
+                None => {
+                    if let Some(expression) = Expression::from_event(&event) {
+                        self.send(StoreMessage::Expression(ev_samples, expression));
+                    }
+                }
             }
             // In any case, resend event so we don't block MIDI for later blocks
             context.send_event(event);
 
-            //nih_log!("Event @ {:.6}: {:?}", ev_time, event.as_midi());
+            //nih_log!("Event @ {}: {:?}", ev_samples, event.as_midi());
         }
 
         if let Some(buf) = self
             .generator
             .generate(buffer.samples() as f32 / context.transport().sample_rate)
         {
-            let ev_time = self.samples as f32 / context.transport().sample_rate;
-            /*self.tx
-                .iter_mut()
-                .map(|tx| tx.send(StoreMessage::MidiData(ev_time, buf)).unwrap_or(()))
-                .next()
-                .unwrap_or(());*/
+            //self.send(StoreMessage::MidiData(self.samples, buf));
         }
 
-        let t_old = self.time.load(Ordering::SeqCst);
         self.samples += buffer.samples() as Samples;
-        let t_now = self.samples as f32 / context.transport().sample_rate;
-        self.time.store(t_now, Ordering::SeqCst);
+        self.seconds += buffer.samples() as f64 / sample_rate as f64;
+        self.time.store(self.seconds as f32, Ordering::SeqCst);
 
         let mut rng = self.generator.rng.take().unwrap();
         if rng.random_bool(0.003) {
             nih_dbg!("Transport: {:?}", context.transport());
         }
         self.generator.rng = Some(rng);
-        if let Some(ti) = TransportInfo::from_transport(context.transport(), t_old) {
-            if let Some(tx) = &self.tx {
-                tx.send(StoreMessage::TransportInfo(ti)).unwrap_or(());
+
+        ProcessStatus::Normal
+    }
+}
+
+impl Mucap {
+    /// Hands a message to the store delivery thread without blocking or allocating.
+    ///
+    /// If the queue is full, the message is dropped and `false` is returned. Dropped messages are
+    /// counted so the UI can tell that the capture is incomplete, except for sample rate changes,
+    /// which are sent again with the next block.
+    fn send(&mut self, msg: StoreMessage) -> bool {
+        let Some(tx) = &mut self.tx else {
+            return false;
+        };
+        match tx.push(msg) {
+            Ok(()) => true,
+            Err(rtrb::PushError::Full(msg)) => {
+                if !matches!(msg, StoreMessage::SampleRate(..)) {
+                    self.dropped_events.fetch_add(1, Ordering::Relaxed);
+                }
+                false
             }
         }
+    }
+}
 
-        ProcessStatus::Normal
+impl Drop for Mucap {
+    fn drop(&mut self) {
+        // Dropping the producer ends the delivery thread, which closes the journal on its way out
+        self.tx = None;
+        let Some(thread) = self.store_delivery_thread.take() else {
+            return;
+        };
+        if thread.join().is_err() {
+            nih_warn!("Store delivery thread panicked, keeping its journal");
+        }
     }
 }
 
@@ -1,22 +1,32 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use anyhow::Result;
 use midly::MidiMessage;
-use midly::live::LiveEvent;
+use midly::live::{LiveEvent, SystemCommon, SystemRealtime};
 use midly::num::{u4, u7};
+use miniserde::{Deserialize, Serialize};
 use nih_plug::midi::{NoteEvent, sysex::SysExMessage};
-use nih_plug::nih_dbg;
+use nih_plug::{nih_dbg, nih_warn};
 
-use crate::TransportInfo;
+use crate::persist::{self, CaptureSnapshot};
+use crate::tempo_estimator::Onset;
+use crate::{Samples, TransportInfo};
 
 /// Information about a MIDI note with links to the NoteOn and NoteOff events
 #[derive(Clone)]
 pub struct Note {
-    /// Start time of the note in seconds.
-    pub t_start: f32,
+    /// Start time of the note in samples.
+    pub t_start: Samples,
     /// Index of the NoteOn event in the store.
     pub idx_on: usize,
-    /// End time of the note in seconds.
-    pub t_end: f32,
-    /// Index of the NoteOff event in the store.
+    /// End time of the note in samples.
+    pub t_end: Samples,
+    /// Index of the event that ended the note in the store.
+    ///
+    /// This is the NoteOff, or the NoteOn that retriggered the note with
+    /// [`NotePairing::Retrigger`].
     pub idx_off: usize,
     /// MIDI channel (0-15).
     pub channel: u4,
@@ -24,6 +34,160 @@ pub struct Note {
     pub key: u7,
     /// MIDI velocity (0-127).
     pub vel: u7,
+    /// End of the sound in samples, later than `t_end` if the sustain pedal held the note.
+    ///
+    /// `None` while the note is in flight or still held by the pedal.
+    pub sounding_end: Option<Samples>,
+    /// Voice id the host assigned to the note, only sent by CLAP hosts.
+    pub voice_id: Option<i32>,
+}
+
+/// A time range during which the sustain pedal (CC64) was down on a channel.
+#[derive(Clone, Debug)]
+pub struct PedalRegion {
+    pub channel: u4,
+    /// Time the pedal went down in samples.
+    pub t_start: Samples,
+    /// Time the pedal went up in samples.
+    pub t_end: Samples,
+}
+
+/// Controller number of the sustain pedal.
+pub const SUSTAIN_PEDAL: u8 = 64;
+
+/// Kind of a per-note expression, as sent by CLAP hosts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum ExpressionKind {
+    /// Gain from 0 to 4, 1 is unity gain.
+    Volume,
+    /// Panning from -1 (left) to 1 (right).
+    Pan,
+    /// Tuning in semitones from -120 to 120.
+    Tuning,
+    /// Vibrato amount from 0 to 1.
+    Vibrato,
+    /// Expression from 0 to 1.
+    Expression,
+    /// Brightness from 0 to 1.
+    Brightness,
+    /// Pressure from 0 to 1.
+    Pressure,
+}
+
+/// Expressions MPE member channels carry, see [`MidiStore::set_mpe_mode`].
+const MPE_EXPRESSIONS: [ExpressionKind; 3] = [
+    ExpressionKind::Tuning,
+    ExpressionKind::Pressure,
+    ExpressionKind::Brightness,
+];
+
+impl ExpressionKind {
+    /// Maps a value of this kind to the range 0 to 1, unity gain and center panning map to 0.5.
+    ///
+    /// Tuning has no natural range, it is mapped to 0 to 1 over +-48 semitones, the default
+    /// pitch bend range of MPE member channels.
+    pub fn normalized(self, value: f32) -> f32 {
+        match self {
+            ExpressionKind::Volume => value / 2.,
+            ExpressionKind::Pan => (value + 1.) / 2.,
+            ExpressionKind::Tuning => (value / 48. + 1.) / 2.,
+            _ => value,
+        }
+        .clamp(0., 1.)
+    }
+}
+
+/// A per-note expression value, belonging to the note started by the NoteOn at `idx_on`.
+#[derive(Clone, Debug)]
+pub struct NoteExpression {
+    /// Time in samples.
+    pub time: Samples,
+    /// Index of the NoteOn of the note, see [`Note::idx_on`].
+    pub idx_on: usize,
+    pub channel: u4,
+    pub key: u7,
+    pub kind: ExpressionKind,
+    pub value: f32,
+    /// The expression was derived from a message on an MPE member channel, which is part of
+    /// the captured events.
+    pub from_channel: bool,
+}
+
+impl NoteExpression {
+    /// The expression as kept in a [`CaptureSnapshot`], see [`CaptureSnapshot::expressions`].
+    pub fn record(&self) -> (Samples, u8, u8, ExpressionKind, f32) {
+        (self.time, self.channel.as_int(), self.key.as_int(), self.kind, self.value)
+    }
+}
+
+/// Part of the state of a channel a message sets, see [`MidiStore::channel_state_before`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChannelState {
+    Controller(u8),
+    Program,
+    PitchBend,
+    Pressure,
+}
+
+impl ChannelState {
+    /// Channel state set by `message`, `None` for note messages.
+    pub fn of(message: &MidiMessage) -> Option<Self> {
+        match message {
+            MidiMessage::Controller { controller, .. } => {
+                Some(ChannelState::Controller(controller.as_int()))
+            }
+            MidiMessage::ProgramChange { .. } => Some(ChannelState::Program),
+            MidiMessage::PitchBend { .. } => Some(ChannelState::PitchBend),
+            MidiMessage::ChannelAftertouch { .. } => Some(ChannelState::Pressure),
+            _ => None,
+        }
+    }
+}
+
+/// How MPE zones are set up, see [`MidiStore::set_mpe_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MpeMode {
+    /// No MPE, messages on all channels are loose events.
+    #[default]
+    Off,
+    /// Zones are set up by the MPE Configuration Message the controller sends.
+    Auto,
+    /// A lower zone with manager channel 1 and member channels 2 to 16.
+    Lower,
+    /// An upper zone with manager channel 16 and member channels 1 to 15.
+    Upper,
+}
+
+/// An MPE zone, see [`MidiStore::mpe_zones`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MpeZone {
+    /// Manager channel, 0 for the lower zone and 15 for the upper zone.
+    pub manager: u4,
+    /// Number of member channels, next to the manager channel.
+    pub members: u8,
+    /// Pitch bend range of the member channels in semitones.
+    pub bend_range: f32,
+}
+
+impl MpeZone {
+    /// Zone with the given manager channel, using the default member pitch bend range.
+    pub fn new(manager: u4, members: u8) -> Self {
+        Self {
+            manager,
+            members,
+            bend_range: 48.,
+        }
+    }
+
+    /// Returns whether `channel` is one of the member channels.
+    pub fn is_member(&self, channel: u4) -> bool {
+        let channel = channel.as_int();
+        if self.manager.as_int() == 0 {
+            (1..=self.members).contains(&channel)
+        } else {
+            (15 - self.members..15).contains(&channel)
+        }
+    }
 }
 
 /// A MIDI bar marker with timing information.
@@ -31,14 +195,198 @@ pub struct Note {
 pub struct Bar {
     /// Bar number as reported by DAW.
     pub bar_number: i32,
-    /// Time of the bar start in seconds.
-    pub t: f32,
+    /// Time of the bar start in samples.
+    pub t: Samples,
 }
 
+/// Regular bar grid extrapolated from the last captured bar, tempo and time signature.
+///
+/// Used for snapping where the transport was not playing and no bars were captured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GhostGrid {
+    /// Time of one of the bars in samples.
+    pub anchor: Samples,
+    /// Length of a bar in samples, at the sample rate in effect at the anchor.
+    pub bar_length: f64,
+}
+
+impl GhostGrid {
+    /// The same grid, moved by `samples`.
+    pub fn shifted(self, samples: Samples) -> Self {
+        Self {
+            anchor: self.anchor + samples,
+            ..self
+        }
+    }
+
+    /// Returns an iterator of the bar times of the grid in [s0, s1] in samples.
+    pub fn bars_in_samples(self, s0: Samples, s1: Samples) -> impl Iterator<Item = Samples> {
+        let first = ((s0 - self.anchor) as f64 / self.bar_length).ceil() as i64;
+        let last = ((s1 - self.anchor) as f64 / self.bar_length).floor() as i64;
+        (first..=last).map(move |n| self.anchor + (n as f64 * self.bar_length).round() as Samples)
+    }
+}
+
+/// One pass of the host transport through a looped region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopPass {
+    /// Number of the pass, counting from 1 for the first pass through the loop.
+    pub number: u32,
+    /// Time the transport was at the loop start in samples, or started playing if that was
+    /// inside the loop.
+    pub t_start: Samples,
+    /// Time the transport jumped back to the loop start in samples.
+    pub t_end: Samples,
+    /// Loop region in quarter notes as (start, end).
+    pub beats: (f64, f64),
+}
+
+/// Tempo and time signature reported by the host, in effect from `t` on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoChange {
+    /// First sample the tempo is in effect, the start of the block it was reported for.
+    pub t: Samples,
+    /// Tempo in BPM.
+    pub tempo: f64,
+    /// Time signature as (numerator, denominator).
+    pub time_sig: (i32, i32),
+}
+
+impl TempoChange {
+    /// Length of a bar in quarter notes.
+    pub fn bar_beats(&self) -> f64 {
+        4. * self.time_sig.0 as f64 / self.time_sig.1 as f64
+    }
+}
+
+/// Tempo and bar grid estimated from the notes of a take played without the host transport, see
+/// [`crate::tempo_estimator`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TempoEstimate {
+    /// Start of the first note of the take in samples.
+    pub t_start: Samples,
+    /// Start of the last note of the take in samples.
+    pub t_end: Samples,
+    /// Tempo in BPM.
+    pub tempo: f64,
+    /// Time signature as (numerator, denominator).
+    pub time_sig: (i32, i32),
+    /// Time of the first downbeat of the take in samples, at or before its first note.
+    pub downbeat: Samples,
+}
+
+impl TempoEstimate {
+    /// Length of a bar in quarter notes.
+    pub fn bar_beats(&self) -> f64 {
+        4. * self.time_sig.0 as f64 / self.time_sig.1 as f64
+    }
+}
+
+/// State of the MIDI clock of an external sequencer, see [`MidiStore::add_clock`].
+#[derive(Debug, Default)]
+struct MidiClock {
+    /// The sequencer plays, from Start or Continue until Stop.
+    running: bool,
+    /// Song position of the next clock tick in ticks, 24 per quarter note.
+    position: i64,
+    /// Times of the latest clock ticks in samples, for measuring the tempo.
+    ticks: VecDeque<Samples>,
+    /// Tempo measured from the clock ticks in BPM.
+    tempo: Option<f64>,
+}
+
+/// A span of time with a constant sample rate.
+///
+/// Sample counts keep running when the host changes the sample rate, so converting samples to
+/// seconds needs to know where each sample rate took effect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleRateEpoch {
+    /// First sample of the epoch.
+    pub start: Samples,
+    /// Time of the first sample in seconds.
+    pub seconds: f64,
+    /// Sample rate in Hz.
+    pub sample_rate: f32,
+}
+
+/// Sample rate assumed until the plugin reports the real one.
+const DEFAULT_SAMPLE_RATE: f32 = 48000.;
+
+/// Limits on how much captured history the MidiStore keeps.
+///
+/// When any of the limits is exceeded, the oldest events, notes and bars are evicted together.
+/// Eviction removes a bit more than strictly needed so it does not run on every single event.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Retention {
+    /// Maximum age of captured data in seconds, relative to the latest event.
+    pub max_age: Option<f32>,
+    /// Maximum (approximate) memory used by events, notes and bars in bytes.
+    pub max_bytes: Option<usize>,
+}
+
+/// How NoteOns for a key and channel that already has a note in flight are paired with NoteOffs.
+///
+/// Hosts and MPE controllers do send stacked NoteOns for the same key, this decides which notes
+/// end up in the note list. The events themselves are always stored as received.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotePairing {
+    /// Stack the notes, a NoteOff ends the oldest one.
+    Fifo,
+    /// Stack the notes, a NoteOff ends the newest one.
+    Lifo,
+    /// The new NoteOn ends the previous note.
+    #[default]
+    Retrigger,
+    /// Ignore the new NoteOn, the next NoteOff ends the first note.
+    IgnoreDuplicate,
+}
+
+/// MIDI clock ticks per quarter note.
+const CLOCK_TICKS_PER_BEAT: i64 = 24;
+
+/// Number of clock ticks the clock tempo is measured over.
+const CLOCK_TEMPO_TICKS: usize = 24;
+
+/// Fewest clock ticks the clock tempo is measured over, before that the clock is not followed.
+const MIN_CLOCK_TEMPO_TICKS: usize = 6;
+
+/// Smallest change of the measured clock tempo in BPM that counts as a tempo change, smaller
+/// ones are jitter of the clock.
+const CLOCK_TEMPO_TOLERANCE: f64 = 0.5;
+
+/// Time in seconds without clock ticks after which the clock counts as gone.
+const CLOCK_TIMEOUT: f64 = 1.0;
+
+/// Silence in seconds that separates two takes, see [`MidiStore::untimed_take`].
+const TAKE_GAP: f64 = 4.0;
+
+/// Tempo assumed for the ghost grid if the host never reported one.
+const GHOST_GRID_TEMPO: f64 = 120.;
+
+/// Difference in quarter notes between the expected and the reported transport position that
+/// counts as a jump.
+const TRANSPORT_JUMP_BEATS: f64 = 0.1;
+
+/// Longest time in seconds after the start of a block that events get a position from its transport.
+const MAX_POSITION_OFFSET: f64 = 1.0;
+
+/// Registered parameter number that selects no parameter.
+const NULL_RPN: (u8, u8) = (127, 127);
+
+/// Fraction of a retention limit that is kept after eviction.
+const RETENTION_HYSTERESIS: f32 = 0.9;
+
+/// Notes held longer than this many samples are looked up through `long_notes` in time queries.
+const LONG_NOTE: Samples = 1 << 19;
+
 /// A MIDI event stored in the MidiStore.
 pub enum StoreEntry {
     /// A MIDI message with its channel.
     MidiData { channel: u4, data: MidiMessage },
+    /// A SysEx message, the bytes are kept separately so entries stay small.
+    ///
+    /// Use [`MidiStore::sysex`] to look up the message.
+    SysEx { id: u32 },
 }
 
 /// Stores MIDI events and tracks completed notes, in-flight notes, and timing information.
@@ -47,43 +395,322 @@ pub enum StoreEntry {
 /// and maintains caches of note and time ranges for efficient querying. It also provides information
 /// about the current transport and captures bar events if they are provided.
 pub struct MidiStore {
-    /// Raw MIDI events stored as (time, entry) tuples, time is never decreasing.
-    pub store: Vec<(f32, StoreEntry)>,
-    /// Completed notes (both NoteOn and NoteOff received).
-    pub notes: Vec<Note>,
+    /// Raw MIDI events stored as (time, entry) tuples, time in samples is never decreasing.
+    ///
+    /// Use [`MidiStore::entry`] to look up events by the indices stored in notes, the front of
+    /// this queue is evicted according to the retention policy.
+    pub store: VecDeque<(Samples, StoreEntry)>,
+    /// Position of each event in `store` in quarter notes as reported by the host, `None` for
+    /// events captured while the transport was not playing. See [`MidiStore::beats`].
+    positions: VecDeque<Option<f64>>,
+    /// Number of events evicted from the front of `store` so far.
+    evicted: usize,
+    /// Indices of the events setting each channel state by (channel, state), in order.
+    state_events: BTreeMap<(u8, ChannelState), VecDeque<usize>>,
+    /// Number of indices in `state_events`.
+    state_event_count: usize,
+    /// Completed notes (both NoteOn and NoteOff received), in the order they ended.
+    ///
+    /// As notes are sorted by `t_end`, time queries binary search for the first note ending
+    /// in range and only need to look `max_note_duration` beyond the end of the range. Longer
+    /// notes are found through `long_notes`.
+    pub notes: VecDeque<Note>,
     /// Notes that have started but not yet ended (in-flight notes).
     pub in_flight: Vec<Note>,
     /// Bar markers extracted from transport information.
-    pub bars: Vec<Bar>,
+    pub bars: VecDeque<Bar>,
+    /// Passes through the loop region of the host transport, sorted by time.
+    pub loop_passes: VecDeque<LoopPass>,
+    /// Tempo and time signature changes, sorted by time.
+    ///
+    /// The first change may lie before the oldest event, it is kept as long as it is in effect.
+    pub tempo_map: VecDeque<TempoChange>,
+    /// Tempo estimates of takes played without the host transport, sorted by time.
+    pub tempo_estimates: VecDeque<TempoEstimate>,
+    /// SysEx messages as (time, bytes including 0xF0 and 0xF7), referenced by `StoreEntry::SysEx`.
+    sysex: VecDeque<(Samples, Box<[u8]>)>,
+    /// Number of SysEx messages evicted from the front of `sysex` so far.
+    sysex_evicted: usize,
+    /// Approximate memory used by the SysEx messages in bytes.
+    sysex_bytes: usize,
+    /// Per-note expressions, sorted by time.
+    pub expressions: VecDeque<NoteExpression>,
+    /// Completed sustain pedal regions, in the order they ended.
+    pub pedals: VecDeque<PedalRegion>,
+    /// Start of the current pedal region for each channel, `None` while the pedal is up.
+    pedal_down: [Option<Samples>; 16],
+    /// Limits for the captured history.
+    retention: Retention,
+    /// Pairing of stacked NoteOns with NoteOffs.
+    pairing: NotePairing,
+    /// How MPE zones are set up.
+    mpe_mode: MpeMode,
+    /// Lower and upper MPE zone.
+    mpe_zones: [Option<MpeZone>; 2],
+    /// Registered parameter number selected on each channel as (MSB, LSB).
+    rpn: [(u8, u8); 16],
+    /// Last expression values received on each channel, in the order of `MPE_EXPRESSIONS`.
+    channel_expressions: [[Option<f32>; 3]; 16],
+    /// Sample rates in effect over time, sorted by start and never empty.
+    sample_rates: Vec<SampleRateEpoch>,
     /// Last recorded bar start position in beats.
     last_bar: Option<f64>,
+    /// Time and position in quarter notes of the last transport while playing, and the time
+    /// the current pass started.
+    playhead: Option<(Samples, f64, Samples)>,
+    /// The host transport was playing in its last report.
+    host_playing: bool,
+    /// MIDI clock of an external sequencer.
+    clock: MidiClock,
     /// Cache of minimum and maximum note keys seen so far.
     note_range_cache: Option<(u7, u7)>,
-    /// Cache of minimum and maximum times seen so far.
-    time_range_cache: Option<(f32, f32)>,
+    /// Duration of the longest completed note up to `LONG_NOTE` in samples.
+    max_note_duration: Samples,
+    /// Completed notes longer than `LONG_NOTE` as (t_end, earliest t_start) for each distinct
+    /// `t_end`, in the order they ended.
+    long_notes: VecDeque<(Samples, Samples)>,
+    /// Longest time a note kept sounding after its key was released, in samples.
+    max_sustain: Samples,
+    /// Duration of the longest completed pedal region in samples.
+    max_pedal_duration: Samples,
+    /// Cache of minimum and maximum times in samples seen so far.
+    time_range_cache: Option<(Samples, Samples)>,
     /// Current transport information.
     pub transport: TransportInfo,
+    /// Number of MIDI events, expressions and transport reports the audio thread could not hand
+    /// over to the store.
+    dropped_events: Arc<AtomicUsize>,
+    /// Number of captures restored into the store so far.
+    restore_count: usize,
 }
 
 impl MidiStore {
     /// Creates a new empty MidiStore.
     pub fn new() -> Self {
         Self {
-            store: Vec::with_capacity(60000),
-            notes: Vec::with_capacity(10000),
-            bars: Vec::with_capacity(1000),
+            store: VecDeque::with_capacity(60000),
+            positions: VecDeque::with_capacity(60000),
+            evicted: 0,
+            state_events: BTreeMap::new(),
+            state_event_count: 0,
+            notes: VecDeque::with_capacity(10000),
+            sysex: VecDeque::new(),
+            sysex_evicted: 0,
+            sysex_bytes: 0,
+            bars: VecDeque::with_capacity(1000),
+            tempo_map: VecDeque::new(),
+            tempo_estimates: VecDeque::new(),
+            loop_passes: VecDeque::new(),
+            expressions: VecDeque::new(),
+            pedals: VecDeque::with_capacity(1000),
+            pedal_down: [None; 16],
+            retention: Retention::default(),
+            pairing: NotePairing::default(),
+            mpe_mode: MpeMode::default(),
+            mpe_zones: [None; 2],
+            rpn: [NULL_RPN; 16],
+            channel_expressions: [[None; 3]; 16],
+            sample_rates: vec![SampleRateEpoch {
+                start: 0,
+                seconds: 0.0,
+                sample_rate: DEFAULT_SAMPLE_RATE,
+            }],
             last_bar: None,
+            playhead: None,
+            host_playing: false,
+            clock: MidiClock::default(),
             in_flight: Vec::with_capacity(128 * 16),
             note_range_cache: None,
+            max_note_duration: 0,
+            long_notes: VecDeque::new(),
+            max_sustain: 0,
+            max_pedal_duration: 0,
             time_range_cache: None,
             transport: TransportInfo::default(),
+            dropped_events: Arc::new(AtomicUsize::new(0)),
+            restore_count: 0,
         }
     }
 
+    /// Creates a new empty MidiStore that limits its history according to `retention`.
+    pub fn with_retention(retention: Retention) -> Self {
+        let mut store = Self::new();
+        store.retention = retention;
+        store
+    }
+
+    /// Changes the retention policy, evicting old data right away if necessary.
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.enforce_retention();
+    }
+
+    /// Changes how stacked NoteOns are paired with NoteOffs, only affects notes started later.
+    pub fn set_note_pairing(&mut self, pairing: NotePairing) {
+        self.pairing = pairing;
+    }
+
+    /// Changes how MPE zones are set up.
+    ///
+    /// With MPE, pitch bend, channel pressure and CC74 on member channels are attached to the
+    /// note playing on the channel as expressions. Only affects events added later.
+    pub fn set_mpe_mode(&mut self, mode: MpeMode) {
+        self.mpe_mode = mode;
+        self.mpe_zones = match mode {
+            MpeMode::Off | MpeMode::Auto => [None; 2],
+            MpeMode::Lower => [Some(MpeZone::new(u4::new(0), 15)), None],
+            MpeMode::Upper => [None, Some(MpeZone::new(u4::new(15), 15))],
+        };
+    }
+
+    /// Returns the MPE zones currently set up.
+    pub fn mpe_zones(&self) -> impl Iterator<Item = &MpeZone> {
+        self.mpe_zones.iter().flatten()
+    }
+
+    /// Returns the event with the given index, as stored in `Note::idx_on` and `Note::idx_off`.
+    ///
+    /// Returns `None` if the event has been evicted.
+    pub fn entry(&self, idx: usize) -> Option<&(Samples, StoreEntry)> {
+        idx.checked_sub(self.evicted)
+            .and_then(|idx| self.store.get(idx))
+    }
+
+    /// Returns the host position of the event with the given index in quarter notes.
+    ///
+    /// Returns `None` if the transport was not playing when the event was captured, the event
+    /// was restored from a saved capture or it has been evicted.
+    pub fn beats(&self, idx: usize) -> Option<f64> {
+        idx.checked_sub(self.evicted)
+            .and_then(|idx| self.positions.get(idx).copied().flatten())
+    }
+
+    /// Returns an iterator over the host positions of the events in [s0, s1], as (time, position
+    /// in quarter notes) tuples, see [`MidiStore::beats`].
+    pub fn positions_in_samples(&self, s0: Samples, s1: Samples) -> impl Iterator<Item = (Samples, Option<f64>)> {
+        let first = self.store.partition_point(|(time, _)| *time < s0);
+        let last = self.store.partition_point(|(time, _)| *time <= s1);
+        self.store
+            .range(first..last.max(first))
+            .zip(self.positions.range(first..last.max(first)))
+            .map(|((time, _), beats)| (*time, *beats))
+    }
+
+    /// Position of an event at `time` in quarter notes, from the transport of the current block.
+    ///
+    /// The host sends the transport at the start of every block, before the events of the block.
+    fn position(&self, time: Samples) -> Option<f64> {
+        let transport = &self.transport;
+        let offset = (time - transport.time) as f64 / transport.sample_rate as f64;
+        // Blocks are much shorter than this, later events missed the transport of their block
+        let current = (0.0..MAX_POSITION_OFFSET).contains(&offset);
+        (transport.playing && transport.tempo > 0. && current)
+            .then(|| transport.pos_beats + offset * transport.tempo / 60.)
+    }
+
+    /// Returns the time and bytes of the SysEx message with the given id.
+    ///
+    /// Returns `None` if the message has been evicted.
+    pub fn sysex(&self, id: u32) -> Option<(Samples, &[u8])> {
+        (id as usize)
+            .checked_sub(self.sysex_evicted)
+            .and_then(|idx| self.sysex.get(idx))
+            .map(|(time, data)| (*time, &data[..]))
+    }
+
+    /// Encodes an entry as raw MIDI bytes, as accepted by [`MidiStore::add`] and
+    /// [`MidiStore::add_sysex`].
+    pub fn entry_bytes(&self, entry: &StoreEntry) -> Option<Vec<u8>> {
+        match entry {
+            StoreEntry::MidiData { channel, data } => {
+                let ev = LiveEvent::Midi {
+                    channel: *channel,
+                    message: *data,
+                };
+                let mut bytes = Vec::with_capacity(3);
+                ev.write_std(&mut bytes).ok()?;
+                Some(bytes)
+            }
+            StoreEntry::SysEx { id } => self.sysex(*id).map(|(_, data)| data.to_vec()),
+        }
+    }
+
+    /// Sample rate epochs, sorted by start.
+    pub fn sample_rate_epochs(&self) -> &[SampleRateEpoch] {
+        &self.sample_rates
+    }
+
+    /// Sets the sample rate in effect from sample `start` onwards.
+    ///
+    /// Epochs starting at or after `start` never received any samples and are replaced.
+    pub fn set_sample_rate(&mut self, start: Samples, sample_rate: f32) {
+        if self.sample_rates.last().unwrap().sample_rate == sample_rate {
+            return;
+        }
+        let seconds = self.seconds(start);
+        self.sample_rates.retain(|epoch| epoch.start < start);
+        self.sample_rates.push(SampleRateEpoch {
+            start,
+            seconds,
+            sample_rate,
+        });
+    }
+
+    /// Returns the sample rate epoch that contains sample `s`.
+    fn epoch(&self, s: Samples) -> &SampleRateEpoch {
+        let idx = self.sample_rates.partition_point(|epoch| epoch.start <= s);
+        &self.sample_rates[idx.saturating_sub(1)]
+    }
+
+    /// Returns the sample rate in effect at sample `s`.
+    pub fn sample_rate(&self, s: Samples) -> f32 {
+        self.epoch(s).sample_rate
+    }
+
+    /// Converts a sample position into seconds.
+    pub fn seconds(&self, s: Samples) -> f64 {
+        let epoch = self.epoch(s);
+        epoch.seconds + (s - epoch.start) as f64 / epoch.sample_rate as f64
+    }
+
+    /// Converts a sample position into seconds with f32 precision, e.g. for drawing.
+    pub fn time(&self, s: Samples) -> f32 {
+        self.seconds(s) as f32
+    }
+
+    /// Converts seconds into the nearest sample position.
+    ///
+    /// Infinite times saturate to the smallest or largest sample position.
+    pub fn samples_at(&self, t: f64) -> Samples {
+        let idx = self.sample_rates.partition_point(|epoch| epoch.seconds <= t);
+        let epoch = &self.sample_rates[idx.saturating_sub(1)];
+        epoch
+            .start
+            .saturating_add(((t - epoch.seconds) * epoch.sample_rate as f64).round() as Samples)
+    }
+
+    /// Returns the counter that the audio thread increments for every message it had to drop.
+    pub fn dropped_events_counter(&self) -> Arc<AtomicUsize> {
+        self.dropped_events.clone()
+    }
+
+    /// Number of MIDI events, expressions and transport reports that were lost before reaching
+    /// the store.
+    ///
+    /// If this is not zero, the capture is incomplete.
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    /// Index the next added event will get.
+    fn next_idx(&self) -> usize {
+        self.evicted + self.store.len()
+    }
+
     /// Adds a MIDI event to the store at the given time.
     ///
     /// # Arguments
-    /// * `time` - Time in seconds. Must be >= the time of the last added event.
+    /// * `time` - Time in samples. Must be >= the time of the last added event.
     /// * `data` - MIDI message as a 3-byte array.
     ///
     /// # Returns
@@ -93,62 +720,177 @@ impl MidiStore {
     /// - Parses the MIDI message and adds it to the store.
     /// - For NoteOn/NoteOff messages, updates the in-flight notes list and completed notes list.
     /// - NoteOn with velocity 0 is treated as NoteOff per MIDI 1.0 specification.
-    pub fn add(&mut self, time: f32, data: [u8; 3]) -> Result<()> {
-        if time < self.store.last().map(|e| e.0).unwrap_or(0.0) {
+    pub fn add(&mut self, time: Samples, data: [u8; 3]) -> Result<()> {
+        self.add_raw(time, &data, None)
+    }
+
+    /// Adds a MIDI event with the voice id the host assigned to its note, see [`MidiStore::add`].
+    ///
+    /// A NoteOn passes the voice id on to its note, a NoteOff ends the note with the same voice
+    /// id before falling back to the pairing policy.
+    pub fn add_voice(&mut self, time: Samples, data: [u8; 3], voice_id: Option<i32>) -> Result<()> {
+        self.add_raw(time, &data, voice_id)
+    }
+
+    /// Adds a raw MIDI message of any length to the store, see [`MidiStore::add`].
+    fn add_raw(&mut self, time: Samples, data: &[u8], voice_id: Option<i32>) -> Result<()> {
+        let beats = self.position(time);
+        self.add_positioned(time, data, voice_id, beats)
+    }
+
+    /// Adds a raw MIDI message with its host position in quarter notes.
+    fn add_positioned(
+        &mut self,
+        time: Samples,
+        data: &[u8],
+        voice_id: Option<i32>,
+        beats: Option<f64>,
+    ) -> Result<()> {
+        if time < self.store.back().map(|e| e.0).unwrap_or(Samples::MIN) {
             anyhow::bail!("Later entry exists");
         }
-        let ev = LiveEvent::parse(&data)?;
+        let ev = LiveEvent::parse(data)?;
+        match ev {
+            LiveEvent::Common(SystemCommon::SysEx(_)) => return self.push_sysex(time, data, beats),
+            LiveEvent::Common(SystemCommon::SongPosition(_)) | LiveEvent::Realtime(_) => {
+                self.add_clock(time, ev);
+                return Ok(());
+            }
+            _ => (),
+        }
         if let LiveEvent::Midi { channel, message } = ev {
             let entry = StoreEntry::MidiData {
                 channel,
                 data: message,
             };
-            self.store.push((time, entry));
+            let idx = self.next_idx();
+            self.store.push_back((time, entry));
+            self.positions.push_back(beats);
+            if let Some(state) = ChannelState::of(&message) {
+                let indices = self.state_events.entry((channel.as_int(), state)).or_default();
+                indices.push_back(idx);
+                self.state_event_count += 1;
+            }
             match message {
                 MidiMessage::NoteOn { key: _, vel } if vel == 0 => {
                     // Per MIDI 1.0 Spec: NoteOn with velocity 0 is treated as NoteOff
                     // See: http://midi.teragonaudio.com/tech/midispec/noteon.htm
-                    self.add_off(time, self.store.len() - 1, channel, message)
+                    self.add_off(time, idx, channel, message, voice_id)
                 }
                 MidiMessage::NoteOn { key: _, vel: _ } => {
-                    self.add_on(time, self.store.len() - 1, channel, message)
+                    self.add_on(time, idx, channel, message, voice_id)
                 }
                 MidiMessage::NoteOff { key: _, vel: _ } => {
-                    self.add_off(time, self.store.len() - 1, channel, message)
+                    self.add_off(time, idx, channel, message, voice_id)
+                }
+                MidiMessage::Controller { controller, value } => {
+                    self.add_controller(time, channel, controller, value)
                 }
+                MidiMessage::PitchBend { bend } => {
+                    self.add_channel_expression(time, channel, ExpressionKind::Tuning, bend.as_f32())
+                }
+                MidiMessage::ChannelAftertouch { vel } => self.add_channel_expression(
+                    time,
+                    channel,
+                    ExpressionKind::Pressure,
+                    vel.as_int() as f32 / 127.,
+                ),
                 _ => (),
             }
+            self.enforce_retention();
+        }
+        Ok(())
+    }
+
+    /// Adds a complete SysEx message, starting with 0xF0 and ending with 0xF7, to the store.
+    ///
+    /// Like [`MidiStore::add`], `time` must be >= the time of the last added event.
+    pub fn add_sysex(&mut self, time: Samples, data: &[u8]) -> Result<()> {
+        let beats = self.position(time);
+        self.push_sysex(time, data, beats)
+    }
+
+    fn push_sysex(&mut self, time: Samples, data: &[u8], beats: Option<f64>) -> Result<()> {
+        if time < self.store.back().map(|e| e.0).unwrap_or(Samples::MIN) {
+            anyhow::bail!("Later entry exists");
         }
+        validate_sysex(data)?;
+        let id = (self.sysex_evicted + self.sysex.len()) as u32;
+        self.store.push_back((time, StoreEntry::SysEx { id }));
+        self.positions.push_back(beats);
+        self.sysex_bytes += std::mem::size_of::<(Samples, Box<[u8]>)>() + data.len();
+        self.sysex.push_back((time, data.into()));
+        self.enforce_retention();
         Ok(())
     }
 
-    fn add_on(&mut self, time: f32, idx: usize, channel: u4, message: MidiMessage) {
+    fn add_on(
+        &mut self,
+        time: Samples,
+        idx: usize,
+        channel: u4,
+        message: MidiMessage,
+        voice_id: Option<i32>,
+    ) {
         if let MidiMessage::NoteOn { key, vel } = message {
-            // Check if note is already in-flight, override the existing one (TODO: correct?)
             let new_note = Note {
                 idx_on: idx,
                 t_start: time,
-                t_end: 0.0,
+                t_end: 0,
                 idx_off: 0,
                 channel,
                 key,
                 vel,
+                sounding_end: None,
+                voice_id,
             };
-            if let Some(old) = self
+            let existing = self
                 .in_flight
-                .iter_mut()
-                .filter(|note| note.key == key && note.channel == channel)
-                .next()
-            {
-                *old = new_note;
-            } else {
-                self.update_ranges(new_note.key, time);
-                self.in_flight.push(new_note);
+                .iter()
+                .position(|note| note.key == key && note.channel == channel);
+            match (self.pairing, existing) {
+                (NotePairing::IgnoreDuplicate, Some(_)) => {
+                    nih_dbg!("Ignoring duplicate Note On {} ({}) @ {}", key, channel, time);
+                    return;
+                }
+                (NotePairing::Retrigger, Some(pos)) => {
+                    let note = self.in_flight.remove(pos);
+                    self.complete_note(note, time, idx);
+                }
+                _ => (),
+            }
+            // Striking a key again cuts off its sound held by the pedal
+            self.end_sustained(time, channel, Some(key));
+            self.update_ranges(new_note.key, time);
+            // MPE controllers set up the expressions of a member channel before the NoteOn
+            if self.mpe_zones().any(|zone| zone.is_member(channel)) {
+                let values = self.channel_expressions[channel.as_int() as usize];
+                for (kind, value) in MPE_EXPRESSIONS.into_iter().zip(values) {
+                    if let Some(value) = value {
+                        self.expressions.push_back(NoteExpression {
+                            time,
+                            idx_on: idx,
+                            channel,
+                            key,
+                            kind,
+                            value,
+                            from_channel: true,
+                        });
+                    }
+                }
             }
+            self.in_flight.push(new_note);
         }
     }
 
-    fn add_off(&mut self, time: f32, idx_off: usize, channel: u4, message: MidiMessage) {
+    fn add_off(
+        &mut self,
+        time: Samples,
+        idx_off: usize,
+        channel: u4,
+        message: MidiMessage,
+        voice_id: Option<i32>,
+    ) {
         // Handle both NoteOff and NoteOn with velocity 0 (per MIDI 1.0 spec)
         let key = match message {
             MidiMessage::NoteOff { key, vel: _ } => Some(key),
@@ -157,32 +899,224 @@ impl MidiStore {
         };
 
         if let Some(key) = key {
-            if let Some((idx, _)) = self
+            // in_flight is in the order notes started
+            let mut matching = self
                 .in_flight
                 .iter()
                 .enumerate()
                 .filter(|(_, note)| note.key == key && note.channel == channel)
-                .next()
-            {
-                let mut note = self.in_flight.remove(idx);
-                note.idx_off = idx_off;
-                note.t_end = time;
-                nih_dbg!(
-                    "New note {} ({}), {:.2} s, starting at {:.2}",
-                    note.key,
-                    note.channel,
-                    note.t_end - note.t_start,
-                    note.t_start
-                );
-                self.update_ranges(note.key, note.t_end);
-                self.notes.push(note);
+                .map(|(idx, _)| idx);
+            let by_voice = voice_id.and_then(|voice_id| {
+                self.in_flight
+                    .iter()
+                    .position(|note| note.voice_id == Some(voice_id) && note.key == key)
+            });
+            let idx = by_voice.or_else(|| match self.pairing {
+                NotePairing::Lifo => matching.next_back(),
+                _ => matching.next(),
+            });
+            if let Some(idx) = idx {
+                let note = self.in_flight.remove(idx);
+                self.complete_note(note, time, idx_off);
             } else {
-                nih_dbg!("Note Off without Note On @ {:.6}", time);
+                nih_dbg!("Note Off without Note On @ {}", time);
             }
         }
     }
 
-    fn update_ranges(&mut self, note: u7, time: f32) {
+    /// Adds a per-note expression to the in-flight note it belongs to.
+    ///
+    /// The note is looked up by voice id if the host sent one, by channel and key otherwise.
+    /// Expressions for notes that are not in flight are ignored.
+    pub fn add_expression(
+        &mut self,
+        time: Samples,
+        voice_id: Option<i32>,
+        channel: u4,
+        key: u7,
+        kind: ExpressionKind,
+        value: f32,
+    ) {
+        let by_voice = voice_id.and_then(|voice_id| {
+            self.in_flight
+                .iter()
+                .rev()
+                .find(|note| note.voice_id == Some(voice_id))
+        });
+        let note = by_voice.or_else(|| {
+            self.in_flight
+                .iter()
+                .rev()
+                .find(|note| note.channel == channel && note.key == key)
+        });
+        let Some(note) = note else {
+            nih_dbg!("Expression without note {} ({}) @ {}", key, channel, time);
+            return;
+        };
+        self.expressions.push_back(NoteExpression {
+            time,
+            idx_on: note.idx_on,
+            channel: note.channel,
+            key: note.key,
+            kind,
+            value,
+            from_channel: false,
+        });
+    }
+
+    fn add_controller(&mut self, time: Samples, channel: u4, controller: u7, value: u7) {
+        let rpn = &mut self.rpn[channel.as_int() as usize];
+        match controller.as_int() {
+            SUSTAIN_PEDAL => self.set_pedal(time, channel, value >= 64),
+            101 => rpn.0 = value.as_int(),
+            100 => rpn.1 = value.as_int(),
+            // Data entry MSB
+            6 => {
+                let rpn = *rpn;
+                self.set_rpn(channel, rpn, value.as_int())
+            }
+            74 => self.add_channel_expression(
+                time,
+                channel,
+                ExpressionKind::Brightness,
+                value.as_int() as f32 / 127.,
+            ),
+            _ => (),
+        }
+    }
+
+    /// Applies the registered parameters MPE uses.
+    fn set_rpn(&mut self, channel: u4, rpn: (u8, u8), value: u8) {
+        match (rpn, channel.as_int()) {
+            // MPE Configuration Message, on the manager channel of the zone
+            ((0, 6), manager @ (0 | 15)) if self.mpe_mode == MpeMode::Auto => {
+                let (zone, other) = if manager == 0 { (0, 1) } else { (1, 0) };
+                let members = value.min(15);
+                self.mpe_zones[zone] = (members > 0).then(|| MpeZone::new(channel, members));
+                // Zones must not overlap, the other zone shrinks
+                if let Some(other_zone) = &mut self.mpe_zones[other] {
+                    other_zone.members = other_zone.members.min(14 - members.min(14));
+                    if other_zone.members == 0 {
+                        self.mpe_zones[other] = None;
+                    }
+                }
+                nih_dbg!("MPE zones: {:?}", self.mpe_zones);
+            }
+            // Pitch bend sensitivity, sent on any member channel for the whole zone
+            ((0, 0), _) => {
+                let zone = self.mpe_zones.iter_mut().flatten().find(|zone| zone.is_member(channel));
+                if let Some(zone) = zone {
+                    zone.bend_range = value as f32;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Attaches a pitch bend, pressure or timbre message on an MPE member channel to the note
+    /// playing on that channel.
+    fn add_channel_expression(&mut self, time: Samples, channel: u4, kind: ExpressionKind, value: f32) {
+        let Some(zone) = self.mpe_zones().find(|zone| zone.is_member(channel)) else {
+            return;
+        };
+        let value = match kind {
+            ExpressionKind::Tuning => value * zone.bend_range,
+            _ => value,
+        };
+        let slot = MPE_EXPRESSIONS.iter().position(|k| *k == kind).unwrap();
+        self.channel_expressions[channel.as_int() as usize][slot] = Some(value);
+        if let Some(note) = self.in_flight.iter().rev().find(|note| note.channel == channel) {
+            self.expressions.push_back(NoteExpression {
+                time,
+                idx_on: note.idx_on,
+                channel,
+                key: note.key,
+                kind,
+                value,
+                from_channel: true,
+            });
+        }
+    }
+
+    /// Ends an in-flight note at `time` by the event `idx_off`.
+    fn complete_note(&mut self, mut note: Note, time: Samples, idx_off: usize) {
+        note.idx_off = idx_off;
+        note.t_end = time;
+        note.sounding_end = match self.pedal_down[note.channel.as_int() as usize] {
+            Some(_) => None,
+            None => Some(time),
+        };
+        nih_dbg!(
+            "New note {} ({}), {} samples, starting at {}",
+            note.key,
+            note.channel,
+            note.t_end - note.t_start,
+            note.t_start
+        );
+        self.update_ranges(note.key, note.t_end);
+        self.index_duration(&note);
+        self.notes.push_back(note);
+    }
+
+    /// Accounts for the duration of a completed note in `max_note_duration` or `long_notes`.
+    fn index_duration(&mut self, note: &Note) {
+        if note.t_end - note.t_start <= LONG_NOTE {
+            self.max_note_duration = self.max_note_duration.max(note.t_end - note.t_start);
+            return;
+        }
+        match self.long_notes.back_mut() {
+            Some((t_end, t_start)) if *t_end == note.t_end => *t_start = note.t_start.min(*t_start),
+            _ => self.long_notes.push_back((note.t_end, note.t_start)),
+        }
+    }
+
+    fn set_pedal(&mut self, time: Samples, channel: u4, down: bool) {
+        let pedal = channel.as_int() as usize;
+        match (self.pedal_down[pedal], down) {
+            (None, true) => self.pedal_down[pedal] = Some(time),
+            (Some(t_start), false) => {
+                self.end_sustained(time, channel, None);
+                self.pedal_down[pedal] = None;
+                self.max_pedal_duration = self.max_pedal_duration.max(time - t_start);
+                self.pedals.push_back(PedalRegion {
+                    channel,
+                    t_start,
+                    t_end: time,
+                });
+            }
+            // Half pedaling and repeated values do not change anything
+            _ => (),
+        }
+    }
+
+    /// Ends the sound of notes held by the pedal on `channel`, only for `key` if given.
+    fn end_sustained(&mut self, time: Samples, channel: u4, key: Option<u7>) {
+        let Some(pedal_start) = self.pedal_down[channel.as_int() as usize] else {
+            return;
+        };
+        // Only notes released while the pedal is down can be held by it
+        for note in self.notes.iter_mut().rev() {
+            if note.t_end < pedal_start {
+                break;
+            }
+            if note.channel == channel
+                && note.sounding_end.is_none()
+                && key.is_none_or(|key| key == note.key)
+            {
+                note.sounding_end = Some(time);
+                self.max_sustain = self.max_sustain.max(time - note.t_end);
+            }
+        }
+    }
+
+    /// Releases the pedal on all channels at `time`.
+    fn release_pedals(&mut self, time: Samples) {
+        for channel in 0..16 {
+            self.set_pedal(time, u4::new(channel), false);
+        }
+    }
+
+    fn update_ranges(&mut self, note: u7, time: Samples) {
         // Update note range cache
         if let Some((note_min, note_max)) = self.note_range_cache {
             if note < note_min {
@@ -206,6 +1140,131 @@ impl MidiStore {
         }
     }
 
+    /// Approximate memory used by the captured events, notes and bars in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.store.len() * std::mem::size_of::<(Samples, StoreEntry)>()
+            + self.positions.len() * std::mem::size_of::<Option<f64>>()
+            + self.state_event_count * std::mem::size_of::<usize>()
+            + self.sysex_bytes
+            + (self.notes.len() + self.in_flight.len()) * std::mem::size_of::<Note>()
+            + self.long_notes.len() * std::mem::size_of::<(Samples, Samples)>()
+            + self.bars.len() * std::mem::size_of::<Bar>()
+            + self.tempo_map.len() * std::mem::size_of::<TempoChange>()
+            + self.tempo_estimates.len() * std::mem::size_of::<TempoEstimate>()
+            + self.loop_passes.len() * std::mem::size_of::<LoopPass>()
+            + self.expressions.len() * std::mem::size_of::<NoteExpression>()
+            + self.pedals.len() * std::mem::size_of::<PedalRegion>()
+    }
+
+    /// Evicts the oldest data if any of the retention limits is exceeded.
+    fn enforce_retention(&mut self) {
+        let (Some(&(t_first, _)), Some(&(t_last, _))) = (self.store.front(), self.store.back())
+        else {
+            return;
+        };
+
+        let mut cutoff: Option<Samples> = None;
+        if let Some(max_age) = self.retention.max_age {
+            let last = self.seconds(t_last);
+            if last - self.seconds(t_first) > max_age as f64 {
+                cutoff = Some(self.samples_at(last - (max_age * RETENTION_HYSTERESIS) as f64));
+            }
+        }
+        if let Some(max_bytes) = self.retention.max_bytes {
+            let usage = self.memory_usage();
+            if usage > max_bytes {
+                // Evict events proportionally to how far we are over budget
+                let keep = (self.store.len() as f32 * max_bytes as f32 * RETENTION_HYSTERESIS
+                    / usage as f32) as usize;
+                let n_evict = (self.store.len() - keep).max(1);
+                let t = self
+                    .store
+                    .get(n_evict)
+                    .map(|(t, _)| *t)
+                    .unwrap_or(Samples::MAX);
+                cutoff = Some(cutoff.map_or(t, |c| c.max(t)));
+            }
+        }
+
+        if let Some(cutoff) = cutoff {
+            self.evict_before(cutoff);
+        }
+    }
+
+    /// Removes all events, notes and bars that start before `cutoff`.
+    ///
+    /// Indices of the remaining events do not change, so `idx_on` and `idx_off` stay valid.
+    fn evict_before(&mut self, cutoff: Samples) {
+        let n_evict = self.store.partition_point(|(t, _)| *t < cutoff);
+        self.store.drain(..n_evict);
+        self.positions.drain(..n_evict);
+        self.evicted += n_evict;
+        let n_sysex = self.sysex.partition_point(|(t, _)| *t < cutoff);
+        for (_, data) in self.sysex.drain(..n_sysex) {
+            self.sysex_bytes -= std::mem::size_of::<(Samples, Box<[u8]>)>() + data.len();
+        }
+        self.sysex_evicted += n_sysex;
+
+        // Any note starting before the cutoff lost its NoteOn event
+        self.notes.retain(|note| note.t_start >= cutoff);
+        self.in_flight.retain(|note| note.t_start >= cutoff);
+        let evicted = self.evicted;
+        self.expressions.retain(|expression| expression.idx_on >= evicted);
+        for indices in self.state_events.values_mut() {
+            let n = indices.partition_point(|idx| *idx < evicted);
+            indices.drain(..n);
+            self.state_event_count -= n;
+        }
+        self.state_events.retain(|_, indices| !indices.is_empty());
+        let n_bars = self.bars.partition_point(|bar| bar.t < cutoff);
+        self.bars.drain(..n_bars);
+        let n_passes = self.loop_passes.partition_point(|pass| pass.t_start < cutoff);
+        self.loop_passes.drain(..n_passes);
+        // The tempo in effect at the cutoff is still needed for what is left
+        let n_tempo = self.tempo_map.partition_point(|change| change.t <= cutoff);
+        self.tempo_map.drain(..n_tempo.saturating_sub(1));
+        let n_estimates = self.tempo_estimates.partition_point(|estimate| estimate.t_start < cutoff);
+        self.tempo_estimates.drain(..n_estimates);
+        self.pedals.retain(|pedal| pedal.t_start >= cutoff);
+        self.max_pedal_duration = self
+            .pedals
+            .iter()
+            .map(|pedal| pedal.t_end - pedal.t_start)
+            .max()
+            .unwrap_or(0);
+
+        nih_dbg!(
+            "Evicted {} events before {}, {} events and {} notes left",
+            n_evict,
+            cutoff,
+            self.store.len(),
+            self.notes.len()
+        );
+
+        // Rebuild range caches from what is left
+        self.note_range_cache = None;
+        self.time_range_cache = None;
+        self.max_note_duration = 0;
+        self.long_notes.clear();
+        let notes = std::mem::take(&mut self.notes);
+        for note in notes.iter() {
+            self.update_ranges(note.key, note.t_start);
+            self.update_ranges(note.key, note.t_end);
+            self.index_duration(note);
+        }
+        self.max_sustain = notes
+            .iter()
+            .filter_map(|note| note.sounding_end.map(|end| end - note.t_end))
+            .max()
+            .unwrap_or(0);
+        self.notes = notes;
+        let in_flight = std::mem::take(&mut self.in_flight);
+        for note in in_flight.iter() {
+            self.update_ranges(note.key, note.t_start);
+        }
+        self.in_flight = in_flight;
+    }
+
     /// Returns the minimum and maximum MIDI note numbers seen in completed notes.
     ///
     /// Returns `None` if no notes have been completed yet.
@@ -225,60 +1284,767 @@ impl MidiStore {
     ///
     /// Returns `None` if no notes have been completed yet.
     pub fn time_range(&self) -> Option<(f32, f32)> {
-        self.time_range_cache
+        self.time_range_cache.map(|(t0, t1)| (self.time(t0), self.time(t1)))
+    }
+
+    /// Returns the start and end of a note in seconds.
+    pub fn note_time(&self, note: &Note) -> (f32, f32) {
+        (self.time(note.t_start), self.time(note.t_end))
     }
 
-    /// Returns an iterator of all notes that overlap with the time range [t0, t1].
+    /// Returns an iterator of all notes that overlap with the time range [t0, t1] in seconds.
     ///
     /// A note overlaps if `t0 < note.t_end && t1 > note.t_start`.
     pub fn notes_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &Note> {
-        self.notes.iter().filter(move |note| (t0 < note.t_end) && (t1 > note.t_start))
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        self.notes_in_samples(s0, s1)
+    }
+
+    /// Returns an iterator of all notes that overlap with the time range [s0, s1] in samples.
+    ///
+    /// Only notes longer than `LONG_NOTE` can end after `s1 + max_note_duration` and start
+    /// before `s1`, so only notes ending between `s0` and that point are checked besides them.
+    pub fn notes_in_samples(&self, s0: Samples, s1: Samples) -> impl Iterator<Item = &Note> {
+        let first = self.notes.partition_point(|note| note.t_end <= s0);
+        let horizon = s1.saturating_add(self.max_note_duration);
+        let last = self.notes.partition_point(|note| note.t_end <= horizon);
+        self.notes
+            .range(first..last.max(first))
+            .chain(self.long_notes_after(horizon.max(s0), s1))
+            .filter(move |note| (s0 < note.t_end) && (s1 > note.t_start))
+    }
+
+    /// Returns an iterator of the notes ending after `horizon` among which are all notes
+    /// starting before `s1`, given a horizon of at least `s1 + max_note_duration`.
+    fn long_notes_after(&self, horizon: Samples, s1: Samples) -> impl Iterator<Item = &Note> {
+        let first = self.long_notes.partition_point(|(t_end, _)| *t_end <= horizon);
+        self.long_notes
+            .range(first..)
+            .filter(move |(_, t_start)| *t_start < s1)
+            .flat_map(move |&(t_end, _)| {
+                let first = self.notes.partition_point(|note| note.t_end < t_end);
+                let last = self.notes.partition_point(|note| note.t_end <= t_end);
+                self.notes.range(first..last)
+            })
+    }
+
+    /// Returns an iterator of all notes whose sound overlaps with the time range [s0, s1] in samples.
+    ///
+    /// Like [`MidiStore::notes_in_samples`], but notes held by the sustain pedal count until
+    /// their `sounding_end`. Notes still held by the pedal count as sounding until now.
+    pub fn notes_sounding_in_samples(&self, s0: Samples, s1: Samples) -> impl Iterator<Item = &Note> {
+        // Notes still held by the pedal were released after it went down
+        let lower = self
+            .pedal_down
+            .iter()
+            .flatten()
+            .fold(s0.saturating_sub(self.max_sustain), |lower, t| lower.min(*t));
+        let first = self.notes.partition_point(|note| note.t_end < lower);
+        let horizon = s1.saturating_add(self.max_note_duration);
+        let last = self.notes.partition_point(|note| note.t_end <= horizon);
+        self.notes
+            .range(first..last.max(first))
+            .chain(self.long_notes_after(horizon.max(lower), s1))
+            .filter(move |note| s1 > note.t_start && note.sounding_end.is_none_or(|end| s0 < end))
+    }
+
+    /// Returns an iterator of all notes whose sound overlaps with the time range [t0, t1] in seconds.
+    pub fn notes_sounding_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &Note> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        self.notes_sounding_in_samples(s0, s1)
+    }
+
+    /// Returns an iterator of completed pedal regions overlapping the time range [t0, t1] in seconds.
+    pub fn pedals_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &PedalRegion> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        let first = self.pedals.partition_point(|pedal| pedal.t_end <= s0);
+        let horizon = s1.saturating_add(self.max_pedal_duration);
+        let last = self.pedals.partition_point(|pedal| pedal.t_end <= horizon);
+        self.pedals
+            .range(first..last.max(first))
+            .filter(move |pedal| pedal.t_start < s1)
+    }
+
+    /// Returns the channels the sustain pedal is currently down on with the time it went down.
+    pub fn open_pedals(&self) -> impl Iterator<Item = (u4, Samples)> {
+        self.pedal_down
+            .iter()
+            .enumerate()
+            .filter_map(|(channel, t)| t.map(|t| (u4::new(channel as u8), t)))
+    }
+
+    /// Returns an iterator of notes in [s0, s1] paired with a boolean indicating if they're selected.
+    ///
+    /// A note is considered "selected" if it overlaps with [sel_s0, sel_s1]. All times are in samples.
+    pub fn notes_in_time_select(&self, s0: Samples, s1: Samples, sel_s0: Samples, sel_s1: Samples) -> impl Iterator<Item = (&Note, bool)> {
+        self.notes_in_samples(s0, s1).map(move |note| (note, sel_s0 < note.t_end && sel_s1 > note.t_start))
+    }
+
+    /// Returns an iterator of the per-note expressions in the time range [t0, t1] in seconds.
+    pub fn expressions_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &NoteExpression> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        let first = self.expressions.partition_point(|expression| expression.time < s0);
+        let last = self.expressions.partition_point(|expression| expression.time <= s1);
+        self.expressions.range(first..last.max(first))
+    }
+
+    /// Returns an iterator of the expressions of `note` from its start until `until` in samples.
+    pub fn note_expressions(&self, note: &Note, until: Samples) -> impl Iterator<Item = &NoteExpression> {
+        let first = self.expressions.partition_point(|expression| expression.time < note.t_start);
+        let idx_on = note.idx_on;
+        self.expressions
+            .range(first..)
+            .take_while(move |expression| expression.time <= until)
+            .filter(move |expression| expression.idx_on == idx_on)
+    }
+
+    /// Returns an iterator over all MIDI events as (index, time, channel, message) tuples, time in samples.
+    ///
+    /// The index can be passed to [`MidiStore::entry`]. SysEx messages are not included, see
+    /// [`MidiStore::sysex_in_samples`].
+    pub fn midi_events(&self) -> impl Iterator<Item = (usize, Samples, u4, MidiMessage)> {
+        self.midi_events_in_samples(Samples::MIN, Samples::MAX)
+    }
+
+    /// Returns an iterator over the MIDI events in [s0, s1], see [`MidiStore::midi_events`].
+    pub fn midi_events_in_samples(
+        &self,
+        s0: Samples,
+        s1: Samples,
+    ) -> impl Iterator<Item = (usize, Samples, u4, MidiMessage)> {
+        let first = self.store.partition_point(|(time, _)| *time < s0);
+        let last = self.store.partition_point(|(time, _)| *time <= s1);
+        let evicted = self.evicted;
+        self.store
+            .range(first..last.max(first))
+            .enumerate()
+            .filter_map(move |(idx, (time, entry))| match entry {
+                StoreEntry::MidiData { channel, data } => {
+                    Some((evicted + first + idx, *time, *channel, *data))
+                }
+                StoreEntry::SysEx { .. } => None,
+            })
+    }
+
+    /// Returns the last message before `s` setting each channel state as (channel, state,
+    /// message), sorted by channel and state.
+    ///
+    /// Only looks up one message per channel state, no matter how long the history is.
+    pub fn channel_state_before(
+        &self,
+        s: Samples,
+    ) -> impl Iterator<Item = (u4, ChannelState, MidiMessage)> {
+        self.state_events.iter().filter_map(move |(&(channel, state), indices)| {
+            let n = indices.partition_point(|idx| self.store[idx - self.evicted].0 < s);
+            let idx = indices.get(n.checked_sub(1)?)?;
+            match self.store[idx - self.evicted].1 {
+                StoreEntry::MidiData { data, .. } => Some((u4::new(channel), state, data)),
+                StoreEntry::SysEx { .. } => None,
+            }
+        })
+    }
+
+    /// Returns an iterator over the SysEx messages in [s0, s1] as (time, bytes) tuples, time in samples.
+    ///
+    /// The bytes include the leading 0xF0 and the trailing 0xF7.
+    pub fn sysex_in_samples(&self, s0: Samples, s1: Samples) -> impl Iterator<Item = (Samples, &[u8])> {
+        let first = self.sysex.partition_point(|(time, _)| *time < s0);
+        let last = self.sysex.partition_point(|(time, _)| *time <= s1);
+        self.sysex
+            .range(first..last.max(first))
+            .map(|(time, data)| (*time, &data[..]))
+    }
+
+    /// Returns an iterator over the SysEx messages in [t0, t1] in seconds, see [`MidiStore::sysex_in_samples`].
+    pub fn sysex_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = (Samples, &[u8])> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        self.sysex_in_samples(s0, s1)
+    }
+
+    /// Adds a bar marker based on the provided transport information.
+    ///
+    /// Calculates the bar number and time, then adds it to the bars list.
+    /// Duplicate consecutive bars (within 0.01 beats) are skipped.
+    ///
+    /// While the host transport is stopped and an external sequencer sends the MIDI clock, the
+    /// clock is followed instead, see [`MidiStore::add_clock`].
+    pub fn add_bar(&mut self, transport: TransportInfo) {
+        self.host_playing = transport.playing;
+        if !transport.playing && self.clock_running(transport.time) {
+            return;
+        }
+        self.follow_transport(transport);
+    }
+
+    /// Records the tempo and bars of a transport report, from the host or the MIDI clock.
+    fn follow_transport(&mut self, transport: TransportInfo) {
+        self.add_tempo(transport.time, transport.tempo, transport.time_sig);
+        self.follow_playhead(&transport);
+        self.transport = transport;
+        if !self.transport.playing {
+            self.last_bar = None;
+            return;
+        }
+        if let Some(last_bar) = self.last_bar {
+            if (self.transport.bar_start_pos_beats - last_bar).abs() < 0.01 {
+                return;
+            }
+        }
+        nih_dbg!("self.transport Info: {:?}", &self.transport);
+        self.last_bar = Some(self.transport.bar_start_pos_beats);
+        let beats_since_bar = self.transport.pos_beats - self.transport.bar_start_pos_beats;
+        let t = self.transport.time
+            - (beats_since_bar * 60. / self.transport.tempo * self.transport.sample_rate as f64)
+                .round() as Samples;
+        let beats_per_bar = 4. * self.transport.time_sig.0 as f32 / self.transport.time_sig.1 as f32;
+        let bar_number = (self.transport.bar_start_pos_beats as f32 / beats_per_bar).round() as i32;
+
+        let bar = Bar {
+            bar_number,
+            t
+        };
+
+        nih_dbg!("Add Bar: {:?}: {}, {}", &bar, self.transport.time, t);
+        self.bars.push_back(bar);
+    }
+
+    /// Follows the MIDI clock, Start, Stop, Continue and Song Position Pointer of an external
+    /// sequencer, e.g. a drum machine.
+    ///
+    /// While the sequencer plays and the host transport does not, every clock tick is followed
+    /// like a transport report of the host, with the tempo measured over the last quarter note.
+    /// So the clock builds the tempo map and the bars, and positions the events in between.
+    fn add_clock(&mut self, time: Samples, event: LiveEvent) {
+        match event {
+            LiveEvent::Realtime(SystemRealtime::TimingClock) => self.clock_tick(time),
+            LiveEvent::Realtime(SystemRealtime::Start) => {
+                self.clock.position = 0;
+                self.clock.running = true;
+                self.clock.ticks.clear();
+            }
+            LiveEvent::Realtime(SystemRealtime::Continue) => {
+                self.clock.running = true;
+                self.clock.ticks.clear();
+            }
+            LiveEvent::Realtime(SystemRealtime::Stop) => {
+                let following = self.clock_running(time);
+                self.clock.running = false;
+                self.clock.ticks.clear();
+                if following && !self.host_playing {
+                    self.follow_transport(TransportInfo {
+                        time,
+                        sample_rate: self.sample_rate(time),
+                        ..Default::default()
+                    });
+                }
+            }
+            // Song positions count sixteenth notes
+            LiveEvent::Common(SystemCommon::SongPosition(position)) => {
+                self.clock.position = position.as_int() as i64 * CLOCK_TICKS_PER_BEAT / 4;
+            }
+            _ => (),
+        }
+    }
+
+    /// Follows a clock tick of a running MIDI clock, see [`MidiStore::add_clock`].
+    fn clock_tick(&mut self, time: Samples) {
+        if !self.clock.running {
+            return;
+        }
+        let position = self.clock.position;
+        self.clock.position += 1;
+        self.clock.ticks.push_back(time);
+        if self.clock.ticks.len() > CLOCK_TEMPO_TICKS + 1 {
+            self.clock.ticks.pop_front();
+        }
+        let n_intervals = self.clock.ticks.len() - 1;
+        if n_intervals < MIN_CLOCK_TEMPO_TICKS || self.host_playing {
+            return;
+        }
+
+        let duration = self.seconds(time) - self.seconds(self.clock.ticks[0]);
+        let measured = 60. * n_intervals as f64 / CLOCK_TICKS_PER_BEAT as f64 / duration;
+        let tempo = match self.clock.tempo {
+            Some(tempo) if (measured - tempo).abs() < CLOCK_TEMPO_TOLERANCE => tempo,
+            _ => (measured * 10.).round() / 10.,
+        };
+        self.clock.tempo = Some(tempo);
+        let time_sig = self.tempo_map.back().map_or((4, 4), |change| change.time_sig);
+        let bar_beats = 4. * time_sig.0 as f64 / time_sig.1 as f64;
+        let pos_beats = position as f64 / CLOCK_TICKS_PER_BEAT as f64;
+        self.follow_transport(TransportInfo {
+            time,
+            playing: true,
+            sample_rate: self.sample_rate(time),
+            tempo,
+            time_sig,
+            pos_beats,
+            bar_start_pos_beats: (pos_beats / bar_beats).floor() * bar_beats,
+            ..Default::default()
+        });
+    }
+
+    /// Returns whether the MIDI clock runs and ticked within [`CLOCK_TIMEOUT`] before `time`.
+    fn clock_running(&self, time: Samples) -> bool {
+        self.clock.running
+            && self
+                .clock
+                .ticks
+                .back()
+                .is_some_and(|tick| self.seconds(time) - self.seconds(*tick) < CLOCK_TIMEOUT)
+    }
+
+    /// Records a loop pass if the transport jumped back to the loop start since the last block.
+    ///
+    /// Hosts that do not report the loop region get the region estimated from the jump. Stopping
+    /// in a pass that follows a recorded one ends it as well.
+    fn follow_playhead(&mut self, transport: &TransportInfo) {
+        if !transport.playing || transport.tempo <= 0. || transport.sample_rate <= 0. {
+            if let Some((_, _, pass_start)) = self.playhead.take() {
+                self.close_pass(pass_start, transport.time);
+            }
+            return;
+        }
+        let Some((last_time, last_beats, mut pass_start)) = self.playhead else {
+            self.playhead = Some((transport.time, transport.pos_beats, transport.time));
+            return;
+        };
+        let beats_per_sample = transport.tempo / 60. / transport.sample_rate as f64;
+        let expected = last_beats + (transport.time - last_time) as f64 * beats_per_sample;
+        if transport.pos_beats < expected - TRANSPORT_JUMP_BEATS {
+            let (loop_start, loop_end) = transport.loop_range.unwrap_or((transport.pos_beats, expected));
+            // The jump happened when the transport was at the loop start
+            let t_end = (transport.time
+                - ((transport.pos_beats - loop_start) / beats_per_sample).round() as Samples)
+                .clamp(last_time, transport.time);
+            let previous = self.loop_passes.back().filter(|pass| pass.t_end == pass_start);
+            let t_start = match previous {
+                Some(_) => pass_start,
+                None => pass_start
+                    .max(t_end - ((loop_end - loop_start) / beats_per_sample).round() as Samples),
+            };
+            let number = match previous {
+                Some(pass) if (pass.beats.0 - loop_start).abs() < TRANSPORT_JUMP_BEATS => pass.number + 1,
+                _ => 1,
+            };
+            let pass = LoopPass {
+                number,
+                t_start,
+                t_end,
+                beats: (loop_start, loop_end),
+            };
+            nih_dbg!("Loop pass: {:?}", &pass);
+            self.loop_passes.push_back(pass);
+            pass_start = t_end;
+        } else if transport.pos_beats > expected + TRANSPORT_JUMP_BEATS {
+            // Jumping ahead is no loop, but ends the pass
+            pass_start = transport.time;
+        }
+        self.playhead = Some((transport.time, transport.pos_beats, pass_start));
+    }
+
+    /// Records the pass from `pass_start` to `t_end` if it follows a recorded pass.
+    fn close_pass(&mut self, pass_start: Samples, t_end: Samples) {
+        let Some(previous) = self.loop_passes.back().filter(|pass| pass.t_end == pass_start) else {
+            return;
+        };
+        if t_end <= pass_start {
+            return;
+        }
+        let pass = LoopPass {
+            number: previous.number + 1,
+            t_start: pass_start,
+            t_end,
+            beats: previous.beats,
+        };
+        nih_dbg!("Loop pass ended by stopping: {:?}", &pass);
+        self.loop_passes.push_back(pass);
+    }
+
+    /// Returns an iterator of the loop passes overlapping the time range [t0, t1] in seconds.
+    pub fn loop_passes_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &LoopPass> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        let first = self.loop_passes.partition_point(|pass| pass.t_end < s0);
+        self.loop_passes
+            .range(first..)
+            .take_while(move |pass| pass.t_start <= s1)
+    }
+
+    /// Returns the loop pass at time `t` in seconds.
+    pub fn loop_pass_at(&self, t: f32) -> Option<&LoopPass> {
+        let s = self.samples_at(t as f64);
+        self.loop_passes_in_time(t, t)
+            .find(|pass| pass.t_start <= s && s < pass.t_end)
+    }
+
+    /// Records the tempo and time signature in effect from `t` on, if they changed.
+    ///
+    /// Invalid tempos and time signatures, e.g. from hosts that do not report them, are ignored.
+    pub fn add_tempo(&mut self, t: Samples, tempo: f64, time_sig: (i32, i32)) {
+        if !tempo.is_finite() || tempo <= 0. || time_sig.0 <= 0 || time_sig.1 <= 0 {
+            return;
+        }
+        if let Some(last) = self.tempo_map.back() {
+            if last.tempo == tempo && last.time_sig == time_sig {
+                return;
+            }
+            if last.t >= t {
+                nih_warn!("Ignoring tempo change at {} before the last one at {}", t, last.t);
+                return;
+            }
+        }
+        self.tempo_map.push_back(TempoChange { t, tempo, time_sig });
     }
 
-    /// Returns an iterator of notes in [t0, t1] paired with a boolean indicating if they're selected.
+    /// Returns the tempo change in effect at sample `s`.
     ///
-    /// A note is considered "selected" if it overlaps with [sel_t0, sel_t1].
-    pub fn notes_in_time_select(&self, t0: f32, t1: f32, sel_t0: f32, sel_t1: f32) -> impl Iterator<Item = (&Note, bool)> {
-        self.notes_in_time(t0, t1).map(move |note| (note, sel_t0 < note.t_end && sel_t1 > note.t_start))
+    /// Before the first recorded change, the first one is assumed to have been in effect.
+    /// Returns `None` if no tempo has been recorded.
+    pub fn tempo_at(&self, s: Samples) -> Option<&TempoChange> {
+        let idx = self.tempo_map.partition_point(|change| change.t <= s);
+        self.tempo_map.get(idx.saturating_sub(1))
+    }
+
+    /// Returns an iterator of the tempo changes after `s0` up to and including `s1`.
+    pub fn tempo_changes_in_samples(&self, s0: Samples, s1: Samples) -> impl Iterator<Item = &TempoChange> {
+        let first = self.tempo_map.partition_point(|change| change.t <= s0);
+        let last = self.tempo_map.partition_point(|change| change.t <= s1);
+        self.tempo_map.range(first..last.max(first))
+    }
+
+    /// Returns the note starts of the latest take played while the transport was not playing,
+    /// as (time of the first, time of the last, onsets).
+    ///
+    /// A take ends where the transport was playing or nothing was played for [`TAKE_GAP`]
+    /// seconds. Only the last `max_onsets` notes are returned, `None` if the latest notes were
+    /// played to the transport.
+    pub fn untimed_take(&self, max_onsets: usize) -> Option<(Samples, Samples, Vec<Onset>)> {
+        let mut onsets = Vec::new();
+        let mut take: Option<(Samples, Samples)> = None;
+        for ((time, entry), beats) in self.store.iter().zip(self.positions.iter()).rev() {
+            let StoreEntry::MidiData { data: MidiMessage::NoteOn { vel, .. }, .. } = entry else {
+                continue;
+            };
+            if *vel == 0 {
+                continue;
+            }
+            if beats.is_some() || onsets.len() >= max_onsets {
+                break;
+            }
+            take = match take {
+                Some((start, _)) if self.seconds(start) - self.seconds(*time) > TAKE_GAP => break,
+                Some((_, end)) => Some((*time, end)),
+                None => Some((*time, *time)),
+            };
+            onsets.push(Onset {
+                seconds: self.seconds(*time),
+                weight: vel.as_int() as f64 / 127.,
+            });
+        }
+        onsets.reverse();
+        take.map(|(start, end)| (start, end, onsets))
     }
 
-    /// Returns an iterator over all MIDI events as (index, time, channel, message) tuples.
-    pub fn midi_events(&self) -> impl Iterator<Item = (usize, f32, u4, MidiMessage)> {
-        self.store.iter().enumerate().map(|(idx, (time, entry))| {
-            match entry {
-                StoreEntry::MidiData { channel, data } => (idx, *time, *channel, *data)
+    /// Beats per bar assumed for estimating the tempo of a take.
+    ///
+    /// Uses the last time signature reported by the host if its bars are whole quarter notes,
+    /// otherwise 4.
+    pub fn estimate_beats_per_bar(&self) -> u32 {
+        self.tempo_map
+            .back()
+            .map(TempoChange::bar_beats)
+            .filter(|beats| *beats >= 1. && beats.fract() == 0.)
+            .map_or(4, |beats| beats as u32)
+    }
+
+    /// Stores the tempo estimate of a take, replacing the previous estimate of the same take.
+    pub fn set_tempo_estimate(&mut self, estimate: TempoEstimate) {
+        match self.tempo_estimates.back_mut() {
+            Some(last) if last.t_end >= estimate.t_start => *last = estimate,
+            _ => self.tempo_estimates.push_back(estimate),
+        }
+    }
+
+    /// Returns the tempo estimate of the take in [s0, s1], if the transport did not play in
+    /// between.
+    ///
+    /// Where the transport was playing, its tempo is known and nothing was estimated.
+    pub fn tempo_estimate_in_samples(&self, s0: Samples, s1: Samples) -> Option<&TempoEstimate> {
+        let first_bar = self.bars.partition_point(|bar| bar.t < s0);
+        let played = self.bars.get(first_bar).is_some_and(|bar| bar.t <= s1)
+            || self.positions_in_samples(s0, s1).any(|(_, beats)| beats.is_some());
+        if played {
+            return None;
+        }
+        self.tempo_estimates
+            .iter()
+            .rev()
+            .find(|estimate| estimate.t_start <= s1 && estimate.t_end >= s0)
+    }
+
+    /// Creates a compact snapshot of the capture for storing it in the plugin state.
+    ///
+    /// Notes are not part of the snapshot, they are rebuilt from the events on restore. If the
+    /// encoded events and expressions exceed `max_bytes`, the oldest ones are left out.
+    pub fn snapshot(&self, max_bytes: usize) -> CaptureSnapshot {
+        // Expressions from member channels come back with their events
+        let mut expressions: Vec<(Samples, u8, u8, ExpressionKind, f32)> = self
+            .expressions
+            .iter()
+            .filter(|expression| !expression.from_channel)
+            .map(|expression| expression.record())
+            .collect();
+        let mut kept_expressions = expressions.len();
+        let mut records = Vec::with_capacity(self.store.len());
+        let mut size = 0;
+        let mut next_time = None;
+        for (time, entry) in self.store.iter().rev() {
+            let Some(bytes) = self.entry_bytes(entry) else {
+                continue;
+            };
+            let delta = next_time.map_or(0, |next: Samples| next - time);
+            // Expressions count from the last event before them on
+            let newer = expressions[..kept_expressions].partition_point(|(t, ..)| t < time);
+            size += persist::record_size(delta, &bytes)
+                + (kept_expressions - newer) * persist::EXPRESSION_RECORD_SIZE;
+            if size > max_bytes {
+                break;
+            }
+            kept_expressions = newer;
+            next_time = Some(*time);
+            records.push((*time, bytes));
+        }
+        records.reverse();
+        // Expressions before the first event belong to notes that are left out
+        let expressions = expressions.split_off(kept_expressions);
+
+        let first = records.first().map_or(0, |(time, _)| *time);
+        let bars = self
+            .bars
+            .iter()
+            .filter(|bar| bar.t >= first)
+            .map(|bar| (bar.bar_number, bar.t))
+            .collect();
+        let tempo_start = self.tempo_at(first).map_or(first, |change| change.t);
+        let tempo_map = self
+            .tempo_map
+            .iter()
+            .filter(|change| change.t >= tempo_start)
+            .map(|change| (change.t, change.tempo, change.time_sig))
+            .collect();
+
+        CaptureSnapshot {
+            sample_rates: self
+                .sample_rates
+                .iter()
+                .map(|epoch| (epoch.start, epoch.seconds, epoch.sample_rate))
+                .collect(),
+            first,
+            events: persist::encode_events(&records),
+            bars,
+            tempo_map,
+            tempo: self.transport.tempo,
+            time_sig: self.transport.time_sig,
+            expressions,
+        }
+    }
+
+    /// Number of captures restored so far, changes whenever [`MidiStore::restore`] succeeds.
+    pub fn restore_count(&self) -> usize {
+        self.restore_count
+    }
+
+    /// Restores a capture from a snapshot in front of everything captured so far.
+    ///
+    /// The restored events keep their exact spacing but are moved to end shortly before
+    /// the earliest event, bar or sample rate epoch already in the store, i.e. they usually
+    /// end up at negative sample positions. Events already in the store are kept as they are.
+    pub fn restore(&mut self, snapshot: &CaptureSnapshot) -> Result<()> {
+        let events = persist::decode_events(snapshot.first, &snapshot.events)?;
+        let Some(last) = events.last().map(|(time, _)| *time) else {
+            return Ok(());
+        };
+        // Validate before touching the store, so a broken snapshot leaves it untouched
+        for (_, data) in events.iter() {
+            validate_event(data)?;
+        }
+        let last = snapshot
+            .bars
+            .last()
+            .map_or(last, |(_, t)| last.max(*t));
+
+        // Leave a second of silence between the restored capture and what is already there
+        let current = self.sample_rates[0];
+        let restored_rate = snapshot
+            .sample_rates
+            .last()
+            .map_or(DEFAULT_SAMPLE_RATE, |(_, _, rate)| *rate);
+        let first_bar = self.bars.front().map_or(current.start, |bar| bar.t);
+        let first_event = self.store.front().map_or(current.start, |(t, _)| *t);
+        let end = current.start.min(first_bar).min(first_event) - restored_rate as Samples;
+        let offset = end - last;
+
+        // Chain the restored sample rates backwards from the current epoch, so time is
+        // continuous where the restored capture hands over to the current session
+        let mut epochs: Vec<SampleRateEpoch> = snapshot
+            .sample_rates
+            .iter()
+            .map(|(start, _, sample_rate)| SampleRateEpoch {
+                start: start + offset,
+                seconds: 0.0,
+                sample_rate: *sample_rate,
+            })
+            .filter(|epoch| epoch.start < current.start)
+            .collect();
+        let mut next = current;
+        for epoch in epochs.iter_mut().rev() {
+            epoch.seconds = next.seconds - (next.start - epoch.start) as f64 / epoch.sample_rate as f64;
+            next = *epoch;
+        }
+        epochs.append(&mut self.sample_rates);
+        self.sample_rates = epochs;
+
+        // Voice ids and expressions are not part of the events, they are carried over to the
+        // replayed notes by the index of their NoteOn
+        let mut voices: Vec<(usize, i32)> = self
+            .notes
+            .iter()
+            .chain(self.in_flight.iter())
+            .filter_map(|note| note.voice_id.map(|voice_id| (note.idx_on, voice_id)))
+            .collect();
+        voices.sort_unstable();
+        let captured_start = self.evicted;
+        // Expressions from member channels are rebuilt from the events
+        let mut expressions: Vec<NoteExpression> = std::mem::take(&mut self.expressions)
+            .into_iter()
+            .filter(|expression| !expression.from_channel)
+            .collect();
+
+        // Events captured so far are replayed after the restored ones to rebuild the notes
+        let captured: Vec<(Samples, Vec<u8>, Option<f64>)> = self
+            .store
+            .iter()
+            .zip(self.positions.iter())
+            .filter_map(|((time, entry), beats)| {
+                self.entry_bytes(entry).map(|bytes| (*time, bytes, *beats))
+            })
+            .collect();
+        self.evicted += self.store.len();
+        self.store.clear();
+        self.positions.clear();
+        self.state_events.clear();
+        self.state_event_count = 0;
+        self.sysex_evicted += self.sysex.len();
+        self.sysex.clear();
+        self.sysex_bytes = 0;
+        self.notes.clear();
+        self.max_note_duration = 0;
+        self.long_notes.clear();
+        self.max_sustain = 0;
+        self.in_flight.clear();
+        self.pedals.clear();
+        self.max_pedal_duration = 0;
+        self.pedal_down = [None; 16];
+        self.rpn = [NULL_RPN; 16];
+        self.channel_expressions = [[None; 3]; 16];
+        if self.mpe_mode == MpeMode::Auto {
+            self.mpe_zones = [None; 2];
+        }
+
+        // Host positions are not part of the snapshot
+        for (time, data) in events.iter() {
+            self.add_positioned(time + offset, data, None, None)?;
+        }
+        // Notes and pedals that never ended in the snapshot will not end now
+        self.in_flight.clear();
+        self.release_pedals(last + offset);
+        let shift = self.next_idx() - captured_start;
+        for expression in expressions.iter_mut() {
+            expression.idx_on += shift;
+        }
+        // Expressions of the snapshot belong to the restored note sounding on their channel
+        // and key, the latest one if several are
+        for (time, channel, key, kind, value) in snapshot.expressions.iter() {
+            let time = time + offset;
+            let note = self
+                .notes_in_samples(time, time + 1)
+                .filter(|note| note.channel.as_int() == *channel && note.key.as_int() == *key)
+                .max_by_key(|note| note.t_start);
+            if let Some(note) = note {
+                expressions.push(NoteExpression {
+                    time,
+                    idx_on: note.idx_on,
+                    channel: note.channel,
+                    key: note.key,
+                    kind: *kind,
+                    value: *value,
+                    from_channel: false,
+                });
             }
-        })
-    }
-
-    /// Adds a bar marker based on the provided transport information.
-    ///
-    /// Calculates the bar number and time, then adds it to the bars list.
-    /// Duplicate consecutive bars (within 0.01 beats) are skipped.
-    pub fn add_bar(&mut self, transport: TransportInfo) {
-        self.transport = transport;
-        if !self.transport.playing {
-            self.last_bar = None;
-            return;
         }
-        if let Some(last_bar) = self.last_bar {
-            if (self.transport.bar_start_pos_beats - last_bar).abs() < 0.01 {
-                return;
+        for (time, data, beats) in captured.iter() {
+            self.add_positioned(*time, data, None, *beats)?;
+        }
+        self.expressions.extend(expressions);
+        self.expressions.make_contiguous().sort_by_key(|expression| expression.time);
+        for note in self.notes.iter_mut().chain(self.in_flight.iter_mut()) {
+            let pos = note
+                .idx_on
+                .checked_sub(shift)
+                .and_then(|idx| voices.binary_search_by_key(&idx, |(idx, _)| *idx).ok());
+            if let Some(pos) = pos {
+                note.voice_id = Some(voices[pos].1);
             }
         }
-        nih_dbg!("self.transport Info: {:?}", &self.transport);
-        self.last_bar = Some(self.transport.bar_start_pos_beats);
-        let t = self.transport.time - (self.transport.pos_beats as f32 - self.transport.bar_start_pos_beats as f32) * 60. / self.transport.tempo as f32;
-        let beats_per_bar = 4. * self.transport.time_sig.0 as f32 / self.transport.time_sig.1 as f32;
-        let bar_number = (self.transport.bar_start_pos_beats as f32 / beats_per_bar).round() as i32;
 
-        let bar = Bar {
-            bar_number,
-            t
+        for (bar_number, t) in snapshot.bars.iter().rev() {
+            self.bars.push_front(Bar {
+                bar_number: *bar_number,
+                t: t + offset,
+            });
+        }
+        // Older snapshots only know the tempo at the time they were taken
+        let restored_tempo: Vec<TempoChange> = if snapshot.tempo_map.is_empty() {
+            vec![TempoChange {
+                t: snapshot.first,
+                tempo: snapshot.tempo,
+                time_sig: snapshot.time_sig,
+            }]
+        } else {
+            snapshot
+                .tempo_map
+                .iter()
+                .map(|(t, tempo, time_sig)| TempoChange {
+                    t: *t,
+                    tempo: *tempo,
+                    time_sig: *time_sig,
+                })
+                .collect()
         };
-
-        nih_dbg!("Add Bar: {:?}: {}, {}", &bar, self.transport.time, t);
-        self.bars.push(bar);
+        // The tempo in effect before the current session only needs to cover what was captured
+        if let Some(front) = self.tempo_map.front_mut() {
+            front.t = front.t.max(end + 1);
+        }
+        let current = std::mem::take(&mut self.tempo_map);
+        for change in restored_tempo.iter().filter(|change| change.t + offset <= end) {
+            self.add_tempo(change.t + offset, change.tempo, change.time_sig);
+        }
+        for change in current {
+            self.add_tempo(change.t, change.tempo, change.time_sig);
+        }
+        if self.transport.tempo == 0.0 {
+            self.transport.tempo = snapshot.tempo;
+            self.transport.time_sig = snapshot.time_sig;
+        }
+        self.enforce_retention();
+        self.restore_count += 1;
+
+        nih_dbg!(
+            "Restored {} events and {} notes from snapshot",
+            self.store.len(),
+            self.notes.len()
+        );
+        Ok(())
     }
 
     /// Returns an iterator of bars whose bar_number is divisible by n.
@@ -288,13 +2054,94 @@ impl MidiStore {
         self.bars.iter().filter(move |bar| bar.bar_number % n == 0)
     }
 
-    /// Finds the nearest bar (filtered by n) to the given time.
+    /// Returns an iterator of the bars in the time range [t0, t1] in seconds.
+    pub fn bars_in_time(&self, t0: f32, t1: f32) -> impl Iterator<Item = &Bar> {
+        let (s0, s1) = (self.samples_at(t0 as f64), self.samples_at(t1 as f64));
+        let first = self.bars.partition_point(|bar| bar.t < s0);
+        let last = self.bars.partition_point(|bar| bar.t <= s1);
+        self.bars.range(first..last.max(first))
+    }
+
+    /// Finds the nearest bar (filtered by n) to the given time in seconds.
     ///
     /// Returns the bar whose time is closest to the given time,
     /// considering only bars where bar_number % n == 0.
     pub fn nearest_bar(&self, time: f32, n: i32) -> Option<&Bar> {
-        self.get_bars(n).reduce(|b1, b2| if (b1.t - time).abs() < (b2.t - time).abs() { b1 } else { b2 })
+        self.nearest_bar_in_samples(self.samples_at(time as f64), n)
+    }
+
+    /// Like [`MidiStore::nearest_bar`], but for a time in samples.
+    pub fn nearest_bar_in_samples(&self, s: Samples, n: i32) -> Option<&Bar> {
+        let split = self.bars.partition_point(|bar| bar.t < s);
+        let before = self.bars.range(..split).rev().find(|bar| bar.bar_number % n == 0);
+        let after = self.bars.range(split..).find(|bar| bar.bar_number % n == 0);
+        match (before, after) {
+            (Some(b1), Some(b2)) => Some(if (b1.t - s).abs() < (b2.t - s).abs() { b1 } else { b2 }),
+            (b1, b2) => b1.or(b2),
+        }
+    }
+
+    /// Bar grid continuing the last captured bar with the last known tempo and time signature.
+    ///
+    /// If a take was played without the transport after the last captured bar, the grid follows
+    /// its estimated tempo and downbeat instead. Without any captured bar, the grid starts at the
+    /// start of the session. Without any known tempo, 120 BPM in 4/4 is assumed.
+    pub fn ghost_grid(&self) -> GhostGrid {
+        let last_bar = self.bars.back().map(|bar| bar.t);
+        if let Some(estimate) = self
+            .tempo_estimates
+            .back()
+            .filter(|estimate| last_bar.is_none_or(|t| t < estimate.t_start))
+        {
+            return GhostGrid {
+                anchor: estimate.downbeat,
+                bar_length: estimate.bar_beats() * 60. / estimate.tempo
+                    * self.sample_rate(estimate.downbeat) as f64,
+            };
+        }
+        let (tempo, time_sig) = self
+            .tempo_map
+            .back()
+            .map_or((GHOST_GRID_TEMPO, (4, 4)), |change| (change.tempo, change.time_sig));
+        let beats_per_bar = 4. * time_sig.0 as f64 / time_sig.1 as f64;
+        let anchor = self.bars.back().map_or(0, |bar| bar.t);
+        GhostGrid {
+            anchor,
+            bar_length: beats_per_bar * 60. / tempo * self.sample_rate(anchor) as f64,
+        }
+    }
+
+    /// Returns an iterator of the bars of `grid` in [s0, s1] in samples, leaving out those that
+    /// are closer than a bar to a captured bar.
+    pub fn ghost_bars_in_samples(
+        &self,
+        grid: GhostGrid,
+        s0: Samples,
+        s1: Samples,
+    ) -> impl Iterator<Item = Samples> {
+        grid.bars_in_samples(s0, s1).filter(move |s| {
+            self.nearest_bar_in_samples(*s, 1)
+                .is_none_or(|bar| (bar.t - s).abs() as f64 >= 0.99 * grid.bar_length)
+        })
+    }
+}
+
+/// Checks that `data` is a MIDI message the store accepts.
+pub fn validate_event(data: &[u8]) -> Result<()> {
+    if let LiveEvent::Common(SystemCommon::SysEx(_)) = LiveEvent::parse(data)? {
+        validate_sysex(data)?;
+    }
+    Ok(())
+}
+
+fn validate_sysex(data: &[u8]) -> Result<()> {
+    let [0xF0, payload @ .., 0xF7] = data else {
+        anyhow::bail!("SysEx message not enclosed in 0xF0 and 0xF7");
+    };
+    if payload.iter().any(|byte| *byte > 0x7F) {
+        anyhow::bail!("Status byte inside SysEx message");
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -309,15 +2156,22 @@ mod tests {
         [0x80 | channel, key, vel]
     }
 
+    /// Store running at 1 kHz, so event times in the tests are in milliseconds
+    fn test_store() -> MidiStore {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store
+    }
+
     #[test]
     fn test_simple_note_on_off() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
         assert_eq!(store.store.len(), 1);
 
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 0);
         assert_eq!(store.store.len(), 2);
         assert_eq!(store.notes.len(), 1);
@@ -325,16 +2179,16 @@ mod tests {
 
     #[test]
     fn test_multiple_sequential_notes() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
 
-        store.add(2.0, note_on(0, 60, 90)).unwrap();
-        store.add(3.0, note_off(0, 60, 0)).unwrap();
+        store.add(2000, note_on(0, 60, 90)).unwrap();
+        store.add(3000, note_off(0, 60, 0)).unwrap();
 
-        store.add(4.0, note_on(0, 60, 80)).unwrap();
-        store.add(5.0, note_off(0, 60, 0)).unwrap();
+        store.add(4000, note_on(0, 60, 80)).unwrap();
+        store.add(5000, note_off(0, 60, 0)).unwrap();
 
         assert_eq!(store.in_flight.len(), 0);
         assert_eq!(store.store.len(), 6);
@@ -343,21 +2197,21 @@ mod tests {
 
     #[test]
     fn test_overlapping_notes_different_keys() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
-        store.add(0.5, note_on(0, 64, 90)).unwrap();
-        store.add(0.75, note_on(0, 67, 80)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(500, note_on(0, 64, 90)).unwrap();
+        store.add(750, note_on(0, 67, 80)).unwrap();
 
         assert_eq!(store.in_flight.len(), 3);
 
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 2);
 
-        store.add(1.5, note_off(0, 67, 0)).unwrap();
+        store.add(1500, note_off(0, 67, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
 
-        store.add(2.0, note_off(0, 64, 0)).unwrap();
+        store.add(2000, note_off(0, 64, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 0);
 
         assert_eq!(store.store.len(), 6);
@@ -365,43 +2219,102 @@ mod tests {
     }
 
     #[test]
-    fn test_note_override_same_key_channel() {
-        // Test that NoteOn for same key/channel overrides existing note
-        let mut store = MidiStore::new();
+    fn test_note_retrigger_same_key_channel() {
+        // Test that NoteOn for same key/channel ends the existing note by default
+        let mut store = test_store();
 
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
         assert_eq!(store.in_flight[0].vel, u7::new(100));
 
-        // Send another NoteOn on same key - should override
-        store.add(0.5, note_on(0, 60, 80)).unwrap();
+        // Send another NoteOn on same key - should retrigger
+        store.add(500, note_on(0, 60, 80)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
         assert_eq!(store.in_flight[0].vel, u7::new(80));
-        assert_eq!(store.in_flight[0].t_start, 0.5);
-
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        assert_eq!(store.in_flight[0].t_start, 500);
         assert_eq!(store.notes.len(), 1);
-        assert_eq!(store.notes[0].vel, u7::new(80));
+        assert_eq!(store.notes[0].vel, u7::new(100));
+        assert_eq!(store.notes[0].t_end, 500);
+        assert_eq!(store.notes[0].idx_off, 1);
+
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        assert_eq!(store.notes.len(), 2);
+        assert_eq!(store.notes[1].vel, u7::new(80));
+
+        // The second NoteOff has no note left to end
+        store.add(1100, note_off(0, 60, 0)).unwrap();
+        assert_eq!(store.notes.len(), 2);
+    }
+
+    /// Two stacked NoteOns on the same key followed by two NoteOffs
+    fn stacked_notes(pairing: NotePairing) -> MidiStore {
+        let mut store = test_store();
+        store.set_note_pairing(pairing);
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(500, note_on(0, 60, 80)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        store.add(1500, note_off(0, 60, 0)).unwrap();
+        store
+    }
+
+    fn note_spans(store: &MidiStore) -> Vec<(Samples, Samples, u8)> {
+        store
+            .notes
+            .iter()
+            .map(|note| (note.t_start, note.t_end, note.vel.as_int()))
+            .collect()
+    }
+
+    #[test]
+    fn test_note_pairing_fifo() {
+        let store = stacked_notes(NotePairing::Fifo);
+        assert_eq!(note_spans(&store), vec![(0, 1000, 100), (500, 1500, 80)]);
+        assert!(store.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_note_pairing_lifo() {
+        let store = stacked_notes(NotePairing::Lifo);
+        assert_eq!(note_spans(&store), vec![(500, 1000, 80), (0, 1500, 100)]);
+        assert!(store.in_flight.is_empty());
+        // Notes stay sorted by end time
+        assert_eq!(store.notes_in_samples(1200, 1300).count(), 1);
+    }
+
+    #[test]
+    fn test_note_pairing_retrigger() {
+        let store = stacked_notes(NotePairing::Retrigger);
+        assert_eq!(note_spans(&store), vec![(0, 500, 100), (500, 1000, 80)]);
+        assert!(store.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_note_pairing_ignore_duplicate() {
+        let store = stacked_notes(NotePairing::IgnoreDuplicate);
+        assert_eq!(note_spans(&store), vec![(0, 1000, 100)]);
+        assert!(store.in_flight.is_empty());
+        // All events are kept regardless
+        assert_eq!(store.store.len(), 4);
     }
 
     #[test]
     fn test_multiple_channels() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // Channel 0 (0x90 = 10010000, channel 0)
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
         // Channel 1 (0x91 = 10010001, channel 1)
-        store.add(0.1, note_on(1, 60, 90)).unwrap();
+        store.add(100, note_on(1, 60, 90)).unwrap();
 
         assert_eq!(store.in_flight.len(), 2);
 
         // Turn off channel 0
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
         assert_eq!(store.in_flight[0].channel, u4::new(1));
 
         // Turn off channel 1
-        store.add(1.5, note_off(1, 60, 0)).unwrap();
+        store.add(1500, note_off(1, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 0);
         assert_eq!(store.notes.len(), 2);
     }
@@ -409,35 +2322,35 @@ mod tests {
     #[test]
     fn test_note_on_velocity_zero_as_note_off() {
         // NoteOn with velocity 0 should be treated as NoteOff per MIDI 1.0 spec
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
 
         // NoteOn with vel=0 should properly close the note
-        store.add(1.0, note_on(0, 60, 0)).unwrap();
+        store.add(1000, note_on(0, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 0);
         assert_eq!(store.notes.len(), 1);
-        assert_eq!(store.notes[0].t_end, 1.0);
+        assert_eq!(store.notes[0].t_end, 1000);
     }
 
     #[test]
     fn test_time_order_violation() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(1.0, note_on(0, 60, 100)).unwrap();
+        store.add(1000, note_on(0, 60, 100)).unwrap();
 
         // Attempting to add event in the past should fail
-        let result = store.add(0.5, note_off(0, 60, 0));
+        let result = store.add(500, note_off(0, 60, 0));
         assert!(result.is_err());
     }
 
     #[test]
     fn test_note_off_without_note_on() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // NoteOff without corresponding NoteOn
-        store.add(0.0, note_off(0, 60, 0)).unwrap();
+        store.add(0, note_off(0, 60, 0)).unwrap();
 
         // Should not crash, note should be ignored (logged)
         assert_eq!(store.in_flight.len(), 0);
@@ -447,17 +2360,17 @@ mod tests {
 
     #[test]
     fn test_note_properties() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
-        store.add(0.5, note_on(0, 72, 95)).unwrap();
-        store.add(1.5, note_off(0, 72, 0)).unwrap();
+        store.add(500, note_on(0, 72, 95)).unwrap();
+        store.add(1500, note_off(0, 72, 0)).unwrap();
 
         assert_eq!(store.notes.len(), 1);
         let note = &store.notes[0];
         assert_eq!(note.key, u7::new(72));
         assert_eq!(note.vel, u7::new(95));
-        assert_eq!(note.t_start, 0.5);
-        assert_eq!(note.t_end, 1.5);
+        assert_eq!(note.t_start, 500);
+        assert_eq!(note.t_end, 1500);
         assert_eq!(note.idx_on, 0);
         assert_eq!(note.idx_off, 1);
         assert_eq!(note.channel, u4::new(0));
@@ -466,10 +2379,10 @@ mod tests {
     #[test]
     fn test_non_note_midi_events() {
         // Test that non-note MIDI events are stored but don't affect notes
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // Control Change message (0xB0 = CC on channel 0, 0x07 = volume, 0x40 = value 64)
-        store.add(0.0, [0xB0, 0x07, 0x40]).unwrap();
+        store.add(0, [0xB0, 0x07, 0x40]).unwrap();
 
         assert_eq!(store.in_flight.len(), 0);
         assert_eq!(store.notes.len(), 0);
@@ -478,99 +2391,101 @@ mod tests {
 
     #[test]
     fn test_mixed_channels_and_keys() {
-        // Complex scenario: overlapping notes on different channels/keys with some overrides
-        let mut store = MidiStore::new();
+        // Complex scenario: overlapping notes on different channels/keys with a retrigger
+        let mut store = test_store();
 
         // Channel 0, key 60
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
         // Channel 1, key 60 (different channel, should not conflict)
-        store.add(0.2, note_on(1, 60, 90)).unwrap();
+        store.add(200, note_on(1, 60, 90)).unwrap();
         // Channel 0, key 64
-        store.add(0.3, note_on(0, 64, 85)).unwrap();
-        // Channel 0, key 60 again (override first note)
-        store.add(0.5, note_on(0, 60, 75)).unwrap();
+        store.add(300, note_on(0, 64, 85)).unwrap();
+        // Channel 0, key 60 again (retriggers the first note)
+        store.add(500, note_on(0, 60, 75)).unwrap();
 
         assert_eq!(store.in_flight.len(), 3);
+        assert_eq!(store.notes.len(), 1);
+        assert_eq!(store.notes[0].vel, u7::new(100));
 
-        // Turn off channel 0, key 60 (should remove the overridden note)
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        // Turn off channel 0, key 60 (should end the retriggered note)
+        store.add(1000, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 2);
-        assert_eq!(store.notes.len(), 1);
-        assert_eq!(store.notes[0].vel, u7::new(75)); // Should be the override velocity
+        assert_eq!(store.notes.len(), 2);
+        assert_eq!(store.notes[1].vel, u7::new(75)); // Should be the retrigger velocity
 
         // Turn off channel 1, key 60
-        store.add(1.2, note_off(1, 60, 0)).unwrap();
+        store.add(1200, note_off(1, 60, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 1);
-        assert_eq!(store.notes.len(), 2);
+        assert_eq!(store.notes.len(), 3);
 
         // Turn off channel 0, key 64
-        store.add(1.5, note_off(0, 64, 0)).unwrap();
+        store.add(1500, note_off(0, 64, 0)).unwrap();
         assert_eq!(store.in_flight.len(), 0);
-        assert_eq!(store.notes.len(), 3);
+        assert_eq!(store.notes.len(), 4);
     }
 
     #[test]
     fn test_note_range() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // Empty store should return None
         assert_eq!(store.note_range(), None);
 
         // Single note
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.note_range(), Some((u7::new(60), u7::new(60))));
 
         // Multiple notes with range
-        store.add(2.0, note_on(0, 55, 100)).unwrap();
-        store.add(3.0, note_off(0, 55, 0)).unwrap();
+        store.add(2000, note_on(0, 55, 100)).unwrap();
+        store.add(3000, note_off(0, 55, 0)).unwrap();
         assert_eq!(store.note_range(), Some((u7::new(55), u7::new(60))));
 
-        store.add(4.0, note_on(0, 72, 100)).unwrap();
-        store.add(5.0, note_off(0, 72, 0)).unwrap();
+        store.add(4000, note_on(0, 72, 100)).unwrap();
+        store.add(5000, note_off(0, 72, 0)).unwrap();
         assert_eq!(store.note_range(), Some((u7::new(55), u7::new(72))));
     }
 
     #[test]
     fn test_time_range() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // Empty store should return None
         assert_eq!(store.time_range(), None);
 
         // Single note (0.5 - 1.5)
-        store.add(0.5, note_on(0, 60, 100)).unwrap();
-        store.add(1.5, note_off(0, 60, 0)).unwrap();
+        store.add(500, note_on(0, 60, 100)).unwrap();
+        store.add(1500, note_off(0, 60, 0)).unwrap();
         assert_eq!(store.time_range(), Some((0.5, 1.5)));
 
         // Add earlier note (0.1 - 0.9) - extends minimum
-        store.add(2.0, note_on(0, 55, 100)).unwrap();
-        store.add(2.9, note_off(0, 55, 0)).unwrap();
+        store.add(2000, note_on(0, 55, 100)).unwrap();
+        store.add(2900, note_off(0, 55, 0)).unwrap();
         // At this point, we have notes ending at 2.9, so max extends
         assert_eq!(store.time_range(), Some((0.5, 2.9)));
 
         // Add later note (3.0 - 5.5) - extends maximum
-        store.add(3.0, note_on(0, 72, 100)).unwrap();
-        store.add(5.5, note_off(0, 72, 0)).unwrap();
+        store.add(3000, note_on(0, 72, 100)).unwrap();
+        store.add(5500, note_off(0, 72, 0)).unwrap();
         assert_eq!(store.time_range(), Some((0.5, 5.5)));
     }
 
     #[test]
     fn test_notes_in_time() {
-        let mut store = MidiStore::new();
+        let mut store = test_store();
 
         // Create notes at different time ranges:
         // Note 1: 0.0-1.0
-        store.add(0.0, note_on(0, 60, 100)).unwrap();
-        store.add(1.0, note_off(0, 60, 0)).unwrap();
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
 
         // Note 2: 2.0-3.0
-        store.add(2.0, note_on(0, 64, 100)).unwrap();
-        store.add(3.0, note_off(0, 64, 0)).unwrap();
+        store.add(2000, note_on(0, 64, 100)).unwrap();
+        store.add(3000, note_off(0, 64, 0)).unwrap();
 
         // Note 3: 3.5-5.0
-        store.add(3.5, note_on(0, 67, 100)).unwrap();
-        store.add(5.0, note_off(0, 67, 0)).unwrap();
+        store.add(3500, note_on(0, 67, 100)).unwrap();
+        store.add(5000, note_off(0, 67, 0)).unwrap();
 
         // Query before any notes
         let notes = store.notes_in_time(-1.0, 0.0).collect::<Vec<_>>();
@@ -609,4 +2524,910 @@ mod tests {
         assert_eq!(notes.len(), 1);
         assert_eq!(notes[0].key, u7::new(64));
     }
+
+    fn retention_store(retention: Retention) -> MidiStore {
+        let mut store = MidiStore::with_retention(retention);
+        store.set_sample_rate(0, 1000.);
+        store
+    }
+
+    #[test]
+    fn test_retention_max_age() {
+        let mut store = retention_store(Retention {
+            max_age: Some(10.0),
+            max_bytes: None,
+        });
+
+        for i in 0..20 {
+            let t = i * 1000;
+            store.add(t, note_on(0, 60 + i as u8, 100)).unwrap();
+            store.add(t + 500, note_off(0, 60 + i as u8, 0)).unwrap();
+        }
+
+        // Nothing older than the limit survives, but eviction leaves some headroom
+        let t_first = store.store.front().unwrap().0;
+        assert!(t_first >= 19500 - 10000);
+        assert!(store.notes.iter().all(|note| note.t_start >= t_first));
+
+        // Range caches only cover remaining notes
+        let (t0, t1) = store.time_range().unwrap();
+        assert!(t0 >= store.time(t_first));
+        assert_eq!(t1, 19.5);
+        let (n0, n1) = store.note_range().unwrap();
+        assert!(n0.as_int() > 60);
+        assert_eq!(n1, u7::new(79));
+    }
+
+    #[test]
+    fn test_retention_keeps_indices_valid() {
+        let mut store = retention_store(Retention {
+            max_age: Some(5.0),
+            max_bytes: None,
+        });
+
+        for i in 0..20 {
+            let t = i * 1000;
+            store.add(t, note_on(0, 60, 100)).unwrap();
+            store.add(t + 500, note_off(0, 60, 0)).unwrap();
+        }
+        // Start a note that stays in flight while eviction happens
+        store.add(20000, note_on(0, 72, 100)).unwrap();
+        store.add(30000, [0xB0, 0x07, 0x40]).unwrap();
+
+        assert!(store.notes.len() < 20);
+        for note in store.notes.iter() {
+            let (t_on, on) = store.entry(note.idx_on).unwrap();
+            let (t_off, off) = store.entry(note.idx_off).unwrap();
+            assert_eq!(*t_on, note.t_start);
+            assert_eq!(*t_off, note.t_end);
+            assert!(matches!(on, StoreEntry::MidiData { data: MidiMessage::NoteOn { .. }, .. }));
+            assert!(matches!(off, StoreEntry::MidiData { data: MidiMessage::NoteOff { .. }, .. }));
+        }
+
+        // Evicted events can no longer be looked up
+        assert!(store.entry(0).is_none());
+
+        // Events reported by midi_events() are addressable by their index
+        for (idx, time, _, _) in store.midi_events() {
+            assert_eq!(store.entry(idx).unwrap().0, time);
+        }
+    }
+
+    #[test]
+    fn test_retention_evicts_in_flight_and_bars() {
+        let mut store = retention_store(Retention {
+            max_age: Some(5.0),
+            max_bytes: None,
+        });
+
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.bars.push_back(Bar { bar_number: 0, t: 0 });
+        store.bars.push_back(Bar { bar_number: 1, t: 8000 });
+        store.add(10000, [0xB0, 0x07, 0x40]).unwrap();
+
+        assert_eq!(store.in_flight.len(), 0);
+        assert_eq!(store.bars.len(), 1);
+        assert_eq!(store.bars[0].bar_number, 1);
+
+        // The matching NoteOff is now a NoteOff without NoteOn
+        store.add(11000, note_off(0, 60, 0)).unwrap();
+        assert_eq!(store.notes.len(), 0);
+    }
+
+    #[test]
+    fn test_retention_max_bytes() {
+        // Room for 100 events with their host positions
+        let budget =
+            100 * (std::mem::size_of::<(Samples, StoreEntry)>() + std::mem::size_of::<Option<f64>>());
+        let mut store = retention_store(Retention {
+            max_age: None,
+            max_bytes: Some(budget),
+        });
+
+        for i in 0..1000 {
+            store.add(i * 10, [0xB0, 0x07, (i % 128) as u8]).unwrap();
+        }
+
+        assert!(store.memory_usage() <= budget);
+        assert!(store.store.len() > 50);
+        assert_eq!(store.store.back().unwrap().0, 9990);
+    }
+
+    #[test]
+    fn test_set_retention_evicts_immediately() {
+        let mut store = test_store();
+        for i in 0..100 {
+            store.add(i * 1000, [0xB0, 0x07, 0x40]).unwrap();
+        }
+        assert_eq!(store.store.len(), 100);
+
+        store.set_retention(Retention {
+            max_age: Some(10.0),
+            max_bytes: None,
+        });
+        assert!(store.store.len() <= 11);
+    }
+
+    #[test]
+    fn test_sample_rate_epochs() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 48000.);
+        store.set_sample_rate(48000 * 10, 96000.);
+
+        assert_eq!(store.seconds(24000), 0.5);
+        assert_eq!(store.seconds(48000 * 10), 10.0);
+        assert_eq!(store.seconds(48000 * 10 + 96000), 11.0);
+        assert_eq!(store.sample_rate(48000 * 10 - 1), 48000.);
+        assert_eq!(store.sample_rate(48000 * 10), 96000.);
+
+        assert_eq!(store.samples_at(0.5), 24000);
+        assert_eq!(store.samples_at(11.0), 48000 * 10 + 96000);
+        assert_eq!(store.samples_at(f64::NEG_INFINITY), Samples::MIN);
+        assert_eq!(store.samples_at(f64::INFINITY), Samples::MAX);
+
+        // Setting the same rate again does not start a new epoch
+        store.set_sample_rate(48000 * 20, 96000.);
+        assert_eq!(store.sample_rates.len(), 2);
+
+        // An epoch without samples gets replaced
+        store.set_sample_rate(48000 * 10, 44100.);
+        assert_eq!(store.sample_rates.len(), 2);
+        assert_eq!(store.seconds(48000 * 10 + 44100), 11.0);
+    }
+
+    #[test]
+    fn test_sample_accuracy_after_hours() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 48000.);
+
+        // Ten hours in, notes one sample apart still keep their distance
+        let t = 48000 * 60 * 60 * 10;
+        store.add(t, note_on(0, 60, 100)).unwrap();
+        store.add(t + 1, note_off(0, 60, 0)).unwrap();
+
+        let note = &store.notes[0];
+        assert_eq!(note.t_end - note.t_start, 1);
+        let dt = store.seconds(note.t_end) - store.seconds(note.t_start);
+        assert!((dt - 1. / 48000.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_dropped_events_counter() {
+        let store = test_store();
+        assert_eq!(store.dropped_events(), 0);
+
+        let counter = store.dropped_events_counter();
+        counter.fetch_add(3, Ordering::Relaxed);
+        assert_eq!(store.dropped_events(), 3);
+    }
+
+    /// Store with overlapping notes of random length on 4 keys and a bar every second
+    fn random_store(n_notes: usize) -> MidiStore {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut store = test_store();
+        let mut pending: Vec<(Samples, u8)> = Vec::new();
+        let mut time = 0;
+        for _ in 0..n_notes {
+            time += rng.random_range(0..200);
+            pending.retain(|(t_end, key)| {
+                let done = *t_end <= time;
+                if done {
+                    store.add(*t_end, note_off(0, *key, 0)).unwrap();
+                }
+                !done
+            });
+            pending.sort();
+            let key = 60 + rng.random_range(0..4);
+            if pending.iter().all(|(_, k)| *k != key) {
+                store.add(time, note_on(0, key, 100)).unwrap();
+                pending.push((time + rng.random_range(1..5000), key));
+            }
+        }
+        for bar in 0..(time / 1000) {
+            store.bars.push_back(Bar {
+                bar_number: bar as i32,
+                t: bar * 1000,
+            });
+        }
+        store
+    }
+
+    #[test]
+    fn test_indexed_queries_match_scan() {
+        let store = random_store(2000);
+        let end = store.time_range_cache.unwrap().1;
+        for s0 in (-1000..end + 1000).step_by(777) {
+            for len in [0, 1, 100, 2500, 10000] {
+                let s1 = s0 + len;
+                let indexed: Vec<usize> = store.notes_in_samples(s0, s1).map(|n| n.idx_on).collect();
+                let scanned: Vec<usize> = store
+                    .notes
+                    .iter()
+                    .filter(|note| s0 < note.t_end && s1 > note.t_start)
+                    .map(|n| n.idx_on)
+                    .collect();
+                assert_eq!(indexed, scanned);
+
+                let indexed: Vec<usize> = store.midi_events_in_samples(s0, s1).map(|e| e.0).collect();
+                let scanned: Vec<usize> = store
+                    .midi_events()
+                    .filter(|(_, time, _, _)| *time >= s0 && *time <= s1)
+                    .map(|e| e.0)
+                    .collect();
+                assert_eq!(indexed, scanned);
+                for idx in indexed {
+                    let (time, _) = store.entry(idx).unwrap();
+                    assert!(*time >= s0 && *time <= s1);
+                }
+
+                let (t0, t1) = (store.time(s0), store.time(s1));
+                assert_eq!(
+                    store.bars_in_time(t0, t1).count(),
+                    store.bars.iter().filter(|bar| bar.t >= s0 && bar.t <= s1).count()
+                );
+            }
+
+            let t = store.time(s0);
+            for n in [1, 4, 7] {
+                let scanned = store.get_bars(n).reduce(|b1, b2| {
+                    if (b1.t - s0).abs() < (b2.t - s0).abs() { b1 } else { b2 }
+                });
+                assert_eq!(
+                    store.nearest_bar(t, n).map(|bar| bar.t),
+                    scanned.map(|bar| bar.t)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_note_duration_after_eviction() {
+        let mut store = test_store();
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(10000, note_off(0, 60, 0)).unwrap();
+        store.add(20000, note_on(0, 61, 100)).unwrap();
+        store.add(20100, note_off(0, 61, 0)).unwrap();
+        assert_eq!(store.max_note_duration, 10000);
+        assert_eq!(store.notes_in_samples(5000, 6000).count(), 1);
+
+        store.evict_before(15000);
+        assert_eq!(store.max_note_duration, 100);
+        assert_eq!(store.notes_in_samples(20050, 20060).count(), 1);
+    }
+
+    #[test]
+    fn test_long_notes() {
+        let mut store = test_store();
+        store.add(0, note_on(1, 24, 100)).unwrap();
+        store.add(10, note_on(1, 25, 100)).unwrap();
+        for idx in 0..400 {
+            store.add(idx * 1000 + 100, note_on(0, 60, 100)).unwrap();
+            store.add(idx * 1000 + 200, note_off(0, 60, 0)).unwrap();
+        }
+        // Two drones ending together, one of them held by the pedal
+        store.add(LONG_NOTE + 5000, pedal(1, true)).unwrap();
+        store.add(LONG_NOTE + 6000, note_off(1, 24, 0)).unwrap();
+        store.add(LONG_NOTE + 6000, note_off(1, 25, 0)).unwrap();
+        store.add(LONG_NOTE + 7000, pedal(1, false)).unwrap();
+        assert_eq!(store.max_note_duration, 100);
+        assert_eq!(store.long_notes, [(LONG_NOTE + 6000, 0)]);
+
+        let keys = |notes: Vec<&Note>| notes.iter().map(|note| note.key.as_int()).collect::<Vec<_>>();
+        assert_eq!(keys(store.notes_in_samples(5, 8).collect()), [24]);
+        assert_eq!(keys(store.notes_in_samples(50000, 50500).collect()), [60, 24, 25]);
+        assert_eq!(keys(store.notes_sounding_in_samples(50000, 50500).collect()), [60, 24, 25]);
+        assert_eq!(keys(store.notes_sounding_in_samples(LONG_NOTE + 6500, LONG_NOTE + 6600).collect()), [24, 25]);
+        assert_eq!(store.notes_in_samples(LONG_NOTE + 6500, LONG_NOTE + 6600).count(), 0);
+
+        store.evict_before(5);
+        assert_eq!(store.long_notes, [(LONG_NOTE + 6000, 10)]);
+        assert_eq!(keys(store.notes_in_samples(50000, 50500).collect()), [60, 25]);
+    }
+
+    fn pedal(channel: u8, down: bool) -> [u8; 3] {
+        [0xB0 | channel, 64, if down { 127 } else { 0 }]
+    }
+
+    #[test]
+    fn test_sustain_pedal_sounding_end() {
+        let mut store = test_store();
+
+        // Released before the pedal goes down, not held
+        store.add(0, note_on(0, 48, 100)).unwrap();
+        store.add(50, note_off(0, 48, 0)).unwrap();
+        store.add(60, pedal(0, true)).unwrap();
+        store.add(100, note_on(0, 60, 100)).unwrap();
+        store.add(200, note_off(0, 60, 0)).unwrap();
+        store.add(300, note_on(0, 64, 100)).unwrap();
+        store.add(400, note_off(0, 64, 0)).unwrap();
+        // Other channels are not affected by the pedal
+        store.add(450, note_on(1, 67, 100)).unwrap();
+        store.add(500, note_off(1, 67, 0)).unwrap();
+        // Half pedaling does not release
+        store.add(600, [0xB0, 64, 100]).unwrap();
+        assert_eq!(store.notes[1].sounding_end, None);
+        assert_eq!(store.open_pedals().collect::<Vec<_>>(), vec![(u4::new(0), 60)]);
+
+        store.add(1000, pedal(0, false)).unwrap();
+        store.add(1100, note_on(0, 72, 100)).unwrap();
+        store.add(1200, note_off(0, 72, 0)).unwrap();
+
+        let ends: Vec<Option<Samples>> = store.notes.iter().map(|n| n.sounding_end).collect();
+        assert_eq!(ends, vec![Some(50), Some(1000), Some(1000), Some(500), Some(1200)]);
+        assert_eq!(store.pedals.len(), 1);
+        assert_eq!((store.pedals[0].t_start, store.pedals[0].t_end), (60, 1000));
+        assert_eq!(store.open_pedals().count(), 0);
+    }
+
+    #[test]
+    fn test_sustain_pedal_restrike() {
+        let mut store = test_store();
+        store.add(0, pedal(0, true)).unwrap();
+        store.add(100, note_on(0, 60, 100)).unwrap();
+        store.add(200, note_off(0, 60, 0)).unwrap();
+        // Striking the same key again ends the held sound
+        store.add(500, note_on(0, 60, 100)).unwrap();
+        store.add(600, note_off(0, 60, 0)).unwrap();
+        store.add(900, pedal(0, false)).unwrap();
+
+        assert_eq!(store.notes[0].sounding_end, Some(500));
+        assert_eq!(store.notes[1].sounding_end, Some(900));
+    }
+
+    #[test]
+    fn test_notes_sounding_in_samples() {
+        let mut store = test_store();
+        store.add(0, pedal(0, true)).unwrap();
+        store.add(100, note_on(0, 60, 100)).unwrap();
+        store.add(200, note_off(0, 60, 0)).unwrap();
+        store.add(250, note_on(0, 62, 100)).unwrap();
+        store.add(300, note_off(0, 62, 0)).unwrap();
+
+        // Still held by the pedal
+        assert_eq!(store.notes_in_samples(500, 600).count(), 0);
+        assert_eq!(store.notes_sounding_in_samples(500, 600).count(), 2);
+
+        store.add(1000, pedal(0, false)).unwrap();
+        store.add(5000, note_on(0, 64, 100)).unwrap();
+        store.add(5100, note_off(0, 64, 0)).unwrap();
+        assert_eq!(store.notes_sounding_in_samples(500, 600).count(), 2);
+        assert_eq!(store.notes_sounding_in_samples(999, 1200).count(), 2);
+        assert_eq!(store.notes_sounding_in_samples(1000, 1200).count(), 0);
+        assert_eq!(store.notes_sounding_in_samples(1000, 5050).count(), 1);
+        assert_eq!(store.pedals_in_time(0.5, 0.6).count(), 1);
+        assert_eq!(store.pedals_in_time(1.5, 2.0).count(), 0);
+    }
+
+    #[test]
+    fn test_sustain_pedal_eviction() {
+        let mut store = test_store();
+        store.add(0, pedal(0, true)).unwrap();
+        store.add(100, note_on(0, 60, 100)).unwrap();
+        store.add(200, note_off(0, 60, 0)).unwrap();
+        store.add(1000, pedal(0, false)).unwrap();
+        store.add(2000, pedal(0, true)).unwrap();
+        store.add(2100, note_on(0, 60, 100)).unwrap();
+        store.add(2200, note_off(0, 60, 0)).unwrap();
+
+        store.evict_before(1500);
+        assert_eq!(store.pedals.len(), 0);
+        assert_eq!(store.max_sustain, 0);
+        // The pedal that is still down keeps holding notes
+        store.add(3000, pedal(0, false)).unwrap();
+        assert_eq!(store.notes[0].sounding_end, Some(3000));
+        assert_eq!(store.pedals.len(), 1);
+    }
+
+    #[test]
+    fn test_sysex_stored_separately() {
+        let mut store = test_store();
+        let sysex = [0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7];
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add_sysex(500, &sysex).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+
+        assert_eq!(store.store.len(), 3);
+        assert_eq!(store.notes.len(), 1);
+        assert_eq!(store.notes[0].idx_off, 2);
+        // SysEx is not a channel message and only shows up in its own query
+        assert_eq!(store.midi_events().count(), 2);
+        let listed: Vec<(Samples, &[u8])> = store.sysex_in_samples(0, 1000).collect();
+        assert_eq!(listed, vec![(500, &sysex[..])]);
+        assert_eq!(store.sysex_in_samples(600, 1000).count(), 0);
+        assert_eq!(store.entry_bytes(&store.entry(1).unwrap().1), Some(sysex.to_vec()));
+
+        // Unterminated messages and stray status bytes are rejected
+        assert!(store.add_sysex(2000, &[0xF0, 0x7E, 0x7F]).is_err());
+        assert!(store.add_sysex(2000, &[0xF0, 0x90, 0x7F, 0xF7]).is_err());
+        assert_eq!(store.store.len(), 3);
+
+        // Snapshots keep SysEx
+        let mut restored = test_store();
+        restored.restore(&store.snapshot(usize::MAX)).unwrap();
+        assert_eq!(restored.store.len(), 3);
+        let (time, data) = restored.sysex_in_samples(Samples::MIN, Samples::MAX).next().unwrap();
+        assert_eq!(time, restored.notes[0].t_start + 500);
+        assert_eq!(data, &sysex[..]);
+    }
+
+    #[test]
+    fn test_sysex_eviction() {
+        let mut store = test_store();
+        let sysex = [0xF0, 0x41, 0x10, 0x42, 0xF7];
+        for t in 0..10 {
+            store.add(t * 1000, note_on(0, 60, 100)).unwrap();
+            store.add_sysex(t * 1000 + 100, &sysex).unwrap();
+            store.add(t * 1000 + 500, note_off(0, 60, 0)).unwrap();
+        }
+        let usage = store.memory_usage();
+        store.set_retention(Retention {
+            max_age: Some(4.0),
+            max_bytes: None,
+        });
+        assert!(store.memory_usage() < usage);
+
+        let first = store.store.front().unwrap().0;
+        let listed: Vec<Samples> = store.sysex_in_samples(Samples::MIN, Samples::MAX).map(|(t, _)| t).collect();
+        assert!(listed.iter().all(|t| *t >= first));
+        assert_eq!(listed.len(), store.store.iter().filter(|(_, e)| matches!(e, StoreEntry::SysEx { .. })).count());
+        // Ids of the remaining messages still resolve
+        for (_, entry) in store.store.iter() {
+            if let StoreEntry::SysEx { id } = entry {
+                assert_eq!(store.sysex(*id).unwrap().1, &sysex[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_voice_ids_pair_notes() {
+        let mut store = test_store();
+        store.set_note_pairing(NotePairing::Fifo);
+        store.add_voice(0, note_on(0, 60, 100), Some(7)).unwrap();
+        store.add_voice(100, note_on(0, 60, 90), Some(8)).unwrap();
+        // The voice id decides which of the stacked notes ends
+        store.add_voice(500, note_off(0, 60, 0), Some(8)).unwrap();
+        assert_eq!(store.notes[0].voice_id, Some(8));
+        assert_eq!(store.notes[0].t_start, 100);
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        assert_eq!(store.notes[1].voice_id, Some(7));
+    }
+
+    #[test]
+    fn test_expressions_attach_to_notes() {
+        let mut store = test_store();
+        store.add_voice(0, note_on(0, 60, 100), Some(1)).unwrap();
+        store.add(0, note_on(0, 64, 100)).unwrap();
+        store.add_expression(100, Some(1), u4::new(0), u7::new(60), ExpressionKind::Tuning, 0.5);
+        // Without a matching voice id, channel and key decide
+        store.add_expression(200, Some(5), u4::new(0), u7::new(64), ExpressionKind::Pan, -1.);
+        // No note for this one
+        store.add_expression(300, None, u4::new(1), u7::new(64), ExpressionKind::Pan, 1.);
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        store.add(1000, note_off(0, 64, 0)).unwrap();
+        store.add_expression(1100, Some(1), u4::new(0), u7::new(60), ExpressionKind::Tuning, 1.);
+
+        assert_eq!(store.expressions.len(), 2);
+        let c = &store.notes[0];
+        let e = &store.notes[1];
+        assert_eq!(store.note_expressions(c, Samples::MAX).map(|e| e.value).collect::<Vec<_>>(), vec![0.5]);
+        assert_eq!(store.note_expressions(e, Samples::MAX).map(|e| e.kind).collect::<Vec<_>>(), vec![ExpressionKind::Pan]);
+        assert_eq!(store.note_expressions(c, 50).count(), 0);
+        assert_eq!(store.expressions_in_time(0.15, 0.25).count(), 1);
+        assert_eq!(ExpressionKind::Pan.normalized(-1.), 0.);
+        assert_eq!(ExpressionKind::Volume.normalized(1.), 0.5);
+    }
+
+    #[test]
+    fn test_expressions_survive_restore_and_evict_with_notes() {
+        let mut snapshot_store = test_store();
+        snapshot_store.add(0, note_on(0, 50, 100)).unwrap();
+        snapshot_store.add(100, note_off(0, 50, 0)).unwrap();
+        let snapshot = snapshot_store.snapshot(usize::MAX);
+
+        let mut store = test_store();
+        store.add_voice(0, note_on(0, 60, 100), Some(3)).unwrap();
+        store.add_expression(100, Some(3), u4::new(0), u7::new(60), ExpressionKind::Vibrato, 0.7);
+        store.restore(&snapshot).unwrap();
+        store.add_voice(1000, note_off(0, 60, 0), Some(3)).unwrap();
+
+        let note = store.notes.back().unwrap();
+        assert_eq!(note.key, u7::new(60));
+        assert_eq!(note.voice_id, Some(3));
+        assert_eq!(store.note_expressions(note, Samples::MAX).count(), 1);
+
+        store.set_retention(Retention {
+            max_age: Some(0.5),
+            max_bytes: None,
+        });
+        assert!(store.notes.is_empty());
+        assert!(store.expressions.is_empty());
+    }
+
+    fn controller(channel: u8, controller: u8, value: u8) -> [u8; 3] {
+        [0xB0 | channel, controller, value]
+    }
+
+    /// MPE Configuration Message for the zone managed by `manager`
+    fn mcm(store: &mut MidiStore, time: Samples, manager: u8, members: u8) {
+        store.add(time, controller(manager, 101, 0)).unwrap();
+        store.add(time, controller(manager, 100, 6)).unwrap();
+        store.add(time, controller(manager, 6, members)).unwrap();
+    }
+
+    #[test]
+    fn test_mpe_zones_from_mcm() {
+        let mut store = test_store();
+        mcm(&mut store, 0, 0, 7);
+        assert_eq!(store.mpe_zones().count(), 0);
+
+        store.set_mpe_mode(MpeMode::Auto);
+        mcm(&mut store, 0, 0, 7);
+        let zones: Vec<MpeZone> = store.mpe_zones().copied().collect();
+        assert_eq!(zones, vec![MpeZone::new(u4::new(0), 7)]);
+        assert!(zones[0].is_member(u4::new(7)));
+        assert!(!zones[0].is_member(u4::new(8)));
+
+        // The upper zone takes what is left, a larger lower zone then shrinks it
+        mcm(&mut store, 0, 15, 10);
+        assert_eq!(store.mpe_zones[1].unwrap().members, 10);
+        assert_eq!(store.mpe_zones[0].unwrap().members, 4);
+        mcm(&mut store, 0, 0, 14);
+        assert!(store.mpe_zones[1].is_none());
+
+        // Pitch bend range is set on a member channel
+        store.add(0, controller(3, 101, 0)).unwrap();
+        store.add(0, controller(3, 100, 0)).unwrap();
+        store.add(0, controller(3, 6, 24)).unwrap();
+        assert_eq!(store.mpe_zones[0].unwrap().bend_range, 24.);
+
+        store.set_mpe_mode(MpeMode::Upper);
+        assert_eq!(store.mpe_zones().copied().collect::<Vec<_>>(), vec![MpeZone::new(u4::new(15), 15)]);
+    }
+
+    #[test]
+    fn test_mpe_member_channels_attach_to_notes() {
+        let mut store = test_store();
+        store.set_mpe_mode(MpeMode::Lower);
+        // Initial values are sent before the NoteOn
+        store.add(0, [0xE1, 0x00, 0x40]).unwrap();
+        store.add(0, [0xD1, 0x00, 0x00]).unwrap();
+        store.add(0, note_on(1, 60, 100)).unwrap();
+        store.add(0, note_on(2, 64, 100)).unwrap();
+        // Half way up on channel 2, pressure and timbre on channel 3
+        store.add(100, [0xE1, 0x00, 0x60]).unwrap();
+        store.add(200, [0xD2, 0x7F, 0x00]).unwrap();
+        store.add(300, controller(2, 74, 127)).unwrap();
+        // The manager channel and notes that are not playing are left alone
+        store.add(300, [0xE0, 0x00, 0x60]).unwrap();
+        store.add(400, note_off(1, 60, 0)).unwrap();
+        store.add(500, [0xE1, 0x00, 0x40]).unwrap();
+        store.add(600, note_off(2, 64, 0)).unwrap();
+
+        let expressions = |key: u8| -> Vec<(Samples, ExpressionKind, f32)> {
+            let note = store.notes.iter().find(|note| note.key == u7::new(key)).unwrap();
+            store
+                .note_expressions(note, Samples::MAX)
+                .map(|e| (e.time, e.kind, e.value))
+                .collect()
+        };
+        assert_eq!(
+            expressions(60),
+            vec![
+                (0, ExpressionKind::Tuning, 0.),
+                (0, ExpressionKind::Pressure, 0.),
+                (100, ExpressionKind::Tuning, 24.),
+            ]
+        );
+        assert_eq!(
+            expressions(64),
+            vec![(200, ExpressionKind::Pressure, 1.), (300, ExpressionKind::Brightness, 1.)]
+        );
+        // All events are still stored as received
+        assert_eq!(store.store.len(), 11);
+
+        // Expressions are rebuilt from the events on restore
+        let mut restored = test_store();
+        restored.set_mpe_mode(MpeMode::Lower);
+        restored.restore(&store.snapshot(usize::MAX)).unwrap();
+        assert_eq!(restored.expressions.len(), store.expressions.len());
+    }
+
+    #[test]
+    fn test_tempo_map() {
+        let mut store = test_store();
+        store.add_tempo(0, 120., (4, 4));
+        store.add_tempo(500, 120., (4, 4));
+        store.add_tempo(600, 0., (4, 4));
+        store.add_tempo(1000, 60., (3, 4));
+        store.add_tempo(2000, 90., (3, 4));
+        assert_eq!(store.tempo_map.len(), 3);
+        assert_eq!(store.tempo_at(-100).unwrap().tempo, 120.);
+        assert_eq!(store.tempo_at(999).unwrap().tempo, 120.);
+        assert_eq!(store.tempo_at(1000).unwrap().time_sig, (3, 4));
+        assert_eq!(store.tempo_changes_in_samples(0, 2000).map(|change| change.t).collect::<Vec<_>>(), vec![1000, 2000]);
+
+        for time in (0..3000).step_by(100) {
+            store.add(time, [0xB0, 1, 0]).unwrap();
+        }
+        // The tempo in effect at the cutoff is kept
+        store.evict_before(1500);
+        assert_eq!(store.tempo_map.iter().map(|change| change.t).collect::<Vec<_>>(), vec![1000, 2000]);
+        assert_eq!(store.tempo_at(1500).unwrap().tempo, 60.);
+
+        // The restored capture keeps its tempo map in front of the current one
+        let mut restored = test_store();
+        restored.add_tempo(0, 140., (4, 4));
+        restored.restore(&store.snapshot(usize::MAX)).unwrap();
+        let offset = restored.store[0].0 - store.store[0].0;
+        assert_eq!(restored.tempo_at(1500 + offset).unwrap().tempo, 60.);
+        assert_eq!(restored.tempo_at(2500 + offset).unwrap().tempo, 90.);
+        assert_eq!(restored.tempo_at(0).unwrap().tempo, 140.);
+        assert_eq!(restored.tempo_map.len(), 3);
+    }
+
+    #[test]
+    fn test_event_positions() {
+        let mut store = test_store();
+        let transport = |time, playing| TransportInfo {
+            time,
+            playing,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (4, 4),
+            pos_beats: 8.,
+            bar_start_pos_beats: 8.,
+            ..Default::default()
+        };
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add_bar(transport(100, true));
+        store.add(100, note_off(0, 60, 0)).unwrap();
+        store.add(350, [0xB0, 1, 0]).unwrap();
+        store.add_sysex(600, &[0xF0, 0x7E, 0xF7]).unwrap();
+        // Events long after the last transport did not get the transport of their block
+        store.add(1500, [0xB0, 1, 0]).unwrap();
+        store.add_bar(transport(2000, false));
+        store.add(2000, [0xB0, 1, 0]).unwrap();
+
+        let positions: Vec<Option<f64>> = store.positions_in_samples(0, 3000).map(|(_, beats)| beats).collect();
+        assert_eq!(positions, vec![None, Some(8.), Some(8.5), Some(9.), None, None]);
+        assert_eq!(store.beats(store.notes[0].idx_off), Some(8.));
+
+        // Positions stay with their events through eviction and restore
+        store.evict_before(200);
+        assert_eq!(store.beats(2), Some(8.5));
+        store.restore(&store.snapshot(usize::MAX)).unwrap();
+        let positions: Vec<Option<f64>> = store.positions_in_samples(0, 3000).map(|(_, beats)| beats).collect();
+        assert_eq!(positions, vec![Some(8.5), Some(9.), None, None]);
+        assert!(store.positions_in_samples(Samples::MIN, -1).all(|(_, beats)| beats.is_none()));
+    }
+
+    #[test]
+    fn test_loop_passes() {
+        let mut store = test_store();
+        store.set_sample_rate(0, 1000.);
+        let transport = |time, pos_beats, loop_range| TransportInfo {
+            time,
+            playing: true,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (4, 4),
+            pos_beats,
+            bar_start_pos_beats: (pos_beats / 4.).floor() * 4.,
+            loop_range,
+            ..Default::default()
+        };
+        // Start two beats before a loop from beat 4 to beat 8, which takes 2000 samples, so the
+        // loop starts at sample 1050
+        let mut pos = 2.;
+        for block in 0..75 {
+            let time = block * 100 + 50;
+            store.add_bar(transport(time, pos, Some((4., 8.))));
+            pos += 0.2;
+            if pos > 8. {
+                pos -= 4.;
+            }
+        }
+        let passes: Vec<(u32, Samples, Samples)> =
+            store.loop_passes.iter().map(|pass| (pass.number, pass.t_start, pass.t_end)).collect();
+        assert_eq!(passes, vec![(1, 1050, 3050), (2, 3050, 5050), (3, 5050, 7050)]);
+        assert_eq!(store.loop_passes[0].beats, (4., 8.));
+        assert_eq!(store.loop_pass_at(3.5).unwrap().number, 2);
+        assert!(store.loop_pass_at(0.5).is_none());
+        assert_eq!(store.loop_passes_in_time(2.5, 5.5).count(), 3);
+
+        // Stopping ends the pass that was playing
+        store.add_bar(TransportInfo { playing: false, ..transport(7550, 5., None) });
+        let pass = store.loop_passes.back().unwrap();
+        assert_eq!((pass.number, pass.t_start, pass.t_end, pass.beats), (4, 7050, 7550, (4., 8.)));
+        assert_eq!(store.loop_pass_at(7.3).unwrap().number, 4);
+        // Stopping again adds nothing
+        store.add_bar(TransportInfo { playing: false, ..transport(7650, 5., None) });
+        assert_eq!(store.loop_passes.len(), 4);
+
+        // After stopping, passes count from 1 again, a region is estimated without a loop range
+        store.add_bar(transport(8000, 4., None));
+        store.add_bar(transport(9000, 6., None));
+        store.add_bar(transport(10000, 4., None));
+        let pass = store.loop_passes.back().unwrap();
+        assert_eq!((pass.number, pass.t_start, pass.t_end, pass.beats), (1, 8000, 10000, (4., 8.)));
+
+        // Passes are evicted with the bars
+        store.evict_before(4000);
+        assert_eq!(store.loop_passes.front().unwrap().t_start, 5050);
+    }
+
+    #[test]
+    fn test_channel_state_before() {
+        let mut store = test_store();
+        store.add(0, [0xC0, 7, 0]).unwrap();
+        store.add(10, [0xB0, 1, 20]).unwrap();
+        // Lots of playing after the state was set
+        for time in (100..100_000).step_by(10) {
+            store.add(time, [0xB0, 1, (time % 128) as u8]).unwrap();
+            store.add(time, [0xD1, 64, 0]).unwrap();
+        }
+        let state = |store: &MidiStore, s| -> Vec<(u8, ChannelState, MidiMessage)> {
+            store
+                .channel_state_before(s)
+                .map(|(channel, state, message)| (channel.as_int(), state, message))
+                .collect()
+        };
+        let program = MidiMessage::ProgramChange { program: u7::new(7) };
+        let modulation = |value| MidiMessage::Controller {
+            controller: u7::new(1),
+            value: u7::new(value),
+        };
+        assert_eq!(
+            state(&store, 100),
+            vec![
+                (0, ChannelState::Controller(1), modulation(20)),
+                (0, ChannelState::Program, program),
+            ]
+        );
+        let later = state(&store, 99_995);
+        assert_eq!(later.len(), 3);
+        assert_eq!(later[0], (0, ChannelState::Controller(1), modulation((99_990 % 128) as u8)));
+        assert_eq!(later[1], (0, ChannelState::Program, program));
+        assert_eq!(later[2].1, ChannelState::Pressure);
+
+        // Evicted events set no state anymore
+        store.evict_before(50);
+        assert_eq!(state(&store, 100), vec![]);
+        assert_eq!(store.state_event_count, 2 * 9990);
+    }
+
+    #[test]
+    fn test_ghost_grid() {
+        let mut store = test_store();
+        store.set_sample_rate(0, 1000.);
+        // Without tempo or bars, 120 BPM in 4/4 from the session start
+        let grid = store.ghost_grid();
+        assert_eq!(grid, GhostGrid { anchor: 0, bar_length: 2000. });
+        assert_eq!(grid.bars_in_samples(500, 6500).collect::<Vec<_>>(), vec![2000, 4000, 6000]);
+        assert_eq!(grid.shifted(500).bars_in_samples(0, 3000).collect::<Vec<_>>(), vec![500, 2500]);
+
+        // Continues the last bar, leaving out ghost bars next to captured ones
+        let transport = |time, pos_beats| TransportInfo {
+            time,
+            playing: true,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (3, 4),
+            pos_beats,
+            bar_start_pos_beats: pos_beats,
+            ..Default::default()
+        };
+        store.add_bar(transport(1000, 3.));
+        store.add_bar(transport(2500, 6.));
+        let grid = store.ghost_grid();
+        assert_eq!(grid, GhostGrid { anchor: 2500, bar_length: 1500. });
+        let bars = store.ghost_bars_in_samples(grid, 0, 7000).collect::<Vec<_>>();
+        assert_eq!(bars, vec![4000, 5500, 7000]);
+        let shifted = grid.shifted(500);
+        let bars = store.ghost_bars_in_samples(shifted, 0, 7000).collect::<Vec<_>>();
+        assert_eq!(bars, vec![4500, 6000]);
+
+        // Bars are as long in samples as at the sample rate of the anchor
+        store.set_sample_rate(8000, 2000.);
+        store.add_bar(transport(9000, 9.));
+        assert_eq!(store.ghost_grid(), GhostGrid { anchor: 9000, bar_length: 3000. });
+    }
+
+    #[test]
+    fn test_midi_clock() {
+        // At 24 kHz and 120 BPM, the clock ticks every 500 samples
+        let mut store = test_store();
+        store.set_sample_rate(0, 24000.);
+        let host = |time, tempo| TransportInfo {
+            time,
+            sample_rate: 24000.,
+            tempo,
+            time_sig: (4, 4),
+            ..Default::default()
+        };
+        let tempo_map = |store: &MidiStore| -> Vec<(Samples, f64)> {
+            store.tempo_map.iter().map(|change| (change.t, change.tempo)).collect()
+        };
+        store.add_bar(host(0, 90.));
+        store.add(0, [0xFA, 0, 0]).unwrap();
+        for tick in 0..24 * 9 {
+            let time = tick * 500;
+            if time == 60000 {
+                // The stopped host transport is ignored while the clock runs
+                store.add_bar(host(time, 90.));
+            }
+            store.add(time, [0xF8, 0, 0]).unwrap();
+            if tick == 100 {
+                store.add(time + 250, note_on(0, 60, 100)).unwrap();
+            }
+        }
+        assert_eq!(tempo_map(&store), vec![(0, 90.), (3000, 120.)]);
+        let bars: Vec<(i32, Samples)> = store.bars.iter().map(|bar| (bar.bar_number, bar.t)).collect();
+        assert_eq!(bars, vec![(0, 0), (1, 48000), (2, 96000)]);
+        // Events are positioned by the clock, the note comes half a tick after tick 100
+        let note_idx = store.in_flight[0].idx_on;
+        assert!((store.beats(note_idx).unwrap() - 100.5 / 24.).abs() < 1e-9);
+        // Clock messages are no events of the capture
+        assert_eq!(store.store.len(), 1);
+
+        // After Stop, the host is followed again, Continue picks up at the song position
+        store.add(108000, [0xFC, 0, 0]).unwrap();
+        store.add_bar(host(110000, 90.));
+        store.add(115000, [0xF2, 16, 0]).unwrap();
+        store.add(120000, [0xFB, 0, 0]).unwrap();
+        for tick in 0..24 {
+            store.add(120000 + tick * 500, [0xF8, 0, 0]).unwrap();
+        }
+        assert_eq!(tempo_map(&store), vec![(0, 90.), (3000, 120.), (110000, 90.), (123000, 120.)]);
+        assert_eq!(store.bars.back().map(|bar| (bar.bar_number, bar.t)), Some((1, 120000)));
+    }
+
+    #[test]
+    fn test_tempo_estimates() {
+        let mut store = test_store();
+        store.set_sample_rate(0, 1000.);
+        for time in (0..9600).step_by(600) {
+            store.add(time, note_on(0, 60, 127)).unwrap();
+            store.add(time + 300, note_off(0, 60, 0)).unwrap();
+        }
+        let (t_start, t_end, onsets) = store.untimed_take(1024).unwrap();
+        assert_eq!((t_start, t_end, onsets.len()), (0, 9000, 16));
+        assert_eq!(onsets[1], Onset { seconds: 0.6, weight: 1.0 });
+        assert_eq!(store.untimed_take(4).unwrap().0, 7200);
+
+        // The take grows until a gap, then a new one starts
+        let estimate = |t_start, t_end| TempoEstimate {
+            t_start,
+            t_end,
+            tempo: 100.,
+            time_sig: (4, 4),
+            downbeat: t_start,
+        };
+        store.set_tempo_estimate(estimate(0, 7200));
+        store.set_tempo_estimate(estimate(0, 9000));
+        assert_eq!(store.tempo_estimates.len(), 1);
+        assert_eq!(store.ghost_grid(), GhostGrid { anchor: 0, bar_length: 2400. });
+        store.add(15000, note_on(0, 62, 100)).unwrap();
+        assert_eq!(store.untimed_take(1024).unwrap().0, 15000);
+        store.set_tempo_estimate(estimate(15000, 15000));
+        assert_eq!(store.tempo_estimates.len(), 2);
+        assert_eq!(store.tempo_estimate_in_samples(1000, 2000).unwrap().t_start, 0);
+        assert_eq!(store.tempo_estimate_in_samples(12000, 16000).unwrap().t_start, 15000);
+
+        // Notes played to the transport end the take, the estimate is not used where it played
+        store.add_bar(TransportInfo {
+            time: 16000,
+            playing: true,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (4, 4),
+            ..Default::default()
+        });
+        store.add(16100, note_on(0, 64, 100)).unwrap();
+        assert!(store.untimed_take(1024).is_none());
+        assert!(store.tempo_estimate_in_samples(15000, 17000).is_none());
+        assert_eq!(store.ghost_grid().anchor, 16000);
+    }
 }
@@ -0,0 +1,325 @@
+//! Persistence of the captured MIDI inside the plugin state.
+//!
+//! When enabled in the config, the MidiStore is serialized together with the rest of the plugin
+//! state, so the capture survives closing and reopening a project. Only raw events, bars and the
+//! tempo map are stored, notes are rebuilt from the events on restore. Events are packed as
+//! hex encoded records of (varint time delta, varint length, raw MIDI bytes) to keep the state small.
+
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use nih_plug::params::persist::PersistentField;
+use nih_plug::{nih_log, nih_warn};
+use serde::{Deserialize, Serialize};
+
+use crate::Samples;
+use crate::midistore::{ExpressionKind, MidiStore};
+
+/// Serializable snapshot of a MidiStore, see [`MidiStore::snapshot`].
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct CaptureSnapshot {
+    /// Sample rate epochs as (start sample, start in seconds, sample rate).
+    pub sample_rates: Vec<(Samples, f64, f32)>,
+    /// Time of the first event in samples.
+    pub first: Samples,
+    /// Packed events, each time is relative to the previous event.
+    pub events: String,
+    /// Bars as (bar number, time in samples).
+    pub bars: Vec<(i32, Samples)>,
+    /// Tempo map as (time in samples, tempo in BPM, time signature), see [`MidiStore::tempo_map`].
+    #[serde(default)]
+    pub tempo_map: Vec<(Samples, f64, (i32, i32))>,
+    /// Tempo in BPM at the time of the snapshot.
+    pub tempo: f64,
+    /// Time signature at the time of the snapshot.
+    pub time_sig: (i32, i32),
+    /// Per-note expressions as (time in samples, channel, key, kind, value), restored onto the
+    /// note sounding on their channel and key.
+    #[serde(default)]
+    pub expressions: Vec<(Samples, u8, u8, ExpressionKind, f32)>,
+}
+
+/// Persistent field that snapshots the shared MidiStore when the host saves the plugin state.
+pub struct PersistentCapture {
+    store: Arc<RwLock<MidiStore>>,
+    /// Maximum size of the packed events, `None` disables persisting the capture.
+    max_bytes: Option<usize>,
+}
+
+impl PersistentCapture {
+    pub fn new(store: Arc<RwLock<MidiStore>>, max_bytes: Option<usize>) -> Self {
+        Self { store, max_bytes }
+    }
+}
+
+impl<'a> PersistentField<'a, CaptureSnapshot> for PersistentCapture {
+    fn set(&self, new_value: CaptureSnapshot) {
+        if self.max_bytes.is_none() || new_value.events.is_empty() {
+            return;
+        }
+        // Hosts may reload the plugin state in the middle of a session, restoring then
+        // would duplicate the capture
+        if !self.store.read().unwrap().store.is_empty() {
+            nih_log!("Not restoring capture from plugin state, store already contains events");
+            return;
+        }
+        match self.store.write().unwrap().restore(&new_value) {
+            Ok(()) => nih_log!("Restored capture from plugin state"),
+            Err(e) => nih_warn!("Not restoring capture from plugin state: {}", e),
+        }
+    }
+
+    fn map<F, R>(&self, f: F) -> R
+    where
+        F: Fn(&CaptureSnapshot) -> R,
+    {
+        match self.max_bytes {
+            Some(max_bytes) => f(&self.store.read().unwrap().snapshot(max_bytes)),
+            None => f(&CaptureSnapshot::default()),
+        }
+    }
+}
+
+/// Number of bytes a record takes up in the packed event string.
+pub fn record_size(delta: Samples, data: &[u8]) -> usize {
+    let mut packed = Vec::with_capacity(16);
+    write_varint(&mut packed, delta as u64);
+    write_varint(&mut packed, data.len() as u64);
+    2 * (packed.len() + data.len())
+}
+
+/// Size an expression takes up in a serialized snapshot, at most.
+pub const EXPRESSION_RECORD_SIZE: usize = 48;
+
+/// Packs events given as (time, raw MIDI bytes) into a string.
+///
+/// Times must not decrease, the first time is not stored and must be kept separately.
+pub fn encode_events(events: &[(Samples, Vec<u8>)]) -> String {
+    let mut packed = Vec::with_capacity(events.len() * 6);
+    let mut last = events.first().map_or(0, |(time, _)| *time);
+    for (time, data) in events {
+        write_varint(&mut packed, (time - last) as u64);
+        write_varint(&mut packed, data.len() as u64);
+        packed.extend_from_slice(data);
+        last = *time;
+    }
+    packed.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Unpacks events packed by [`encode_events`], starting at time `first`.
+pub fn decode_events(first: Samples, events: &str) -> Result<Vec<(Samples, Vec<u8>)>> {
+    if !events.len().is_multiple_of(2) {
+        anyhow::bail!("Packed events have odd length");
+    }
+    let packed = (0..events.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&events[idx..idx + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()?;
+
+    let mut decoded = Vec::new();
+    let mut rest = &packed[..];
+    let mut time = first;
+    while !rest.is_empty() {
+        time += read_varint(&mut rest)? as Samples;
+        let len = read_varint(&mut rest)? as usize;
+        if len > rest.len() {
+            anyhow::bail!("Truncated event");
+        }
+        let (data, tail) = rest.split_at(len);
+        decoded.push((time, data.to_vec()));
+        rest = tail;
+    }
+    Ok(decoded)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = data.split_first() else {
+            anyhow::bail!("Truncated varint");
+        };
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    anyhow::bail!("Varint too long")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::{u4, u7};
+
+    fn note_on(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+        [0x90 | channel, key, vel]
+    }
+
+    fn note_off(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+        [0x80 | channel, key, vel]
+    }
+
+    fn recorded_store() -> MidiStore {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 48000.);
+        store.add(1000, note_on(0, 60, 100)).unwrap();
+        store.add(2000, [0xB0, 0x40, 0x7F]).unwrap();
+        store.add(48000, note_off(0, 60, 0)).unwrap();
+        store.add(100000, [0xC1, 0x05, 0x00]).unwrap();
+        store.add(200000, note_on(1, 64, 90)).unwrap();
+        store.add(300000, note_off(1, 64, 0)).unwrap();
+        // Still held when the snapshot is taken
+        store.add(310000, note_on(1, 67, 90)).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_encode_decode_events() {
+        let events = vec![
+            (10, vec![0x90, 60, 100]),
+            (10, vec![0xC0, 5]),
+            (1_000_000_000, vec![0x80, 60, 0]),
+        ];
+        let packed = encode_events(&events);
+        assert_eq!(decode_events(10, &packed).unwrap(), events);
+        assert!(decode_events(10, &packed[1..]).is_err());
+        assert!(decode_events(10, &packed[..packed.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_snapshot_restore_roundtrip() {
+        let recorded = recorded_store();
+        let snapshot = recorded.snapshot(usize::MAX);
+
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 48000.);
+        store.restore(&snapshot).unwrap();
+
+        assert_eq!(store.store.len(), 7);
+        assert_eq!(store.notes.len(), 2);
+        assert_eq!(store.in_flight.len(), 0);
+
+        // Spacing is kept exactly, the capture ends before the current session
+        let offset = store.notes[0].t_start - recorded.notes[0].t_start;
+        for (restored, original) in store.notes.iter().zip(recorded.notes.iter()) {
+            assert_eq!(restored.t_start, original.t_start + offset);
+            assert_eq!(restored.t_end, original.t_end + offset);
+            assert_eq!(restored.key, original.key);
+        }
+        assert!(store.store.back().unwrap().0 < 0);
+        assert!(store.seconds(store.store.back().unwrap().0) <= -1.0);
+
+        // Time keeps running continuously into the current session
+        assert_eq!(store.seconds(0), 0.0);
+        assert_eq!(store.seconds(-48000), -1.0);
+
+        // New events are captured after the restored ones
+        store.add(0, note_on(0, 72, 100)).unwrap();
+        store.add(100, note_off(0, 72, 0)).unwrap();
+        assert_eq!(store.notes.len(), 3);
+
+        // Expressions come back on their notes
+        let mut recorded = recorded_store();
+        let (channel, key) = (u4::new(1), u7::new(67));
+        recorded.add_expression(320000, None, channel, key, ExpressionKind::Tuning, 0.5);
+        recorded.add_expression(330000, None, channel, key, ExpressionKind::Brightness, 0.25);
+        recorded.add(340000, note_off(1, 67, 0)).unwrap();
+        let snapshot = recorded.snapshot(usize::MAX);
+        assert_eq!(snapshot.expressions.len(), 2);
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 48000.);
+        store.restore(&snapshot).unwrap();
+        let note = store.notes.back().unwrap();
+        assert_eq!(store.expressions.len(), 2);
+        assert!(store.expressions.iter().all(|expression| expression.idx_on == note.idx_on));
+        assert_eq!(store.expressions[1].kind, ExpressionKind::Brightness);
+        assert_eq!(store.expressions[1].time - note.t_start, 330000 - 310000);
+
+        // Expressions count against the size, so fewer events fit
+        let capped = recorded.snapshot(snapshot.events.len() + EXPRESSION_RECORD_SIZE);
+        let all = decode_events(snapshot.first, &snapshot.events).unwrap();
+        let events = decode_events(capped.first, &capped.events).unwrap();
+        assert!(events.len() < all.len());
+        assert_eq!(capped.expressions.len(), 2);
+        // Without room for its note, an expression is left out
+        let capped = recorded.snapshot(EXPRESSION_RECORD_SIZE + 8);
+        assert_eq!(decode_events(capped.first, &capped.events).unwrap().len(), 1);
+        assert!(capped.expressions.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_size_cap() {
+        let recorded = recorded_store();
+        let full = recorded.snapshot(usize::MAX);
+        let capped = recorded.snapshot(full.events.len() / 2);
+
+        assert!(capped.events.len() <= full.events.len() / 2);
+        let events = decode_events(capped.first, &capped.events).unwrap();
+        assert!(events.len() < 7);
+        // The newest events are kept
+        assert_eq!(events.last().unwrap().0, 310000);
+    }
+
+    #[test]
+    fn test_restore_into_non_empty_store() {
+        let snapshot = recorded_store().snapshot(usize::MAX);
+
+        let mut store = MidiStore::new();
+        store.add(0, note_on(0, 72, 100)).unwrap();
+        store.restore(&snapshot).unwrap();
+
+        // The restored capture ends up in front, the held note keeps going
+        assert_eq!(store.store.len(), 8);
+        assert_eq!(store.store.back().unwrap().0, 0);
+        assert_eq!(store.notes.len(), 2);
+        assert_eq!(store.in_flight.len(), 1);
+        store.add(100, note_off(0, 72, 0)).unwrap();
+        assert_eq!(store.notes.len(), 3);
+        assert!(store.notes[1].t_end < 0);
+    }
+
+    #[test]
+    fn test_persistent_capture_skips_non_empty_store() {
+        let snapshot = recorded_store().snapshot(usize::MAX);
+
+        let store = Arc::new(RwLock::new(MidiStore::new()));
+        store.write().unwrap().add(0, note_on(0, 72, 100)).unwrap();
+        let capture = PersistentCapture::new(store.clone(), Some(usize::MAX));
+        capture.set(snapshot.clone());
+        assert_eq!(store.read().unwrap().store.len(), 1);
+
+        let store = Arc::new(RwLock::new(MidiStore::new()));
+        let capture = PersistentCapture::new(store.clone(), Some(usize::MAX));
+        capture.set(snapshot);
+        assert_eq!(store.read().unwrap().store.len(), 7);
+    }
+
+    #[test]
+    fn test_restore_with_different_sample_rate() {
+        let snapshot = recorded_store().snapshot(usize::MAX);
+
+        let mut store = MidiStore::new();
+        store.restore(&snapshot).unwrap();
+        store.set_sample_rate(0, 44100.);
+
+        // Restored events keep their recorded sample rate
+        let note = &store.notes[0];
+        assert_eq!(store.sample_rate(note.t_start), 48000.);
+        let duration = store.seconds(note.t_end) - store.seconds(note.t_start);
+        assert!((duration - 47000. / 48000.).abs() < 1e-9);
+        assert_eq!(store.sample_rate(0), 44100.);
+    }
+}
@@ -0,0 +1,199 @@
+//! SysEx messages on their way from the audio thread into the MidiStore.
+//!
+//! nih-plug hands SysEx to the plugin in a fixed size buffer, see [`SysEx`]. The audio thread must
+//! not allocate, so messages are split into fixed size [`SysExChunk`]s for the store queue and put
+//! back together by a [`SysExAssembler`] on the store delivery thread.
+//!
+//! nih-plug has no events for system common and realtime messages, e.g. the MIDI clock, and
+//! hands them over as SysEx as well. These are short enough to go into the queue as they are.
+
+use nih_plug::midi::sysex::SysExMessage;
+
+use crate::Samples;
+
+/// Longest SysEx message that is captured, including the leading 0xF0 and the trailing 0xF7.
+///
+/// Every note event the host sends carries a buffer of this size, so it cannot be arbitrarily
+/// large. Longer messages are dropped by nih-plug before they reach the plugin.
+pub const MAX_SYSEX_LEN: usize = 512;
+
+/// Number of SysEx bytes carried by a single store queue message.
+pub const SYSEX_CHUNK_LEN: usize = 32;
+
+/// A complete SysEx message as received from the host, including 0xF0 and 0xF7.
+///
+/// Also carries system common and realtime messages, see [`system_message`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SysEx {
+    data: [u8; MAX_SYSEX_LEN],
+    len: usize,
+}
+
+impl SysExMessage for SysEx {
+    type Buffer = [u8; MAX_SYSEX_LEN];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        let system = buffer.len() <= 3 && matches!(buffer.first(), Some(0xF1..=0xF6 | 0xF8..=0xFF));
+        if buffer.len() > MAX_SYSEX_LEN || (buffer.first() != Some(&0xF0) && !system) {
+            return None;
+        }
+        let mut data = [0; MAX_SYSEX_LEN];
+        data[..buffer.len()].copy_from_slice(buffer);
+        Some(Self {
+            data,
+            len: buffer.len(),
+        })
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        (self.data, self.len)
+    }
+}
+
+/// Returns the system common or realtime message in `data` padded to three bytes, `None` for
+/// SysEx messages.
+pub fn system_message(data: &[u8]) -> Option<[u8; 3]> {
+    if !matches!(data.first(), Some(0xF1..=0xF6 | 0xF8..=0xFF)) || data.len() > 3 {
+        return None;
+    }
+    let mut message = [0; 3];
+    message[..data.len()].copy_from_slice(data);
+    Some(message)
+}
+
+/// Part of a SysEx message on its way through the store queue.
+#[derive(Debug, Clone, Copy)]
+pub struct SysExChunk {
+    /// Time of the whole message in samples.
+    pub time: Samples,
+    /// This chunk starts a new message.
+    pub first: bool,
+    /// This chunk completes the message.
+    pub last: bool,
+    len: u8,
+    data: [u8; SYSEX_CHUNK_LEN],
+}
+
+impl SysExChunk {
+    /// Bytes of the message carried by this chunk.
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+/// Splits a SysEx message into chunks without allocating.
+pub fn chunks(time: Samples, message: &[u8]) -> impl Iterator<Item = SysExChunk> + '_ {
+    let n_chunks = message.len().div_ceil(SYSEX_CHUNK_LEN);
+    message
+        .chunks(SYSEX_CHUNK_LEN)
+        .enumerate()
+        .map(move |(idx, part)| {
+            let mut data = [0; SYSEX_CHUNK_LEN];
+            data[..part.len()].copy_from_slice(part);
+            SysExChunk {
+                time,
+                first: idx == 0,
+                last: idx + 1 == n_chunks,
+                len: part.len() as u8,
+                data,
+            }
+        })
+}
+
+/// Puts SysEx messages back together from their chunks.
+///
+/// The audio thread stops sending the rest of a message once a chunk did not fit into the queue,
+/// so a message that is missing chunks is discarded when the next message starts.
+#[derive(Default)]
+pub struct SysExAssembler {
+    time: Samples,
+    data: Vec<u8>,
+    /// A message has been started and not completed yet.
+    pending: bool,
+}
+
+impl SysExAssembler {
+    /// Adds a chunk, returns the time and bytes of the message once it is complete.
+    pub fn push(&mut self, chunk: &SysExChunk) -> Option<(Samples, &[u8])> {
+        if chunk.first {
+            self.time = chunk.time;
+            self.data.clear();
+            self.pending = true;
+        } else if !self.pending {
+            return None;
+        }
+        self.data.extend_from_slice(chunk.data());
+        if chunk.last {
+            self.pending = false;
+            return Some((self.time, &self.data));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: usize) -> Vec<u8> {
+        let mut message = vec![0xF0];
+        message.extend((0..len - 2).map(|idx| (idx % 128) as u8));
+        message.push(0xF7);
+        message
+    }
+
+    #[test]
+    fn test_chunks_roundtrip() {
+        let mut assembler = SysExAssembler::default();
+        for len in [2, SYSEX_CHUNK_LEN, SYSEX_CHUNK_LEN + 1, MAX_SYSEX_LEN] {
+            let message = message(len);
+            let chunks: Vec<SysExChunk> = chunks(100, &message).collect();
+            assert_eq!(chunks.len(), len.div_ceil(SYSEX_CHUNK_LEN));
+            let (last, rest) = chunks.split_last().unwrap();
+            for chunk in rest {
+                assert!(assembler.push(chunk).is_none());
+            }
+            assert_eq!(assembler.push(last), Some((100, &message[..])));
+        }
+    }
+
+    #[test]
+    fn test_incomplete_message_discarded() {
+        let mut assembler = SysExAssembler::default();
+        let long = message(3 * SYSEX_CHUNK_LEN);
+        let short = message(4);
+
+        // The rest of the long message did not make it through the queue
+        let first = chunks(100, &long).next().unwrap();
+        assert!(assembler.push(&first).is_none());
+        let complete = chunks(200, &short).next().unwrap();
+        assert_eq!(assembler.push(&complete), Some((200, &short[..])));
+
+        // Chunks without the start of their message are ignored
+        let tail = chunks(300, &long).last().unwrap();
+        assert!(assembler.push(&tail).is_none());
+    }
+
+    #[test]
+    fn test_from_buffer() {
+        let sysex = SysEx::from_buffer(&message(10)).unwrap();
+        let (buffer, len) = sysex.to_buffer();
+        assert_eq!(&buffer[..len], &message(10)[..]);
+        assert!(SysEx::from_buffer(&message(MAX_SYSEX_LEN + 1)).is_none());
+        assert!(SysEx::from_buffer(&[0x90, 60, 100]).is_none());
+    }
+
+    #[test]
+    fn test_system_messages() {
+        // Clock and Song Position Pointer, CLAP hosts pad the clock to three bytes
+        for data in [&[0xF8][..], &[0xF8, 0, 0], &[0xF2, 0x10, 0x01]] {
+            let (buffer, len) = SysEx::from_buffer(data).unwrap().to_buffer();
+            assert_eq!(&buffer[..len], data);
+        }
+        assert_eq!(system_message(&[0xF8]), Some([0xF8, 0, 0]));
+        assert_eq!(system_message(&[0xF2, 0x10, 0x01]), Some([0xF2, 0x10, 0x01]));
+        assert_eq!(system_message(&message(10)), None);
+        assert!(SysEx::from_buffer(&[0xF7]).is_none());
+        assert!(SysEx::from_buffer(&[0xF8, 0, 0, 0]).is_none());
+    }
+}
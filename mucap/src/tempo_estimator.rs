@@ -0,0 +1,371 @@
+//! Tempo and bar grid estimation from the notes alone, for notes played without the host
+//! transport.
+//!
+//! Standalone, or with the transport stopped, the tempo reported by the host is missing or has
+//! nothing to do with the playing. The [`TempoEstimator`] runs on the store delivery thread and
+//! estimates the tempo and downbeats of the latest take from its note onsets, see [`estimate`].
+//! The result is stored as a [`TempoEstimate`] and used for the ghost grid and exporting.
+
+use std::f64::consts::TAU;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use nih_plug::nih_dbg;
+
+use crate::midistore::{MidiStore, TempoEstimate};
+
+/// A note start, notes of a chord are merged into a single onset by [`estimate`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Onset {
+    /// Time in seconds.
+    pub seconds: f64,
+    /// Strength of the onset, the velocity from 0 to 1 for a single note.
+    pub weight: f64,
+}
+
+/// Beat grid found by [`estimate`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BeatGrid {
+    /// Tempo in BPM.
+    pub tempo: f64,
+    /// Time of the first downbeat at or before the first onset in seconds.
+    pub downbeat: f64,
+}
+
+/// Range of tempos considered, in BPM.
+const TEMPO_RANGE: (f64, f64) = (50., 200.);
+
+/// Step between the tempos tried before refining the best one, in BPM.
+const TEMPO_STEP: f64 = 0.5;
+
+/// Tempo that is preferred if several tempos fit the onsets equally well, e.g. half and double
+/// tempo, in BPM.
+const PREFERRED_TEMPO: f64 = 120.;
+
+/// Width of the preference for [`PREFERRED_TEMPO`] in octaves.
+const TEMPO_PREFERENCE_WIDTH: f64 = 0.4;
+
+/// Share of the score of a tempo that half the tempo needs to reach to be preferred.
+const HALF_TEMPO_SUPPORT: f64 = 0.6;
+
+/// Notes starting within this many seconds of each other form a chord.
+const CHORD_WINDOW: f64 = 0.05;
+
+/// Longest interval between two onsets compared for the tempo, in seconds.
+const MAX_INTERVAL: f64 = 2.5;
+
+/// Timing deviation of onsets from the beat grid that is still considered on the beat, as
+/// standard deviation in beats.
+const TIMING_DEVIATION: f64 = 0.06;
+
+/// Onsets further from the beat grid than this many beats are left out of fitting the grid.
+const MAX_GRID_DEVIATION: f64 = 0.25;
+
+/// Fewest onsets needed for an estimate.
+const MIN_ONSETS: usize = 8;
+
+/// Share of intervals that need to fit the tempo for an estimate.
+const MIN_CONFIDENCE: f64 = 0.3;
+
+/// Estimates tempo and downbeats from the onsets of a take, sorted by time.
+///
+/// Every pair of onsets close to each other votes for the tempos whose beat length divides the
+/// interval between them, weighted by the strength of both onsets. The tempo with the most
+/// votes, slightly preferring tempos around [`PREFERRED_TEMPO`], is halved as long as the
+/// onsets between the slower beats are weak, and refined by fitting a beat grid to the onsets.
+/// The downbeat is the beat of the bar that has the strongest onsets, ties going to the beat
+/// the take starts on.
+///
+/// Returns `None` if there are too few onsets or they do not follow a steady beat.
+pub fn estimate(onsets: &[Onset], beats_per_bar: u32) -> Option<BeatGrid> {
+    let onsets = merge_chords(onsets);
+    if onsets.len() < MIN_ONSETS || beats_per_bar == 0 {
+        return None;
+    }
+
+    let mut intervals = Vec::new();
+    for (idx, first) in onsets.iter().enumerate() {
+        for second in onsets[idx + 1..].iter() {
+            let interval = second.seconds - first.seconds;
+            if interval > MAX_INTERVAL {
+                break;
+            }
+            intervals.push((interval, first.weight * second.weight));
+        }
+    }
+    let total: f64 = intervals.iter().map(|(_, weight)| weight).sum();
+
+    let steps = ((TEMPO_RANGE.1 - TEMPO_RANGE.0) / TEMPO_STEP).round() as usize;
+    let (mut score, mut tempo) = (0..=steps)
+        .map(|step| {
+            let tempo = TEMPO_RANGE.0 + step as f64 * TEMPO_STEP;
+            (interval_score(&intervals, 60. / tempo), tempo)
+        })
+        .max_by(|(score1, tempo1), (score2, tempo2)| {
+            (score1 * tempo_preference(*tempo1)).total_cmp(&(score2 * tempo_preference(*tempo2)))
+        })?;
+    if score < MIN_CONFIDENCE * total {
+        nih_dbg!("No steady beat found, best tempo {} fits {:.2}", tempo, score / total);
+        return None;
+    }
+    // Every interval that fits a tempo also fits double the tempo, so the beats are only split
+    // up if the onsets in between are about as strong as the ones on the beats
+    while tempo / 2. >= TEMPO_RANGE.0 {
+        let half = interval_score(&intervals, 120. / tempo);
+        if half < HALF_TEMPO_SUPPORT * score {
+            break;
+        }
+        (score, tempo) = (half, tempo / 2.);
+    }
+
+    let (start, beat) = fit_grid(&onsets, 60. / tempo);
+    let beat_of = |onset: &Onset| {
+        let position = (onset.seconds - start) / beat;
+        let nearest = position.round();
+        ((position - nearest).abs() <= MAX_GRID_DEVIATION).then_some(nearest as i64)
+    };
+
+    let bar = beats_per_bar as i64;
+    let mut accents = vec![0.0; beats_per_bar as usize];
+    for onset in onsets.iter() {
+        if let Some(beat) = beat_of(onset) {
+            accents[beat.rem_euclid(bar) as usize] += onset.weight;
+        }
+    }
+    let first = beat_of(&onsets[0]).unwrap_or(((onsets[0].seconds - start) / beat).floor() as i64);
+    let first_phase = first.rem_euclid(bar);
+    let downbeat_phase = (0..bar)
+        .map(|offset| (first_phase + offset) % bar)
+        .fold(first_phase, |best, phase| {
+            if accents[phase as usize] > accents[best as usize] { phase } else { best }
+        });
+    let downbeat = first - (first - downbeat_phase).rem_euclid(bar);
+
+    Some(BeatGrid {
+        tempo: 60. / beat,
+        downbeat: start + downbeat as f64 * beat,
+    })
+}
+
+/// Merges onsets of notes played together, adding up their weights.
+fn merge_chords(onsets: &[Onset]) -> Vec<Onset> {
+    let mut merged: Vec<Onset> = Vec::with_capacity(onsets.len());
+    let mut chord_start = f64::NEG_INFINITY;
+    for onset in onsets.iter() {
+        match merged.last_mut() {
+            Some(last) if onset.seconds - chord_start < CHORD_WINDOW => last.weight += onset.weight,
+            _ => {
+                chord_start = onset.seconds;
+                merged.push(*onset);
+            }
+        }
+    }
+    merged
+}
+
+/// Sum of the weights of the intervals that are a whole number of beats of length `beat`,
+/// counting intervals that are off by a bit partially.
+fn interval_score(intervals: &[(f64, f64)], beat: f64) -> f64 {
+    intervals
+        .iter()
+        .map(|(interval, weight)| {
+            let beats = interval / beat;
+            if beats < 0.5 {
+                return 0.0;
+            }
+            let deviation = (beats - beats.round()) / TIMING_DEVIATION;
+            weight * (-deviation * deviation / 2.).exp()
+        })
+        .sum()
+}
+
+/// Preference of tempos from 0 to 1, 1 for [`PREFERRED_TEMPO`].
+fn tempo_preference(tempo: f64) -> f64 {
+    let octaves = (tempo / PREFERRED_TEMPO).log2() / TEMPO_PREFERENCE_WIDTH;
+    (-octaves * octaves / 2.).exp()
+}
+
+/// Fits a beat grid with beats of about `beat` seconds to the onsets.
+///
+/// Returns the time of a beat and the beat length in seconds.
+fn fit_grid(onsets: &[Onset], beat: f64) -> (f64, f64) {
+    // The phase of the beats is the weighted circular mean of the onsets
+    let (sin, cos) = onsets.iter().fold((0.0, 0.0), |(sin, cos), onset| {
+        let angle = TAU * onset.seconds / beat;
+        (sin + onset.weight * angle.sin(), cos + onset.weight * angle.cos())
+    });
+    let start = sin.atan2(cos) / TAU * beat;
+
+    // Least squares fit of the onset times over their beat numbers
+    let (mut sw, mut sx, mut sy, mut sxx, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for onset in onsets.iter() {
+        let position = (onset.seconds - start) / beat;
+        let nearest = position.round();
+        if (position - nearest).abs() > MAX_GRID_DEVIATION {
+            continue;
+        }
+        let w = onset.weight;
+        sw += w;
+        sx += w * nearest;
+        sy += w * onset.seconds;
+        sxx += w * nearest * nearest;
+        sxy += w * nearest * onset.seconds;
+    }
+    let denominator = sw * sxx - sx * sx;
+    if denominator.abs() < f64::EPSILON {
+        return (start, beat);
+    }
+    let fitted = (sw * sxy - sx * sy) / denominator;
+    ((sy - fitted * sx) / sw, fitted)
+}
+
+/// Number of note starts of the latest take that are used for the estimate.
+const MAX_TAKE_ONSETS: usize = 1024;
+
+/// Shortest time between two estimates.
+const ESTIMATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the tempo estimate of the latest take up to date, see [`MidiStore::untimed_take`].
+pub struct TempoEstimator {
+    /// Time of the last event when the estimate was last updated.
+    estimated_until: Option<i64>,
+    last_run: Instant,
+}
+
+impl Default for TempoEstimator {
+    fn default() -> Self {
+        Self {
+            estimated_until: None,
+            last_run: Instant::now(),
+        }
+    }
+}
+
+impl TempoEstimator {
+    /// Estimates the latest take again if events were added since the last estimate.
+    ///
+    /// The onsets are collected under a read lock, the store is only locked for writing to
+    /// store the estimate.
+    pub fn update_if_due(&mut self, store: &RwLock<MidiStore>) {
+        if self.last_run.elapsed() < ESTIMATE_INTERVAL {
+            return;
+        }
+        let (take, beats_per_bar) = {
+            let store = store.read().unwrap();
+            let last_event = store.store.back().map(|(time, _)| *time);
+            if last_event == self.estimated_until {
+                return;
+            }
+            self.estimated_until = last_event;
+            (store.untimed_take(MAX_TAKE_ONSETS), store.estimate_beats_per_bar())
+        };
+        self.last_run = Instant::now();
+        let Some((t_start, t_end, onsets)) = take else {
+            return;
+        };
+        let Some(grid) = estimate(&onsets, beats_per_bar) else {
+            return;
+        };
+        let mut store = store.write().unwrap();
+        let estimate = TempoEstimate {
+            t_start,
+            t_end,
+            tempo: grid.tempo,
+            time_sig: (beats_per_bar as i32, 4),
+            downbeat: store.samples_at(grid.downbeat),
+        };
+        nih_dbg!("Estimated tempo: {:?}", estimate);
+        store.set_tempo_estimate(estimate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Onsets on the given beats at `tempo`, starting at `start` seconds, accented every
+    /// `accent` beats.
+    fn onsets(tempo: f64, start: f64, beats: &[f64], accent: i64) -> Vec<Onset> {
+        beats
+            .iter()
+            .map(|beat| Onset {
+                seconds: start + beat * 60. / tempo,
+                weight: if beat.fract() == 0. && (*beat as i64) % accent == 0 { 1.0 } else { 0.5 },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_estimate_steady_quarters() {
+        let beats: Vec<f64> = (0..32).map(|beat| beat as f64).collect();
+        let grid = estimate(&onsets(97., 1.3, &beats, 4), 4).unwrap();
+        assert!((grid.tempo - 97.).abs() < 0.01, "{:?}", grid);
+        assert!((grid.downbeat - 1.3).abs() < 0.001, "{:?}", grid);
+    }
+
+    #[test]
+    fn test_estimate_prefers_slow_beats_without_subdivision() {
+        let beats: Vec<f64> = (0..16).map(|beat| beat as f64).collect();
+        let grid = estimate(&onsets(62., 0.5, &beats, 4), 4).unwrap();
+        assert!((grid.tempo - 62.).abs() < 0.01, "{:?}", grid);
+        // Evenly played eighths are beats of their own
+        let eighths: Vec<f64> = (0..32).map(|eighth| eighth as f64 / 2.).collect();
+        let mut played = onsets(90., 0.5, &eighths, 4);
+        for onset in played.iter_mut() {
+            onset.weight = 0.8;
+        }
+        let grid = estimate(&played, 4).unwrap();
+        assert!((grid.tempo - 180.).abs() < 0.01, "{:?}", grid);
+    }
+
+    #[test]
+    fn test_estimate_finds_downbeat() {
+        // Starts with a pickup of one beat, accents on the following downbeats
+        let beats: Vec<f64> = (0..24).map(|beat| beat as f64).collect();
+        let mut played = onsets(130., 0.0, &beats, 4);
+        for onset in played.iter_mut() {
+            onset.weight = 0.5;
+        }
+        for onset in played.iter_mut().skip(1).step_by(4) {
+            onset.weight = 1.0;
+        }
+        let grid = estimate(&played, 4).unwrap();
+        let beat = 60. / 130.;
+        assert!((grid.tempo - 130.).abs() < 0.01, "{:?}", grid);
+        assert!((grid.downbeat - (beat - 4. * beat)).abs() < 0.001, "{:?}", grid);
+    }
+
+    #[test]
+    fn test_estimate_with_chords_and_jitter() {
+        // Chords on the beats, eighths in between, played with a bit of timing jitter
+        let mut played = Vec::new();
+        for beat in 0..32 {
+            let jitter = [0.0, 0.012, -0.008, 0.015, -0.011][beat % 5];
+            let t = 2.0 + beat as f64 * 60. / 84. + jitter;
+            for _ in 0..3 {
+                played.push(Onset { seconds: t, weight: 0.6 });
+            }
+            played.push(Onset { seconds: t + 30. / 84. - jitter, weight: 0.5 });
+        }
+        played.sort_by(|o1, o2| o1.seconds.total_cmp(&o2.seconds));
+        let grid = estimate(&played, 4).unwrap();
+        assert!((grid.tempo - 84.).abs() < 0.2, "{:?}", grid);
+        assert!((grid.downbeat - 2.0).abs() < 0.02, "{:?}", grid);
+    }
+
+    #[test]
+    fn test_estimate_rejects_irregular_onsets() {
+        // Intervals from a linear congruential generator, between 0.15 and 0.9 seconds
+        let mut state: u32 = 12345;
+        let mut seconds = 0.0;
+        let played: Vec<Onset> = (0..40)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                seconds += 0.15 + (state >> 16) as f64 / 65536. * 0.75;
+                Onset { seconds, weight: 0.8 }
+            })
+            .collect();
+        assert_eq!(estimate(&played, 4), None);
+        assert_eq!(estimate(&played[..5], 4), None);
+    }
+}
@@ -1,244 +1,1975 @@
 use core::f32;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    time::SystemTime,
     sync::{Arc, RwLock},
 };
 
 use crate::{
-    TransportInfo,
-    midistore::{MidiStore, StoreEntry},
+    Samples, TransportInfo,
+    exports::{ClipInfo, ExportLibrary},
+    midistore::{ExpressionKind, MidiStore, MpeZone, Note, StoreEntry, TempoEstimate, SUSTAIN_PEDAL},
 };
 use arboard::Clipboard;
 use midly::{
-    MetaMessage, MidiMessage, Track, TrackEvent, TrackEventKind,
-    num::{u4, u15, u24, u28},
+    MetaMessage, MidiMessage, PitchBend, Smf, Track, TrackEvent, TrackEventKind,
+    num::{u4, u7, u15, u24, u28},
 };
-use nih_plug::{nih_dbg, nih_error, nih_log, nih_warn};
+use miniserde::{Deserialize, Serialize};
+use nih_plug::{nih_dbg, nih_log, nih_warn};
 use tempfile::{Builder, NamedTempFile};
 
+/// Options for exporting a selection to a MIDI file.
+#[derive(Clone, Copy, Debug)]
+pub struct ExportOptions {
+    /// Resolution of the exported file in ticks per quarter note.
+    pub ppqn: u16,
+    /// Extend notes held by the sustain pedal to the end of their sound and leave out the
+    /// pedal events, for DAWs and instruments without pedal support.
+    pub bake_pedal: bool,
+    /// If the selection contains per-note expressions, spread the notes over the member
+    /// channels of an MPE lower zone and write the expressions as channel messages.
+    pub expressions: bool,
+    /// Write the recorded tempo and time signature changes as meta events.
+    pub tempo_map: bool,
+    /// Write a Format 1 file with one track per MIDI channel instead of a single track.
+    pub split_channels: bool,
+    /// Move the events of every track to channel 1 when splitting channels into tracks.
+    pub channel_one: bool,
+    /// Channel state from before the selection that is written at its start.
+    pub chase: ChaseOptions,
+    /// Grid the notes are moved to.
+    pub quantize: QuantizeOptions,
+    /// Where the exported clip ends.
+    pub padding: ClipPadding,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            ppqn: DEFAULT_PPQN,
+            bake_pedal: false,
+            expressions: false,
+            tempo_map: false,
+            split_channels: false,
+            channel_one: false,
+            chase: ChaseOptions::default(),
+            quantize: QuantizeOptions::default(),
+            padding: ClipPadding::default(),
+        }
+    }
+}
+
+/// Where an exported clip ends, some DAWs loop clips that end in the middle of a bar badly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClipPadding {
+    /// The clip ends with the selection.
+    #[default]
+    SelectionEnd,
+    /// The clip is extended to the next bar line, see [`padded_end`].
+    NextBar,
+    /// The clip is extended to the next bar line that makes it 1, 2, 4, 8, ... bars long.
+    PowerOfTwoBars,
+}
+
+/// Classes of channel state that are chased to the selection start, see [`chased_state`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChaseOptions {
+    /// Bank select and program change.
+    pub programs: bool,
+    /// Pitch bend.
+    pub pitch_bend: bool,
+    /// Sustain, portamento, sostenuto, soft, legato and hold 2 pedal (CC64 to CC69).
+    pub pedals: bool,
+    /// All other controllers, e.g. the mod wheel, volume or pan.
+    pub controllers: bool,
+    /// Channel pressure.
+    pub pressure: bool,
+}
+
+/// Grid for quantizing exported notes, starting at every captured bar.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuantizeGrid {
+    /// Notes stay where they were played.
+    #[default]
+    Off,
+    Quarter,
+    QuarterTriplet,
+    Eighth,
+    EighthTriplet,
+    Sixteenth,
+    SixteenthTriplet,
+    ThirtySecond,
+    ThirtySecondTriplet,
+}
+
+impl QuantizeGrid {
+    /// Length of a grid step in quarter notes, `None` if quantizing is off.
+    pub fn quarters(self) -> Option<f64> {
+        use QuantizeGrid::*;
+        match self {
+            Off => None,
+            Quarter => Some(1.),
+            QuarterTriplet => Some(2. / 3.),
+            Eighth => Some(1. / 2.),
+            EighthTriplet => Some(1. / 3.),
+            Sixteenth => Some(1. / 4.),
+            SixteenthTriplet => Some(1. / 6.),
+            ThirtySecond => Some(1. / 8.),
+            ThirtySecondTriplet => Some(1. / 12.),
+        }
+    }
+}
+
+/// What happens to the NoteOffs of quantized notes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteOffQuantize {
+    /// NoteOffs are moved to the grid as well, notes are at least a grid step long.
+    Quantize,
+    /// NoteOffs move with their NoteOn, notes keep the length they were played with.
+    #[default]
+    KeepLength,
+    /// NoteOffs stay where they were played.
+    Leave,
+}
+
+/// How exported notes are quantized, see [`Quantizer`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuantizeOptions {
+    pub grid: QuantizeGrid,
+    /// How far notes are moved towards the grid, from 0 to 1.
+    pub strength: f64,
+    /// How far every second grid point is delayed, from 0 to 1 for up to half a grid step.
+    pub swing: f64,
+    pub note_offs: NoteOffQuantize,
+}
+
+/// Resolution of exported files unless configured otherwise.
+pub const DEFAULT_PPQN: u16 = 480;
+
+/// Number of member channels of the MPE lower zone used for exporting expressions.
+const MPE_MEMBER_CHANNELS: usize = 15;
+
+/// Names of the tracks of split channels, see [`split_channels`].
+const TRACK_NAMES: [&str; 18] = [
+    "Channel 1",
+    "Channel 2",
+    "Channel 3",
+    "Channel 4",
+    "Channel 5",
+    "Channel 6",
+    "Channel 7",
+    "Channel 8",
+    "Channel 9",
+    "Channel 10",
+    "Channel 11",
+    "Channel 12",
+    "Channel 13",
+    "Channel 14",
+    "Channel 15",
+    "Channel 16",
+    "MPE Lower Zone",
+    "MPE Upper Zone",
+];
+
+/// Tempo used for exporting if neither the host nor the notes tell one, in BPM.
+const FALLBACK_TEMPO: f64 = 120.;
+
+/// Number of exports listed in the recent exports panel.
+const RECENT_EXPORTS: usize = 10;
+
+/// An exported clip, as listed in the recent exports panel.
+#[derive(Clone, Debug)]
+pub struct RecentExport {
+    pub path: PathBuf,
+    /// Selection the clip was exported from, in seconds.
+    pub t0: f32,
+    pub t1: f32,
+    /// Numbers of the first and the last captured bar in the clip.
+    pub bars: Option<(i32, i32)>,
+    /// Tempo at the start of the clip in BPM.
+    pub tempo: f64,
+    /// Notes in the clip as (start, end, key), times from 0 to 1 relative to the clip length.
+    pub notes: Vec<(f32, f32, u8)>,
+}
+
 pub struct MidiTransfers {
     store: Arc<RwLock<MidiStore>>,
+    options: ExportOptions,
+    /// Where exported clips are kept, they go to a temp file if this is `None` or fails.
+    library: Option<ExportLibrary>,
     midifile: Option<NamedTempFile>,
+    /// Exported clips whose files are still around, the latest first.
+    recent: VecDeque<RecentExport>,
+    /// Recent export picked in the panel, waiting for the note view to show it.
+    highlight: Option<usize>,
     clippy: Option<Clipboard>,
 }
 
 impl MidiTransfers {
-    pub fn new(store: Arc<RwLock<MidiStore>>) -> Self {
+    pub fn new(
+        store: Arc<RwLock<MidiStore>>,
+        options: ExportOptions,
+        library: Option<ExportLibrary>,
+    ) -> Self {
         Self {
             store,
+            options,
+            library,
             midifile: None,
+            recent: VecDeque::with_capacity(RECENT_EXPORTS),
+            highlight: None,
             clippy: None,
         }
     }
 
+    /// Applies a changed configuration to the following exports.
+    pub fn configure(&mut self, options: ExportOptions, library: Option<ExportLibrary>) {
+        self.options = options;
+        self.library = library;
+    }
+
+    /// Exported clips whose files are still around, the latest first.
+    pub fn recent(&self) -> &VecDeque<RecentExport> {
+        &self.recent
+    }
+
+    /// Copies a recent export to the clipboard again, if its file still exists.
+    pub fn copy_recent(&mut self, idx: usize) {
+        match self.recent.get(idx).map(|recent| recent.path.clone()) {
+            Some(path) if path.exists() => self.copy_to_clipboard(&path),
+            Some(path) => nih_warn!("Recent export no longer exists: {:?}", path),
+            None => {}
+        }
+    }
+
+    /// Forgets recent exports whose files are gone, e.g. removed by the cleanup of another
+    /// instance sharing the export folder. Returns `true` if any were forgotten.
+    pub fn prune_recent(&mut self) -> bool {
+        let len = self.recent.len();
+        self.recent.retain(|recent| recent.path.exists());
+        if self.recent.len() == len {
+            return false;
+        }
+        // Indices changed
+        self.highlight = None;
+        true
+    }
+
+    /// Asks the note view to show a recent export, see [`MidiTransfers::take_highlight`].
+    pub fn highlight(&mut self, idx: usize) {
+        self.highlight = Some(idx);
+    }
+
+    /// Recent export picked in the panel since the last call.
+    pub fn take_highlight(&mut self) -> Option<&RecentExport> {
+        self.highlight.take().and_then(|idx| self.recent.get(idx))
+    }
+
     /// Creates a MIDI file from the selected time range and copies it to the clipboard.
     ///
     /// This function extracts all MIDI notes and events within the specified time range,
     /// normalizes them relative to the selection start time, and exports them as a standard
-    /// MIDI file. The resulting file is then copied to the system clipboard for easy pasting
-    /// into other DAWs.
-    /// 
-    /// The exported clip will run at 480 PPQN and not contain tempo information. Including tempo
-    /// caused annoying popups in Bitwig asking if I want to import the tempo information which
-    /// is also not doing what I wanted. This has the side effect that the DAW should be set to the
-    /// correct tempo as incorrect tempo will cause stretching ot shrinking. You don't need to have
-    /// the BPM set before recording, just at the time of exporting. Also you can just stretch the
-    /// clip to adjust.
-    /// 
-    /// This has the side effect that recording in, e.g, 120 BPM, then setting your DAW to 96, you can
-    /// still select whole bars with snapping and paste them after slowing the BPM and it will still
-    /// be the same number of bars.
+    /// MIDI file in the export library. The resulting file is then copied to the system
+    /// clipboard for easy pasting into other DAWs.
+    ///
+    /// Resolution, file format, tempo information and padding come from the export options,
+    /// which the host profile of the config adjusts to the DAW, see [`crate::host_profile`].
+    /// By default, the clip does not contain tempo information. Including tempo caused annoying
+    /// popups in Bitwig asking if I want to import the tempo information which is also not
+    /// doing what I wanted. Instead, ticks follow the tempo map recorded during the selection,
+    /// so the clip lines up with the bars it was played to.
+    ///
+    /// This has the side effect that recording in, e.g, 120 BPM, then setting your DAW to 96,
+    /// you can still select whole bars with snapping and paste them after slowing the BPM and it
+    /// will still be the same number of bars.
     ///
     /// # Arguments
     ///
     /// * `t0` - Start time of the selection in seconds
     /// * `t1` - End time of the selection in seconds
-    /// * `transport` - Transport information containing tempo and timing details
+    /// * `transport` - Transport information, its tempo is used if the store recorded none
+    ///
+    /// The selection is converted to samples right away, all event timings are derived from the
+    /// exact sample positions stored in the MidiStore.
     ///
     /// # Behavior
     ///
     /// - Returns early if the selection contains no notes
+    /// - Writes Note-On and Note-Off messages from the captured notes, so they are always balanced
     /// - Handles "hanging" notes that start before the selection (includes Note-On messages)
     /// - Handles "incomplete" notes that end after the selection (includes Note-Off messages)
-    /// - Quantizes all event timings based on the current tempo (480 PPQN)
-    /// - Pads the MIDI file to align with bar boundaries when necessary
-    /// - Includes EndOfTrack meta event
+    /// - Writes the controllers, programs and pitch bend in effect at the selection start
+    /// - Converts all event timings to ticks based on the recorded tempo map
+    /// - Moves notes towards the grid of the captured bars if quantizing is configured
+    /// - Uses and writes the estimated tempo of the take if the transport was not playing
+    /// - Pads the MIDI file to the next bar or power-of-two bar count if configured
+    /// - Includes EndOfTrack meta event after all other events
+    ///
+    /// Returns the path of the exported file, e.g. for dragging it out of the view. Files in the
+    /// library stay until they are cleaned up, the temp file used without a library only until
+    /// the next selection is exported.
     ///
     /// # Logging
     ///
     /// Logs warnings on errors (empty selection, file creation, clipboard access) and
     /// informational messages on success.
-    pub fn new_selection(&mut self, t0: f32, t1: f32, transport: &TransportInfo) {
-        let store = self.store.read().unwrap();
+    pub fn new_selection(&mut self, t0: f32, t1: f32, transport: &TransportInfo) -> Option<&Path> {
+        // The clip borrows SysEx data from the store, so it is encoded before releasing the lock
+        let (encoded, info, notes) = {
+            let store = self.store.read().unwrap();
+            let (s0, s1) = (store.samples_at(t0 as f64), store.samples_at(t1 as f64));
+            let encoded = build_smf(&store, s0, s1, transport.tempo, &self.options).map(|smf| {
+                let mut encoded = Vec::new();
+                smf.write_std(&mut encoded).map(|_| encoded)
+            });
+            let info = ClipInfo {
+                time: SystemTime::now(),
+                tempo: clip_tempo(&store, s0, s1, transport.tempo),
+                bars: bar_range(&store, s0, s1),
+            };
+            let length = (s1 - s0).max(1) as f32;
+            let notes = store
+                .notes_in_samples(s0, s1)
+                .map(|note| {
+                    let start = (note.t_start - s0).max(0) as f32 / length;
+                    let end = (note.t_end.min(s1) - s0) as f32 / length;
+                    (start, end, note.key.as_int())
+                })
+                .collect();
+            (encoded, info, notes)
+        };
+        let Some(encoded) = encoded else {
+            nih_warn!("Empty selection, not exporting");
+            return None;
+        };
+        let Ok(encoded) = encoded else {
+            nih_warn!("Error encoding MIDI file");
+            return None;
+        };
+
+        let saved = self.library.as_ref().map(|library| library.save(&encoded, &info));
+        let path = match saved {
+            Some(Ok(path)) => path,
+            Some(Err(e)) => {
+                nih_warn!("Failed to save to the export library, using a tmpfile: {}", e);
+                self.save_tmpfile(&encoded)?
+            }
+            None => self.save_tmpfile(&encoded)?,
+        };
+        // Files of the temp fallback or cleaned up from the library are gone, or were replaced
+        self.recent.retain(|recent| recent.path != path && recent.path.exists());
+        self.recent.truncate(RECENT_EXPORTS - 1);
+        self.recent.push_front(RecentExport {
+            path: path.clone(),
+            t0,
+            t1,
+            bars: info.bars,
+            tempo: info.tempo,
+            notes,
+        });
 
-        let notes = store.notes_in_time_select(-f32::INFINITY, f32::INFINITY, t0, t1);
+        // The file can still be dragged out if the clipboard fails
+        self.copy_to_clipboard(&path);
+        self.recent.front().map(|recent| recent.path.as_path())
+    }
 
-        if notes.count() == 0 {
-            nih_warn!("Empty selection, not exporting");
-            return;
+    /// Puts a reference to the file at `path` into the clipboard.
+    fn copy_to_clipboard(&mut self, path: &Path) {
+        if self.clippy.is_none() {
+            let Ok(mut clippy) = Clipboard::new() else {
+                nih_dbg!("Error acquiring clipboard");
+                return;
+            };
+            self.clippy = Some(clippy);
+        }
+
+        if let Ok(_) = self
+            .clippy
+            .as_mut()
+            .unwrap()
+            .set()
+            .file_list(&[path])
+        {
+            nih_log!("Copied path {:?} to clipboard", path);
+        } else {
+            nih_warn!("Failed to copy to clipboard");
         }
+    }
 
-        let Ok(mut midifile) = Builder::new().prefix("mucap_").suffix(".mid").tempfile() else {
+    /// Writes the clip to a temp file that lives until the next export.
+    fn save_tmpfile(&mut self, encoded: &[u8]) -> Option<PathBuf> {
+        let Ok(midifile) = Builder::new().prefix("mucap_").suffix(".mid").tempfile() else {
             nih_warn!("Failed to create tmpfile");
-            return;
+            return None;
         };
 
         nih_dbg!("Created MIDI file: {:?}", midifile.path());
 
-        let mut of = midifile.as_file_mut();
-        let ppqn = 480;
-        let pps = ppqn as f32 * (transport.tempo as f32 / 60.);
-        let mut smf = midly::Smf::new(midly::Header::new(
-            midly::Format::SingleTrack,
-            midly::Timing::Metrical(u15::new(ppqn)),
-        ));
-        smf.tracks.push(midly::Track::new());
-
-        // We don't really want the tempo in the file to prevent DAWs like Bitwig from asking
-        // if we want to import the tempo for every single paste
-        /*smf.tracks[0].push(TrackEvent {
-            delta: u28::new(0),
-            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(
-                (1000000. * 60. / transport.tempo).round() as u32,
-            ))),
-        });*/
-
-        // Write Note-Ons already active at start
-        for (note, sel) in store.notes_in_time_select(-f32::INFINITY, f32::INFINITY, t0, t1) {
-            // Skip notes with very short tails that would end on tick 0 as these can
-            // cause artifacts (seen in Bitwig Studio)
-            let end_tick = ((note.t_end - t0) * pps).round() as i64;
-            if sel && note.t_start < t0 && end_tick > 0 {
-                if let Some(entry) = store.store.get(note.idx_on) {
-                    let StoreEntry::MidiData { channel, data } = entry.1;
-                    smf.tracks[0].push(TrackEvent {
-                        delta: u28::new(0),
-                        kind: TrackEventKind::Midi {
-                            channel,
-                            message: data,
-                        },
-                    });
+        if let Ok(_) = std::fs::write(midifile.path(), encoded) {
+            nih_dbg!("Saved MIDI file: {:?}", midifile.path());
+        } else {
+            nih_warn!("Error saving MIDI file");
+            return None;
+        }
+
+        let path = midifile.path().to_path_buf();
+        self.midifile = Some(midifile);
+        Some(path)
+    }
+}
+
+/// Tempo at the start of the clip from `s0` to `s1`.
+///
+/// The estimated tempo of an untimed take comes first, then the recorded tempo map and
+/// `tempo` if neither is known.
+fn clip_tempo(store: &MidiStore, s0: Samples, s1: Samples, tempo: f64) -> f64 {
+    let tempo = match store.tempo_estimate_in_samples(s0, s1) {
+        Some(estimate) => estimate.tempo,
+        None => store.tempo_at(s0).map_or(tempo, |change| change.tempo),
+    };
+    if tempo > 0. { tempo } else { FALLBACK_TEMPO }
+}
+
+/// Numbers of the first and the last captured bar the clip from `s0` to `s1` covers.
+///
+/// A clip starting inside a bar covers that bar if the next one was captured as well, the bar
+/// the clip ends in counts if it starts before the end. `None` if no bar starts in the clip.
+fn bar_range(store: &MidiStore, s0: Samples, s1: Samples) -> Option<(i32, i32)> {
+    let first = store.bars.partition_point(|bar| bar.t < s0);
+    let last = store.bars.partition_point(|bar| bar.t < s1);
+    if first == last {
+        return None;
+    }
+    let first_bar = &store.bars[first];
+    let first_number = match first.checked_sub(1).map(|idx| &store.bars[idx]) {
+        Some(before) if first_bar.t > s0 && before.bar_number + 1 == first_bar.bar_number => {
+            before.bar_number
+        }
+        _ => first_bar.bar_number,
+    };
+    Some((first_number, store.bars[last - 1].bar_number))
+}
+
+/// An event of the exported clip.
+struct ExportEvent<'a> {
+    tick: i64,
+    /// Position among events on the same tick, NoteOffs go first so retriggered notes do not
+    /// overlap, except for notes that start and end on the same tick.
+    order: u8,
+    /// Original time in samples, keeps the captured order of events on the same tick.
+    time: Samples,
+    kind: TrackEventKind<'a>,
+}
+
+/// Converts sample times to ticks, following the tempo map from the selection start on.
+///
+/// Events that were captured while the host transport was playing carry their position on the
+/// host grid. Between such events, ticks follow the host positions instead, so notes land
+/// exactly where the DAW saw them. If the transport did not play at all during the selection,
+/// the tempo estimated from the notes is used instead of the tempo map.
+struct TickClock<'a> {
+    store: &'a MidiStore,
+    /// Tempo estimate of the take the selection is part of, if the transport did not play.
+    estimate: Option<TempoEstimate>,
+    /// Time of the selection start in seconds.
+    sec0: f64,
+    /// Spans of constant tempo as (first sample, seconds since selection start, tick, ticks
+    /// per second), the first one starts at the selection start.
+    segments: Vec<(Samples, f64, f64, f64)>,
+    /// Events with a host position as (time, tick, continues the transport run of the event
+    /// before).
+    knots: Vec<(Samples, f64, bool)>,
+}
+
+impl<'a> TickClock<'a> {
+    /// Ticks start at `s0`, `tempo` is used if the store has no tempo recorded or estimated.
+    fn new(store: &'a MidiStore, s0: Samples, s1: Samples, tempo: f64, ppqn: u16) -> Self {
+        let ppqn = ppqn as f64;
+        let tps = |tempo: f64| ppqn * tempo / 60.;
+        let sec0 = store.seconds(s0);
+        let estimate = store.tempo_estimate_in_samples(s0, s1).copied();
+        let tempo = clip_tempo(store, s0, s1, tempo);
+        let mut segments = vec![(s0, 0.0, 0.0, tps(tempo))];
+        let changes = store.tempo_changes_in_samples(s0, s1).filter(|_| estimate.is_none());
+        for change in changes {
+            let (_, seconds, tick, tps_before) = *segments.last().unwrap();
+            let change_seconds = store.seconds(change.t) - sec0;
+            let change_tick = tick + (change_seconds - seconds) * tps_before;
+            segments.push((change.t, change_seconds, change_tick, tps(change.tempo)));
+        }
+        let mut clock = Self {
+            store,
+            estimate,
+            sec0,
+            segments,
+            knots: Vec::new(),
+        };
+
+        let mut last: Option<(Samples, f64, f64)> = None;
+        for (time, beats) in store.positions_in_samples(s0, s1) {
+            let Some(beats) = beats else {
+                // The transport stopped in between
+                last = None;
+                continue;
+            };
+            let knot = match last {
+                Some((last_time, last_beats, last_tick)) => {
+                    let expected = clock.tempo_ticks(time) - clock.tempo_ticks(last_time);
+                    let tick = last_tick + (beats - last_beats) * ppqn;
+                    // The transport jumped if the host position does not match the tempo
+                    if beats >= last_beats && (tick - last_tick - expected).abs() <= ppqn / 4. {
+                        (time, tick, true)
+                    } else {
+                        (time, last_tick + expected, false)
+                    }
                 }
+                None => (time, clock.knot_ticks(time), false),
+            };
+            last = Some((time, beats, knot.1));
+            clock.knots.push(knot);
+        }
+        clock
+    }
+
+    /// Ticks according to the tempo map only.
+    fn tempo_ticks(&self, s: Samples) -> f64 {
+        let idx = self.segments.partition_point(|(start, ..)| *start <= s);
+        let (_, seconds, tick, tps) = self.segments[idx.saturating_sub(1)];
+        tick + (self.store.seconds(s) - self.sec0 - seconds) * tps
+    }
+
+    /// Ticks following the host positions, continuing with the tempo map after the last one.
+    fn knot_ticks(&self, s: Samples) -> f64 {
+        let idx = self.knots.partition_point(|(time, ..)| *time <= s);
+        match (idx.checked_sub(1).map(|idx| self.knots[idx]), self.knots.get(idx)) {
+            (Some((t0, tick0, _)), Some(&(t1, tick1, true))) => {
+                tick0 + (tick1 - tick0) * (s - t0) as f64 / (t1 - t0) as f64
             }
+            (Some((t0, tick0, _)), _) => tick0 + self.tempo_ticks(s) - self.tempo_ticks(t0),
+            (None, _) => self.tempo_ticks(s),
         }
+    }
 
-        let mut sum_delta: i64 = 0;
+    fn ticks(&self, s: Samples) -> i64 {
+        self.knot_ticks(s).round() as i64
+    }
+}
 
-        for (idx, time, channel, message) in store.midi_events() {
-            let write = (time >= t0) && (time <= t1);
-            if write {
-                let track_time = time - t0;
-                let best_track_quantized = (track_time * pps).round() as i64;
-                if (best_track_quantized < sum_delta) {
-                    nih_error!(
-                        "Delta moving backwards, from {} to {}",
-                        sum_delta,
-                        best_track_quantized
-                    );
+/// Moves notes towards a grid that starts at every captured bar.
+///
+/// Bars that are no multiple of the grid step long, e.g. a 7/8 bar on a quarter grid, end with
+/// a shorter step, as the grid starts over at the next bar. Without captured bars around the
+/// selection, the grid starts at the estimated downbeat or at the selection start.
+struct Quantizer {
+    /// Grid step in ticks.
+    step: f64,
+    strength: f64,
+    swing: f64,
+    note_offs: NoteOffQuantize,
+    /// Ticks of the bars the grid starts at, ascending.
+    bars: Vec<f64>,
+}
+
+impl Quantizer {
+    /// Returns `None` if quantizing is off.
+    fn new(
+        options: &QuantizeOptions,
+        store: &MidiStore,
+        clock: &TickClock,
+        s0: Samples,
+        s1: Samples,
+        ppqn: u16,
+    ) -> Option<Self> {
+        let step = options.grid.quarters()? * ppqn as f64;
+        // From the bar the selection starts in up to the first bar after it
+        let first = store.bars.partition_point(|bar| bar.t <= s0).saturating_sub(1);
+        let last = store.bars.partition_point(|bar| bar.t <= s1) + 1;
+        let mut bars: Vec<f64> = store
+            .bars
+            .range(first..last.min(store.bars.len()))
+            .map(|bar| clock.knot_ticks(bar.t))
+            .collect();
+        if bars.is_empty() {
+            bars.push(clock.estimate.map_or(0., |estimate| clock.knot_ticks(estimate.downbeat)));
+        }
+        Some(Self {
+            step,
+            strength: options.strength.clamp(0., 1.),
+            swing: options.swing.clamp(0., 1.),
+            note_offs: options.note_offs,
+            bars,
+        })
+    }
+
+    /// Moves `tick` towards the nearest grid point.
+    fn quantize(&self, tick: i64) -> i64 {
+        let t = tick as f64;
+        let idx = self.bars.partition_point(|bar| *bar <= t);
+        let anchor = self.bars[idx.saturating_sub(1)];
+        let k0 = ((t - anchor) / self.step).floor() as i64;
+        // Every second grid point of the bar is delayed by the swing
+        let grid = (k0 - 1..=k0 + 2).map(|k| {
+            let swing = if k.rem_euclid(2) == 1 { 0.5 * self.swing } else { 0. };
+            anchor + (k as f64 + swing) * self.step
+        });
+        // Where the next bar starts, its grid wins
+        let target = grid
+            .filter(|point| self.bars.get(idx).is_none_or(|next| point < next))
+            .chain(self.bars.get(idx).copied())
+            .min_by(|a, b| (a - t).abs().total_cmp(&(b - t).abs()))
+            .unwrap_or(t);
+        (t + self.strength * (target - t)).round() as i64
+    }
+
+    /// Quantizes a note from `on` to `off` tick.
+    ///
+    /// Notes that started before the selection keep their start, notes that end after it their
+    /// end, and no note ends after `end_tick`.
+    fn note(&self, on: i64, off: i64, starts: bool, ends: bool, end_tick: i64) -> (i64, i64) {
+        let q_on = if starts { self.quantize(on).clamp(0, end_tick) } else { on };
+        if !ends {
+            return (q_on, off.max(q_on));
+        }
+        let q_off = match self.note_offs {
+            NoteOffQuantize::Quantize => {
+                let q_off = self.quantize(off);
+                if q_off > q_on {
+                    q_off
+                } else {
+                    q_on + ((self.strength * self.step).round() as i64).max(1)
                 }
-                let delta = (best_track_quantized - sum_delta) as u32;
-                sum_delta = best_track_quantized;
-                smf.tracks[0].push(TrackEvent {
-                    delta: u28::new(delta),
-                    kind: TrackEventKind::Midi { channel, message },
-                });
             }
+            NoteOffQuantize::KeepLength => off + q_on - on,
+            NoteOffQuantize::Leave => off,
+        };
+        (q_on, q_off.min(end_tick).max(q_on))
+    }
+}
+
+/// Builds a single track MIDI file from the notes and events between `s0` and `s1`.
+///
+/// Event times are converted to ticks at [`ExportOptions::ppqn`] following the host positions
+/// and the tempo map of the store, or the tempo estimated from the notes if the transport was not playing.
+/// `tempo` is only used if the store has no tempo recorded or estimated. Returns `None` if
+/// the range contains no notes. See [`MidiTransfers::new_selection`] for how hanging and
+/// incomplete notes are handled.
+///
+/// NoteOns and NoteOffs are written from the notes in the store rather than from the captured
+/// events, so every exported NoteOn has a matching NoteOff no matter how stacked NoteOns were
+/// paired. Notes still held are ended at the end of the selection.
+///
+/// SysEx messages in the range are written as SysEx events, borrowing their data from the store.
+///
+/// With [`ExportOptions::split_channels`], a Format 1 file is built instead, see
+/// [`split_channels`].
+///
+/// With [`ExportOptions::quantize`], notes are moved towards the grid of the captured bars,
+/// see [`Quantizer`]. Other events stay where they were played.
+///
+/// The clip ends as chosen by [`ExportOptions::padding`], see [`padded_end`].
+pub fn build_smf<'a>(
+    store: &'a MidiStore,
+    s0: Samples,
+    s1: Samples,
+    tempo: f64,
+    options: &ExportOptions,
+) -> Option<Smf<'a>> {
+    // Metrical timing leaves 15 bits for the resolution
+    let ppqn = options.ppqn.clamp(1, u15::max_value().as_int());
+    let clock = TickClock::new(store, s0, s1, tempo, ppqn);
+    let ticks = |s: Samples| clock.ticks(s);
+    // Notes running past the selection end with it, or with the bar line the clip ends at
+    // if the selection ends just past it
+    let clip_end = padded_end(store, &clock, s1, ticks(s1).max(0), options.padding, ppqn);
+    let end_tick = ticks(s1).clamp(0, clip_end);
+    let quantizer = Quantizer::new(&options.quantize, store, &clock, s0, s1, ppqn);
+
+    let mut events = Vec::new();
+    // Note ends as exported, `None` for notes that are still sounding
+    let completed: Vec<(&Note, Option<Samples>)> = if options.bake_pedal {
+        store
+            .notes_sounding_in_samples(s0, s1)
+            .map(|note| (note, note.sounding_end))
+            .collect()
+    } else {
+        store
+            .notes_in_samples(s0, s1)
+            .map(|note| (note, Some(note.t_end)))
+            .collect()
+    };
+    let held = store
+        .in_flight
+        .iter()
+        .filter(|note| note.t_start < s1)
+        .map(|note| (note, None));
+    // Notes as exported with their end, NoteOn tick and NoteOff tick
+    let mut exported = Vec::new();
+    for (note, end) in completed.into_iter().chain(held) {
+        let mut on_tick = if note.t_start < s0 { 0 } else { ticks(note.t_start) };
+        let mut off_tick = match end {
+            Some(end) if end <= s1 => ticks(end).min(end_tick),
+            _ => end_tick,
+        };
+        if let Some(quantizer) = &quantizer {
+            let ends = end.is_some_and(|end| end <= s1);
+            (on_tick, off_tick) = quantizer.note(on_tick, off_tick, note.t_start >= s0, ends, end_tick);
+        }
+        // Skip notes with very short tails that would end on tick 0 as these can
+        // cause artifacts (seen in Bitwig Studio)
+        if note.t_start < s0 && off_tick <= 0 {
+            continue;
+        }
+        exported.push((note, end, on_tick, off_tick.max(on_tick)));
+    }
+    if exported.is_empty() {
+        return None;
+    }
+
+    // A capture from an MPE controller already is in MPE layout, it only needs its zones set
+    // up. Otherwise per-note expressions need a channel of their own for every note.
+    let zones: Vec<MpeZone> = store.mpe_zones().copied().collect();
+    let mpe = zones.is_empty()
+        && options.expressions
+        && exported
+            .iter()
+            .any(|(note, ..)| store.note_expressions(note, s1).next().is_some());
+    let channels = if mpe {
+        events.extend(mpe_configuration(&MpeZone::new(u4::new(0), MPE_MEMBER_CHANNELS as u8)));
+        let spans: Vec<(i64, i64)> = exported.iter().map(|(.., on, off)| (*on, *off)).collect();
+        mpe_channels(&spans)
+    } else {
+        for zone in zones.iter() {
+            events.extend(mpe_configuration(zone));
         }
+        exported.iter().map(|(note, ..)| note.channel).collect()
+    };
 
-        // Write Note-Off at end of selection
-        let track_time = t1 - t0;
-        let best_track_quantized = (track_time * pps).round() as i64;
-        let mut delta_end = ((best_track_quantized - sum_delta) as u32).saturating_sub(1);
-        /*sum_delta = best_track_quantized;
-        smf.tracks[0].push(TrackEvent {
-            delta: u28::new(delta),
+    for ((note, end, on_tick, off_tick), channel) in exported.into_iter().zip(channels) {
+        let completed = end.is_some();
+        let off_vel = match store.entry(note.idx_off) {
+            Some((_, StoreEntry::MidiData { data: MidiMessage::NoteOff { vel, .. }, .. }))
+                if completed =>
+            {
+                *vel
+            }
+            _ => u7::new(0),
+        };
+        let start = note.t_start.max(s0);
+        let zone = zones.iter().find(|zone| zone.is_member(note.channel));
+        // Member channel messages of MPE captures are exported as captured, only notes that
+        // started before the selection need their expressions set up
+        if mpe || (zone.is_some() && note.t_start < s0) {
+            let bend_range = zone.map_or(48., |zone| zone.bend_range);
+            // Values in effect when the note starts go right before the NoteOn, the pitch
+            // bend is always reset as the channel may have played a bent note before
+            let mut initial = vec![(ExpressionKind::Tuning, 0.0)];
+            for expression in store.note_expressions(note, end.map_or(s1, |end| end.min(s1))) {
+                if expression.time > start {
+                    if mpe {
+                        events.push(ExportEvent {
+                            tick: ticks(expression.time).clamp(on_tick, off_tick),
+                            order: 1,
+                            time: expression.time,
+                            kind: TrackEventKind::Midi {
+                                channel,
+                                message: expression_message(expression.kind, expression.value, bend_range),
+                            },
+                        });
+                    }
+                } else if let Some(value) = initial.iter_mut().find(|(kind, _)| *kind == expression.kind) {
+                    value.1 = expression.value;
+                } else {
+                    initial.push((expression.kind, expression.value));
+                }
+            }
+            for (kind, value) in initial {
+                events.push(ExportEvent {
+                    tick: on_tick,
+                    order: 1,
+                    time: start,
+                    kind: TrackEventKind::Midi {
+                        channel,
+                        message: expression_message(kind, value, bend_range),
+                    },
+                });
+            }
+        }
+        events.push(ExportEvent {
+            tick: on_tick,
+            order: 1,
+            time: start,
+            kind: TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn {
+                    key: note.key,
+                    vel: note.vel,
+                },
+            },
+        });
+        events.push(ExportEvent {
+            tick: off_tick,
+            order: if off_tick > on_tick { 0 } else { 2 },
+            time: end.map_or(s1, |end| end.min(s1)),
             kind: TrackEventKind::Midi {
-                channel: 1.into(),
+                channel,
                 message: MidiMessage::NoteOff {
-                    key: 1.into(),
-                    vel: 1.into(),
+                    key: note.key,
+                    vel: off_vel,
                 },
             },
-        });*/
+        });
+    }
 
-        /*nih_log!(
-            "t0: {}, t1: {}, tdiff: {}, sum_delta: {}",
-            t0,
-            t1,
-            t1 - t0,
-            sum_delta
-        );*/
-
-        // Write Note-Offs for incomplete notes
-        for (note, sel) in store.notes_in_time_select(-f32::INFINITY, f32::INFINITY, t0, t1) {
-            if sel && note.t_end > t1 {
-                if let Some(entry) = store.store.get(note.idx_off) {
-                    let StoreEntry::MidiData { channel, data } = entry.1;
-                    smf.tracks[0].push(TrackEvent {
-                        delta: u28::new(delta_end),
-                        kind: TrackEventKind::Midi {
-                            channel,
-                            message: data,
-                        },
-                    });
-                    sum_delta += delta_end as i64;
-                    delta_end = 0;
-                }
-            }
+    for (channel, message) in chased_state(store, s0, &options.chase, options.bake_pedal) {
+        // The state of member channels belongs to their notes, which take care of it
+        if zones.iter().any(|zone| zone.is_member(channel)) {
+            continue;
         }
+        let channel = if mpe { u4::new(0) } else { channel };
+        events.push(ExportEvent {
+            tick: 0,
+            order: 0,
+            time: Samples::MIN,
+            kind: TrackEventKind::Midi { channel, message },
+        });
+    }
 
-        /*let bar_len_pulses = (pps * transport.bar_length()).round() as i64;
-        let abs_delta = (((sum_delta % bar_len_pulses) - bar_len_pulses) % bar_len_pulses).abs();
-        if abs_delta > 10 {
-            nih_log!(
-                "Adjusting delta {}, {}",
-                abs_delta,
-                sum_delta % bar_len_pulses
-            );
-            smf.tracks[0].push(TrackEvent {
-                delta: u28::new((bar_len_pulses - (sum_delta % bar_len_pulses)) as u32),
-                kind: TrackEventKind::Midi {
-                    channel: 1.into(),
-                    message: MidiMessage::NoteOff {
-                        key: 1.into(),
-                        vel: 1.into(),
-                    },
-                },
+    for (_, time, channel, message) in store.midi_events_in_samples(s0, s1) {
+        if matches!(
+            message,
+            MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }
+        ) {
+            continue;
+        }
+        if options.bake_pedal
+            && matches!(message, MidiMessage::Controller { controller, .. } if controller == SUSTAIN_PEDAL)
+        {
+            continue;
+        }
+        // In the MPE layout, channel wide messages apply to the whole zone
+        let channel = if mpe { u4::new(0) } else { channel };
+        events.push(ExportEvent {
+            tick: ticks(time),
+            order: 1,
+            time,
+            kind: TrackEventKind::Midi { channel, message },
+        });
+    }
+    // By default, we don't really want the tempo in the file to prevent DAWs like Bitwig from
+    // asking if we want to import the tempo for every single paste
+    let tempos: Vec<(i64, Samples, f64, (i32, i32))> = match clock.estimate {
+        // Nothing else tells the DAW the tempo the notes were played in, so it is always written
+        Some(estimate) => vec![(0, Samples::MIN, estimate.tempo, estimate.time_sig)],
+        None if options.tempo_map => {
+            // The tempo in effect at the selection start goes first
+            let initial = store.tempo_at(s0).map(|change| (0, Samples::MIN, change));
+            let changes = store
+                .tempo_changes_in_samples(s0, s1)
+                .map(|change| (ticks(change.t), change.t, change));
+            initial
+                .into_iter()
+                .chain(changes)
+                .map(|(tick, time, change)| (tick, time, change.tempo, change.time_sig))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+    let mut last_time_sig = None;
+    for (tick, time, tempo, time_sig) in tempos {
+        events.push(ExportEvent {
+            tick,
+            order: 0,
+            time,
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new(
+                (60_000_000. / tempo).round() as u32,
+            ))),
+        });
+        if last_time_sig != Some(time_sig) {
+            last_time_sig = Some(time_sig);
+            events.push(ExportEvent {
+                tick,
+                order: 0,
+                time,
+                kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
+                    time_sig.0 as u8,
+                    time_sig.1.max(1).ilog2() as u8,
+                    24,
+                    8,
+                )),
             });
-        };*/
-        smf.tracks[0].push(TrackEvent {
-            delta: u28::new(delta_end),
-            kind: TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+        }
+    }
+    for (time, data) in store.sysex_in_samples(s0, s1) {
+        // SMF SysEx events carry the message without the leading 0xF0
+        events.push(ExportEvent {
+            tick: ticks(time),
+            order: 1,
+            time,
+            kind: TrackEventKind::SysEx(&data[1..]),
         });
-        if let Ok(_) = smf.save(&midifile.path()) {
-            nih_dbg!("Saved MIDI file: {:?}", midifile.path());
+    }
+    events.sort_by_key(|ev| (ev.tick, ev.order, ev.time));
+
+    let (format, tracks) = if options.split_channels {
+        let zones = if mpe {
+            vec![MpeZone::new(u4::new(0), MPE_MEMBER_CHANNELS as u8)]
         } else {
-            nih_warn!("Error saving MIDI file");
-            return;
+            zones
+        };
+        (
+            midly::Format::Parallel,
+            split_channels(events, &zones, options.channel_one),
+        )
+    } else {
+        (midly::Format::SingleTrack, vec![events])
+    };
+    let mut smf = midly::Smf::new(midly::Header::new(
+        format,
+        midly::Timing::Metrical(u15::new(ppqn)),
+    ));
+    for events in tracks {
+        smf.tracks.push(write_track(events, clip_end));
+    }
+    Some(smf)
+}
+
+/// Tick an exported clip ends at with `padding`, `end_tick` being the end of the selection.
+///
+/// The bar grid continues from the last captured bar up to the selection end, in the time
+/// signature in effect there. Without captured bars, it starts at the estimated downbeat or at
+/// the selection start. Selections ending a few ticks past a bar line, e.g. through rounding,
+/// end at that bar line instead of taking up another bar.
+///
+/// Power-of-two bar counts start at the bar the clip starts in, a clip starting in the middle
+/// of a bar is shorter by the part of the bar before its start.
+fn padded_end(
+    store: &MidiStore,
+    clock: &TickClock,
+    s1: Samples,
+    end_tick: i64,
+    padding: ClipPadding,
+    ppqn: u16,
+) -> i64 {
+    if padding == ClipPadding::SelectionEnd {
+        return end_tick;
+    }
+    let last_bar = store.bars.partition_point(|bar| bar.t <= s1).checked_sub(1);
+    let (anchor, bar_beats) = match (clock.estimate, last_bar) {
+        (Some(estimate), _) => (clock.knot_ticks(estimate.downbeat), estimate.bar_beats()),
+        (None, Some(idx)) => {
+            let t = store.bars[idx].t;
+            let bar_beats = store.tempo_at(t).map_or(4., |change| change.bar_beats());
+            (clock.knot_ticks(t), bar_beats)
+        }
+        (None, None) => (0., store.tempo_at(s1).map_or(4., |change| change.bar_beats())),
+    };
+    let bar_ticks = bar_beats * ppqn as f64;
+    let tolerance = ppqn as f64 / 48.;
+    // Bar lines are counted from the anchor
+    let next_bar = ((end_tick as f64 - anchor - tolerance) / bar_ticks).ceil();
+    let bar = match padding {
+        ClipPadding::PowerOfTwoBars => {
+            let first_bar = ((tolerance - anchor) / bar_ticks).floor();
+            let count = (next_bar - first_bar).max(1.) as u64;
+            first_bar + count.next_power_of_two() as f64
         }
-        //nih_dbg!("{:?}", smf);
+        _ => next_bar,
+    };
+    (anchor + bar * bar_ticks).round() as i64
+}
 
-        if self.clippy.is_none() {
-            let Ok(mut clippy) = Clipboard::new() else {
-                nih_dbg!("Error acquiring clipboard");
-                return;
+/// Returns the last value of every chased controller, program, pitch bend and pressure before
+/// `s0` per channel, as (channel, message) in the order they are to be sent.
+///
+/// Bank selects go before the program change. Data entry and parameter number controllers are
+/// left out as their values mean nothing without the whole sequence, as are channel mode
+/// messages. With `bake_pedal`, the sustain pedal is left out like the other pedal events.
+fn chased_state(
+    store: &MidiStore,
+    s0: Samples,
+    chase: &ChaseOptions,
+    bake_pedal: bool,
+) -> Vec<(u4, MidiMessage)> {
+    // Last messages by channel, position in the output and controller
+    let mut state = BTreeMap::new();
+    for (channel, _, message) in store.channel_state_before(s0) {
+        let slot = match message {
+            MidiMessage::Controller { controller, .. } => match controller.as_int() {
+                0 | 32 if chase.programs => (0, controller.as_int()),
+                6 | 38 | 96..=101 | 120..=127 => continue,
+                SUSTAIN_PEDAL if bake_pedal => continue,
+                64..=69 if chase.pedals => (2, controller.as_int()),
+                0 | 32 | 64..=69 => continue,
+                _ if chase.controllers => (2, controller.as_int()),
+                _ => continue,
+            },
+            MidiMessage::ProgramChange { .. } if chase.programs => (1, 0),
+            MidiMessage::PitchBend { .. } if chase.pitch_bend => (3, 0),
+            MidiMessage::ChannelAftertouch { .. } if chase.pressure => (4, 0),
+            _ => continue,
+        };
+        state.insert((channel.as_int(), slot), message);
+    }
+    state
+        .into_iter()
+        .map(|((channel, _), message)| (u4::new(channel), message))
+        .collect()
+}
+
+/// Splits the events of a clip into one track per MIDI channel, named after the channel.
+///
+/// The channels of an MPE zone stay together in one track. Tempo, time signature and SysEx
+/// events go into a conductor track in front, if there are any. With `channel_one`, the events
+/// of all tracks but the MPE zones are moved to channel 1.
+fn split_channels<'a>(
+    events: Vec<ExportEvent<'a>>,
+    zones: &[MpeZone],
+    channel_one: bool,
+) -> Vec<Vec<ExportEvent<'a>>> {
+    let mut conductor = Vec::new();
+    // Tracks by their index in TRACK_NAMES, so they are sorted by channel
+    let mut tracks: BTreeMap<usize, Vec<ExportEvent>> = BTreeMap::new();
+    for mut ev in events {
+        let TrackEventKind::Midi { channel, .. } = &mut ev.kind else {
+            conductor.push(ev);
+            continue;
+        };
+        let zone = zones
+            .iter()
+            .find(|zone| zone.manager == *channel || zone.is_member(*channel));
+        let track = match zone {
+            Some(zone) if zone.manager.as_int() == 0 => 16,
+            Some(_) => 17,
+            None => channel.as_int() as usize,
+        };
+        if channel_one && zone.is_none() {
+            *channel = u4::new(0);
+        }
+        tracks
+            .entry(track)
+            .or_insert_with(|| {
+                vec![ExportEvent {
+                    tick: 0,
+                    order: 0,
+                    time: Samples::MIN,
+                    kind: TrackEventKind::Meta(MetaMessage::TrackName(TRACK_NAMES[track].as_bytes())),
+                }]
+            })
+            .push(ev);
+    }
+    (!conductor.is_empty())
+        .then_some(conductor)
+        .into_iter()
+        .chain(tracks.into_values())
+        .collect()
+}
+
+/// Writes sorted events into a track ending at `end_tick`.
+///
+/// Events past the end, which can only get there through rounding, are moved to it, so the
+/// EndOfTrack event comes after the NoteOffs of notes still sounding at the end.
+fn write_track<'a>(events: Vec<ExportEvent<'a>>, end_tick: i64) -> Track<'a> {
+    let mut track = Track::new();
+    let mut sum_delta: i64 = 0;
+    for ev in events {
+        // Events can only end up before the selection start through rounding
+        let tick = ev.tick.min(end_tick).max(sum_delta);
+        track.push(TrackEvent {
+            delta: u28::new((tick - sum_delta) as u32),
+            kind: ev.kind,
+        });
+        sum_delta = tick;
+    }
+
+    track.push(TrackEvent {
+        delta: u28::new((end_tick - sum_delta).max(0) as u32),
+        kind: TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// MPE Configuration Message for `zone` and its member pitch bend range, before anything else.
+fn mpe_configuration<'a>(zone: &MpeZone) -> impl Iterator<Item = ExportEvent<'a>> {
+    // The MCM resets the pitch bend range, which is set on any member channel afterwards
+    let first_member = if zone.manager.as_int() == 0 { 1 } else { 14 };
+    let mut rpns = vec![(zone.manager, 6, zone.members)];
+    if zone.bend_range != 48. {
+        rpns.push((u4::new(first_member), 0, zone.bend_range.round() as u8));
+    }
+    rpns.into_iter().flat_map(|(channel, rpn, value)| {
+        // The null RPN afterwards keeps later data entry from changing the parameter
+        [(101, 0), (100, rpn), (6, value), (101, 127), (100, 127)].map(|(controller, value)| {
+            ExportEvent {
+                tick: 0,
+                order: 0,
+                time: Samples::MIN,
+                kind: TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller {
+                        controller: u7::new(controller),
+                        value: u7::new(value),
+                    },
+                },
+            }
+        })
+    })
+}
+
+/// Assigns each note, given as (NoteOn tick, NoteOff tick), a member channel of the MPE lower zone.
+///
+/// Notes get the channel that has been free the longest. If all channels are busy, the one
+/// that frees up first is shared.
+fn mpe_channels(spans: &[(i64, i64)]) -> Vec<u4> {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by_key(|idx| spans[*idx]);
+    let mut free_at = [i64::MIN; MPE_MEMBER_CHANNELS];
+    let mut channels = vec![u4::new(1); spans.len()];
+    for idx in order {
+        let (on_tick, off_tick) = spans[idx];
+        let member = (0..MPE_MEMBER_CHANNELS)
+            .min_by_key(|member| (free_at[*member] > on_tick, free_at[*member]))
+            .unwrap();
+        free_at[member] = off_tick;
+        channels[idx] = u4::new(member as u8 + 1);
+    }
+    channels
+}
+
+/// Channel message an expression is exported as on an MPE member channel.
+///
+/// Tuning becomes pitch bend over `bend_range` semitones.
+fn expression_message(kind: ExpressionKind, value: f32, bend_range: f32) -> MidiMessage {
+    let controller = match kind {
+        ExpressionKind::Tuning => {
+            return MidiMessage::PitchBend {
+                bend: PitchBend::from_f32(value / bend_range),
+            };
+        }
+        ExpressionKind::Pressure => {
+            return MidiMessage::ChannelAftertouch {
+                vel: u7::new((value.clamp(0., 1.) * 127.).round() as u8),
             };
-            self.clippy = Some(clippy);
         }
+        ExpressionKind::Vibrato => 1,
+        ExpressionKind::Volume => 7,
+        ExpressionKind::Pan => 10,
+        ExpressionKind::Expression => 11,
+        ExpressionKind::Brightness => 74,
+    };
+    MidiMessage::Controller {
+        controller: u7::new(controller),
+        value: u7::new((kind.normalized(value) * 127.).round() as u8),
+    }
+}
 
-        if let Ok(_) = self
-            .clippy
-            .as_mut()
-            .unwrap()
-            .set()
-            .file_list(&[midifile.path()])
-        {
-            nih_log!("Copied path {:?} to clipboard", midifile.path());
-        } else {
-            nih_warn!("Failed to copy to clipboard");
-            return;
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::midistore::{Bar, MpeMode, NotePairing};
+    use midly::num::u14;
+
+    fn note_on(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+        [0x90 | channel, key, vel]
+    }
+
+    fn note_off(channel: u8, key: u8, vel: u8) -> [u8; 3] {
+        [0x80 | channel, key, vel]
+    }
+
+    /// Store at 1 kHz, so with 120 BPM one sample is 0.96 ticks
+    fn stacked_store(pairing: NotePairing) -> MidiStore {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.set_note_pairing(pairing);
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(100, [0xB0, 0x40, 0x7F]).unwrap();
+        store.add(500, note_on(0, 60, 80)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        store.add(1500, note_off(0, 60, 0)).unwrap();
+        store.add(1500, note_on(1, 64, 90)).unwrap();
+        store.add(1800, note_on(0, 60, 70)).unwrap();
+        // Stray NoteOff without any note
+        store.add(1900, note_off(2, 50, 0)).unwrap();
+        store
+    }
+
+    /// Returns (tick, message) of all MIDI events, checking that NoteOns and NoteOffs balance
+    fn check_balanced(smf: &Smf) -> Vec<(i64, MidiMessage)> {
+        let mut events = Vec::new();
+        let mut held: HashMap<(u8, u8), i32> = HashMap::new();
+        let mut tick = 0;
+        for ev in smf.tracks[0].iter() {
+            tick += ev.delta.as_int() as i64;
+            if let TrackEventKind::Midi { channel, message } = ev.kind {
+                match message {
+                    MidiMessage::NoteOn { key, .. } => {
+                        *held.entry((channel.as_int(), key.as_int())).or_default() += 1;
+                    }
+                    MidiMessage::NoteOff { key, .. } => {
+                        let count = held.entry((channel.as_int(), key.as_int())).or_default();
+                        *count -= 1;
+                        assert!(*count >= 0, "NoteOff without NoteOn at {}", tick);
+                    }
+                    _ => (),
+                }
+                events.push((tick, message));
+            }
         }
+        assert!(held.values().all(|count| *count == 0), "Hanging notes: {:?}", held);
+        events
+    }
 
-        self.midifile = Some(midifile);
+    #[test]
+    fn test_export_balanced_for_all_pairings() {
+        for pairing in [
+            NotePairing::Fifo,
+            NotePairing::Lifo,
+            NotePairing::Retrigger,
+            NotePairing::IgnoreDuplicate,
+        ] {
+            let store = stacked_store(pairing);
+            for (s0, s1) in [(0, 2000), (200, 1200), (700, 1700), (1600, 1700)] {
+                let smf = build_smf(&store, s0, s1, 120., &ExportOptions::default()).unwrap();
+                let events = check_balanced(&smf);
+                let end = ((s1 - s0) as f64 * 0.96).round() as i64;
+                assert!(events.iter().all(|(tick, _)| *tick >= 0 && *tick <= end));
+            }
+        }
+    }
+
+    #[test]
+    fn test_export_retrigger_order() {
+        let store = stacked_store(NotePairing::Retrigger);
+        let smf = build_smf(&store, 0, 2000, 120., &ExportOptions::default()).unwrap();
+        let events: Vec<(i64, MidiMessage)> = check_balanced(&smf)
+            .into_iter()
+            .filter(|(_, message)| {
+                matches!(message, MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } if *key == 60)
+            })
+            .collect();
+
+        // The retriggering NoteOn follows the NoteOff of the previous note on the same tick
+        assert!(matches!(events[1], (480, MidiMessage::NoteOff { .. })));
+        assert!(matches!(events[2], (480, MidiMessage::NoteOn { .. })));
+        // Held notes end with the selection
+        assert!(matches!(events.last().unwrap(), (1920, MidiMessage::NoteOff { .. })));
+    }
+
+    #[test]
+    fn test_export_keeps_controllers() {
+        let store = stacked_store(NotePairing::Fifo);
+        let smf = build_smf(&store, 0, 2000, 120., &ExportOptions::default()).unwrap();
+        let events = check_balanced(&smf);
+        assert!(events.contains(&(96, MidiMessage::Controller {
+            controller: u7::new(0x40),
+            value: u7::new(0x7F),
+        })));
+        assert_eq!(
+            events
+                .iter()
+                .filter(|(_, message)| matches!(message, MidiMessage::NoteOn { .. }))
+                .count(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_export_sysex() {
+        let mut store = stacked_store(NotePairing::Retrigger);
+        store.add_sysex(1950, &[0xF0, 0x43, 0x10, 0x4C, 0xF7]).unwrap();
+        let smf = build_smf(&store, 0, 2000, 120., &ExportOptions::default()).unwrap();
+
+        let mut tick = 0;
+        let mut sysex = Vec::new();
+        for ev in smf.tracks[0].iter() {
+            tick += ev.delta.as_int();
+            if let TrackEventKind::SysEx(data) = ev.kind {
+                sysex.push((tick, data));
+            }
+        }
+        assert_eq!(sysex, vec![(1872, &[0x43, 0x10, 0x4C, 0xF7][..])]);
+
+        // The exported file can be read back
+        let mut encoded = Vec::new();
+        smf.write_std(&mut encoded).unwrap();
+        assert_eq!(Smf::parse(&encoded).unwrap().tracks[0].len(), smf.tracks[0].len());
+    }
+
+    #[test]
+    fn test_export_chases_channel_state() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add(0, [0xC0, 5, 0]).unwrap();
+        store.add(10, [0xB0, 0, 1]).unwrap();
+        store.add(20, [0xB0, 32, 2]).unwrap();
+        store.add(30, [0xC0, 7, 0]).unwrap();
+        store.add(40, [0xB0, 1, 30]).unwrap();
+        store.add(50, [0xB0, 1, 40]).unwrap();
+        store.add(60, [0xB0, 101, 0]).unwrap();
+        store.add(70, [0xB1, 64, 127]).unwrap();
+        store.add(80, [0xE0, 0, 0x50]).unwrap();
+        store.add(90, [0xD0, 60, 0]).unwrap();
+        store.add(1000, note_on(0, 60, 100)).unwrap();
+        // An event right at the selection start is exported as played
+        store.add(1000, [0xB0, 1, 50]).unwrap();
+        store.add(1500, note_off(0, 60, 0)).unwrap();
+
+        let chase = ChaseOptions {
+            programs: true,
+            pitch_bend: true,
+            pedals: true,
+            controllers: true,
+            pressure: true,
+        };
+        let messages = |chase: ChaseOptions, bake_pedal: bool| -> Vec<(i64, u8, MidiMessage)> {
+            let options = ExportOptions {
+                chase,
+                bake_pedal,
+                ..Default::default()
+            };
+            let smf = build_smf(&store, 1000, 2000, 120., &options).unwrap();
+            let mut tick = 0;
+            let mut messages = Vec::new();
+            for ev in smf.tracks[0].iter() {
+                tick += ev.delta.as_int() as i64;
+                if let TrackEventKind::Midi { channel, message } = ev.kind {
+                    messages.push((tick, channel.as_int(), message));
+                }
+            }
+            messages
+        };
+        let cc = |controller: u8, value: u8| MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value),
+        };
+        assert_eq!(
+            messages(chase, false)[..8],
+            [
+                (0, 0, cc(0, 1)),
+                (0, 0, cc(32, 2)),
+                (0, 0, MidiMessage::ProgramChange { program: u7::new(7) }),
+                (0, 0, cc(1, 40)),
+                (0, 0, MidiMessage::PitchBend { bend: PitchBend(u14::new(0x50 << 7)) }),
+                (0, 0, MidiMessage::ChannelAftertouch { vel: u7::new(60) }),
+                (0, 1, cc(64, 127)),
+                (0, 0, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(100) }),
+            ]
+        );
+        // Only the chosen classes, a baked pedal is not chased
+        let pedals = ChaseOptions {
+            pedals: true,
+            ..Default::default()
+        };
+        assert_eq!(messages(pedals, false)[0], (0, 1, cc(64, 127)));
+        assert!(messages(pedals, true).iter().all(|(.., message)| *message != cc(64, 127)));
+        assert_eq!(messages(ChaseOptions::default(), false).len(), 3);
+    }
+
+    #[test]
+    fn test_export_split_channels() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add_tempo(0, 120., (4, 4));
+        store.add(0, note_on(3, 48, 100)).unwrap();
+        store.add(100, note_on(0, 72, 100)).unwrap();
+        store.add(200, [0xB3, 1, 64]).unwrap();
+        store.add_sysex(300, &[0xF0, 0x43, 0x10, 0x4C, 0xF7]).unwrap();
+        store.add(500, note_off(0, 72, 0)).unwrap();
+        store.add(1000, note_off(3, 48, 0)).unwrap();
+
+        let options = ExportOptions {
+            tempo_map: true,
+            split_channels: true,
+            ..Default::default()
+        };
+        // Returns the kinds of events of each track, with the channel of MIDI events
+        let tracks = |options: &ExportOptions| -> Vec<Vec<String>> {
+            let smf = build_smf(&store, 0, 1000, 120., options).unwrap();
+            assert_eq!(smf.header.format, midly::Format::Parallel);
+            smf.tracks
+                .iter()
+                .map(|track| {
+                    track
+                        .iter()
+                        .map(|ev| match ev.kind {
+                            TrackEventKind::Midi { channel, message } => {
+                                format!("{:?} {}", message, channel.as_int())
+                            }
+                            TrackEventKind::Meta(MetaMessage::TrackName(name)) => {
+                                String::from_utf8_lossy(name).to_string()
+                            }
+                            TrackEventKind::Meta(message) => format!("{:?}", message),
+                            TrackEventKind::SysEx(_) => "SysEx".to_string(),
+                            _ => "Other".to_string(),
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        let split = tracks(&options);
+        assert_eq!(split.len(), 3);
+        assert_eq!(split[0][2..], ["SysEx", "EndOfTrack"]);
+        assert_eq!(split[1][0], "Channel 1");
+        assert_eq!(split[1].len(), 4);
+        assert_eq!(split[2][0], "Channel 4");
+        assert!(split[2][1..4].iter().all(|ev| ev.ends_with(" 3")));
+
+        // Every track on channel 1
+        let remapped = tracks(&ExportOptions {
+            channel_one: true,
+            ..options
+        });
+        assert!(remapped[2][1..4].iter().all(|ev| ev.ends_with(" 0")));
+        assert_eq!(remapped[2][0], "Channel 4");
+
+        // The exported file can be read back
+        let smf = build_smf(&store, 0, 1000, 120., &options).unwrap();
+        let mut encoded = Vec::new();
+        smf.write_std(&mut encoded).unwrap();
+        assert_eq!(Smf::parse(&encoded).unwrap().tracks.len(), 3);
+    }
+
+    #[test]
+    fn test_export_expressions_as_mpe() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add(0, [0xB0, 0x40, 0x7F]).unwrap();
+        store.add_voice(100, note_on(0, 60, 100), Some(1)).unwrap();
+        store.add_voice(100, note_on(0, 64, 100), Some(2)).unwrap();
+        store.add_expression(300, Some(1), u4::new(0), u7::new(60), ExpressionKind::Tuning, 12.);
+        store.add_expression(400, Some(2), u4::new(0), u7::new(64), ExpressionKind::Brightness, 1.);
+        store.add_voice(1000, note_off(0, 60, 0), Some(1)).unwrap();
+        store.add_voice(1000, note_off(0, 64, 0), Some(2)).unwrap();
+
+        let options = ExportOptions {
+            expressions: true,
+            ..Default::default()
+        };
+        let smf = build_smf(&store, 0, 2000, 120., &options).unwrap();
+        let mut tick = 0;
+        let mut events = Vec::new();
+        for ev in smf.tracks[0].iter() {
+            tick += ev.delta.as_int();
+            if let TrackEventKind::Midi { channel, message } = ev.kind {
+                events.push((tick, channel.as_int(), message));
+            }
+        }
+        let cc = |controller: u8, value: u8| MidiMessage::Controller {
+            controller: u7::new(controller),
+            value: u7::new(value),
+        };
+
+        // The MCM comes first, channel wide events end up on the manager channel
+        assert_eq!(events[2], (0, 0, cc(6, 15)));
+        assert!(events.contains(&(0, 0, cc(64, 127))));
+        // Both notes get a member channel of their own with a reset pitch bend
+        let on = |key: u8| {
+            events
+                .iter()
+                .position(|(_, _, message)| matches!(message, MidiMessage::NoteOn { key: k, .. } if *k == key))
+                .unwrap()
+        };
+        let (on_60, on_64) = (on(60), on(64));
+        assert_ne!(events[on_60].1, events[on_64].1);
+        assert!(events[on_60].1 > 0 && events[on_64].1 > 0);
+        assert!(matches!(events[on_60 - 1].2, MidiMessage::PitchBend { bend } if bend.as_int() == 0));
+        assert!(events.contains(&(
+            288,
+            events[on_60].1,
+            MidiMessage::PitchBend { bend: PitchBend::from_f32(0.25) }
+        )));
+        assert!(events.contains(&(384, events[on_64].1, cc(74, 127))));
+
+        // Without expressions, or when disabled, the channels are kept
+        let smf = build_smf(&store, 0, 2000, 120., &ExportOptions::default()).unwrap();
+        assert!(check_balanced(&smf).iter().all(|(_, message)| !matches!(message, MidiMessage::PitchBend { .. })));
+    }
+
+    #[test]
+    fn test_export_mpe_capture() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.set_mpe_mode(MpeMode::Lower);
+        store.add(0, note_on(1, 60, 100)).unwrap();
+        store.add(100, [0xE1, 0x00, 0x60]).unwrap();
+        store.add(500, [0xE1, 0x00, 0x50]).unwrap();
+        store.add(1000, note_off(1, 60, 0)).unwrap();
+
+        let options = ExportOptions {
+            expressions: true,
+            ..Default::default()
+        };
+        let messages = |s0, s1| -> Vec<(u8, MidiMessage)> {
+            let smf = build_smf(&store, s0, s1, 120., &options).unwrap();
+            smf.tracks[0]
+                .iter()
+                .filter_map(|ev| match ev.kind {
+                    TrackEventKind::Midi { channel, message } => Some((channel.as_int(), message)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Notes and bends stay on the member channel as captured, after the MCM
+        let events = messages(0, 2000);
+        assert_eq!(events.len(), 5 + 4);
+        assert_eq!(events[2], (0, MidiMessage::Controller { controller: u7::new(6), value: u7::new(15) }));
+        assert!(events[5..].iter().all(|(channel, _)| *channel == 1));
+        let bend = |value: f32| MidiMessage::PitchBend { bend: PitchBend::from_f32(value) };
+        assert_eq!(events[6..8], [(1, bend(0.5)), (1, bend(0.25))]);
+
+        // A note that started before the selection starts with the bend in effect
+        let events = messages(200, 2000);
+        assert_eq!(events[5], (1, bend(0.5)));
+        assert!(matches!(events[6], (1, MidiMessage::NoteOn { .. })));
+    }
+
+    #[test]
+    fn test_bar_range() {
+        let mut store = MidiStore::new();
+        assert_eq!(bar_range(&store, 0, 1000), None);
+        for (bar_number, t) in [(5, 1000), (6, 2000), (7, 3000), (8, 4000)] {
+            store.bars.push_back(Bar { bar_number, t });
+        }
+
+        // Snapped to bars 5 up to the start of 8
+        assert_eq!(bar_range(&store, 1000, 4000), Some((5, 7)));
+        // Starting inside a bar covers it, the last bar counts once the clip reaches into it
+        assert_eq!(bar_range(&store, 1500, 4001), Some((5, 8)));
+        // Bars before the first captured one are unknown
+        assert_eq!(bar_range(&store, 500, 2500), Some((5, 6)));
+        assert_eq!(bar_range(&store, 4500, 6000), None);
+        assert_eq!(bar_range(&store, 2100, 2900), None);
+    }
+
+    #[test]
+    fn test_export_uses_tempo_estimate() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        // The host reported a tempo once, but did not play
+        store.add_tempo(0, 120., (4, 4));
+        for (on, off) in [(0, 300), (600, 900), (1200, 1500)] {
+            store.add(on, note_on(0, 60, 100)).unwrap();
+            store.add(off, note_off(0, 60, 0)).unwrap();
+        }
+        store.set_tempo_estimate(TempoEstimate {
+            t_start: 0,
+            t_end: 1200,
+            tempo: 100.,
+            time_sig: (3, 4),
+            downbeat: 0,
+        });
+
+        // At 100 BPM, a beat takes 600 samples, the tempo is written without asking for it
+        let smf = build_smf(&store, 0, 1800, 140., &ExportOptions::default()).unwrap();
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![0, 240, 480, 720, 960, 1200]);
+        assert_eq!(clip_tempo(&store, 0, 1800, 140.), 100.);
+        let meta: Vec<MetaMessage> = smf.tracks[0]
+            .iter()
+            .filter_map(|ev| match ev.kind {
+                TrackEventKind::Meta(message) => Some(message),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            meta,
+            vec![
+                MetaMessage::Tempo(u24::new(600_000)),
+                MetaMessage::TimeSignature(3, 2, 24, 8),
+                MetaMessage::EndOfTrack,
+            ]
+        );
+
+        // Once the transport played in the selection, its tempo is used
+        store.add_bar(TransportInfo {
+            time: 900,
+            playing: true,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (4, 4),
+            ..Default::default()
+        });
+        let smf = build_smf(&store, 0, 1800, 140., &ExportOptions::default()).unwrap();
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![0, 288, 576, 864, 1152, 1440]);
+    }
+
+    #[test]
+    fn test_export_follows_tempo_map() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add_tempo(0, 120., (4, 4));
+        store.add_tempo(1000, 60., (4, 4));
+        for (on, off) in [(0, 500), (1000, 1500), (2000, 2500)] {
+            store.add(on, note_on(0, 60, 100)).unwrap();
+            store.add(off, note_off(0, 60, 0)).unwrap();
+        }
+
+        // The tempo halves after one second, so do the ticks per sample
+        let smf = build_smf(&store, 0, 3000, 140., &ExportOptions::default()).unwrap();
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![0, 480, 960, 1200, 1440, 1680]);
+        assert!(smf.tracks[0].iter().all(|ev| !matches!(ev.kind, TrackEventKind::Meta(MetaMessage::Tempo(_)))));
+
+        let options = ExportOptions {
+            tempo_map: true,
+            ..Default::default()
+        };
+        let meta = |s0, s1| -> Vec<(i64, MetaMessage)> {
+            let smf = build_smf(&store, s0, s1, 140., &options).unwrap();
+            let mut tick = 0;
+            let mut meta = Vec::new();
+            for ev in smf.tracks[0].iter() {
+                tick += ev.delta.as_int() as i64;
+                if let TrackEventKind::Meta(message) = ev.kind {
+                    meta.push((tick, message));
+                }
+            }
+            meta
+        };
+        assert_eq!(
+            meta(0, 3000),
+            vec![
+                (0, MetaMessage::Tempo(u24::new(500_000))),
+                (0, MetaMessage::TimeSignature(4, 2, 24, 8)),
+                (960, MetaMessage::Tempo(u24::new(1_000_000))),
+                (1920, MetaMessage::EndOfTrack),
+            ]
+        );
+        // A selection starting after the change starts with the tempo in effect
+        assert_eq!(meta(1500, 3000)[0], (0, MetaMessage::Tempo(u24::new(1_000_000))));
+
+        // Without a recorded tempo, the given one is used
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(1000, note_off(0, 60, 0)).unwrap();
+        let smf = build_smf(&store, 0, 2000, 60., &ExportOptions::default()).unwrap();
+        assert_eq!(check_balanced(&smf)[1].0, 480);
+    }
+
+    fn playing(time: Samples, pos_beats: f64) -> TransportInfo {
+        TransportInfo {
+            time,
+            playing: true,
+            sample_rate: 1000.,
+            tempo: 120.,
+            time_sig: (4, 4),
+            pos_beats,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_export_follows_host_positions() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add_bar(playing(0, 0.));
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(500, note_off(0, 60, 0)).unwrap();
+        // The host got a little less far than its tempo of 120 BPM says, e.g. during a ramp
+        store.add_bar(playing(1000, 1.9));
+        store.add(1000, note_on(0, 62, 100)).unwrap();
+        store.add(1500, note_off(0, 62, 0)).unwrap();
+        // After stopping and starting again, the positions do not connect
+        store.add_bar(TransportInfo { playing: false, ..playing(1600, 2.5) });
+        store.add(1700, note_on(0, 64, 100)).unwrap();
+        store.add_bar(playing(1800, 0.));
+        store.add(1800, note_off(0, 64, 0)).unwrap();
+        store.add(2000, note_on(0, 65, 100)).unwrap();
+        store.add(2500, note_off(0, 65, 0)).unwrap();
+
+        let smf = build_smf(&store, 0, 3000, 120., &ExportOptions::default()).unwrap();
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        // The first run goes by the host positions, the second one continues at the tempo
+        assert_eq!(ticks, vec![0, 480, 912, 1392, 1584, 1680, 1872, 2352]);
+
+        // Selections starting in the middle of a run keep the spacing
+        let smf = build_smf(&store, 500, 3000, 120., &ExportOptions::default()).unwrap();
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks[..2], [432, 912]);
+    }
+
+    #[test]
+    fn test_mpe_channels() {
+        // Notes go to unused channels first, so release tails are not cut off
+        let channels = mpe_channels(&[(0, 10), (5, 20), (10, 30)]);
+        assert_ne!(channels[0], channels[2]);
+        assert_ne!(channels[1], channels[2]);
+
+        // Then to the channel that has been free the longest
+        let mut spans: Vec<(i64, i64)> = (0..15).map(|idx| (idx, 10 + idx)).collect();
+        spans.push((40, 50));
+        let channels = mpe_channels(&spans);
+        assert_eq!(channels[15], channels[0]);
+
+        // Busy channels are shared if there are more notes than channels
+        let spans: Vec<(i64, i64)> = (0..20).map(|idx| (idx, 100)).collect();
+        let channels = mpe_channels(&spans);
+        assert!(channels.iter().all(|channel| (1..=15).contains(&channel.as_int())));
+    }
+
+    #[test]
+    fn test_export_resolution_and_padding() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add_tempo(0, 120., (4, 4));
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(500, note_off(0, 60, 0)).unwrap();
+
+        let options = ExportOptions {
+            ppqn: 960,
+            padding: ClipPadding::NextBar,
+            ..Default::default()
+        };
+        let smf = build_smf(&store, 0, 3000, 120., &options).unwrap();
+        assert_eq!(smf.header.timing, midly::Timing::Metrical(u15::new(960)));
+        let ticks: Vec<i64> = check_balanced(&smf).iter().map(|(tick, _)| *tick).collect();
+        assert_eq!(ticks, vec![0, 960]);
+        // One and a half bars are padded to two
+        let length: i64 = smf.tracks[0].iter().map(|ev| ev.delta.as_int() as i64).sum();
+        assert_eq!(length, 2 * 4 * 960);
+    }
+
+    #[test]
+    fn test_export_clip_padding() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        // Bars of 3/4 at 120 BPM are 1500 samples or 1440 ticks long
+        let bar = |time: Samples, beats: f64| TransportInfo {
+            time_sig: (3, 4),
+            bar_start_pos_beats: beats,
+            ..playing(time, beats)
+        };
+        store.add_bar(bar(0, 0.));
+        store.add(0, note_on(0, 60, 100)).unwrap();
+        store.add(500, note_off(0, 60, 0)).unwrap();
+        store.add_bar(bar(1500, 3.));
+        // Still held when exporting
+        store.add(2000, note_on(0, 62, 100)).unwrap();
+        store.add_bar(bar(3000, 6.));
+
+        // Ends of the track and of the held note
+        let ends = |s0, s1, padding| -> (i64, i64) {
+            let options = ExportOptions { padding, ..Default::default() };
+            let smf = build_smf(&store, s0, s1, 120., &options).unwrap();
+            let mut tick = 0;
+            let mut note_off = None;
+            for ev in smf.tracks[0].iter() {
+                tick += ev.delta.as_int() as i64;
+                match ev.kind {
+                    TrackEventKind::Midi { message: MidiMessage::NoteOff { key, .. }, .. }
+                        if key == 62 =>
+                    {
+                        note_off = Some(tick)
+                    }
+                    TrackEventKind::Meta(MetaMessage::EndOfTrack) => break,
+                    _ => assert!(note_off.is_none(), "Event after the held NoteOff"),
+                }
+            }
+            assert!(matches!(
+                smf.tracks[0].last().unwrap().kind,
+                TrackEventKind::Meta(MetaMessage::EndOfTrack)
+            ));
+            (tick, note_off.unwrap())
+        };
+
+        // Held notes end with the selection, before the end of the track
+        assert_eq!(ends(0, 3200, ClipPadding::SelectionEnd), (3072, 3072));
+        assert_eq!(ends(0, 3200, ClipPadding::NextBar), (4320, 3072));
+        // Three bars and a bit are padded to four
+        assert_eq!(ends(0, 3200, ClipPadding::PowerOfTwoBars), (5760, 3072));
+        // Bars are counted from the bar the clip starts in
+        assert_eq!(ends(1000, 3200, ClipPadding::PowerOfTwoBars), (5760 - 960, 2112));
+        assert_eq!(ends(1500, 2500, ClipPadding::PowerOfTwoBars), (1440, 960));
+        // Ending just past a bar line ends at the bar line, so do the held notes
+        assert_eq!(ends(0, 3005, ClipPadding::NextBar), (2880, 2880));
+        assert_eq!(ends(0, 3005, ClipPadding::PowerOfTwoBars), (2880, 2880));
+        assert_eq!(ends(0, 3005, ClipPadding::SelectionEnd), (2885, 2885));
+    }
+
+    #[test]
+    fn test_export_empty_selection() {
+        let store = stacked_store(NotePairing::Retrigger);
+        assert!(build_smf(&store, 3000, 4000, 120., &ExportOptions::default()).is_some());
+        let mut store = MidiStore::new();
+        store.add(0, [0xB0, 0x40, 0x7F]).unwrap();
+        assert!(build_smf(&store, 0, 1000, 120., &ExportOptions::default()).is_none());
+    }
+
+    #[test]
+    fn test_export_bake_pedal() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add(0, [0xB0, 0x40, 0x7F]).unwrap();
+        store.add(100, note_on(0, 60, 100)).unwrap();
+        store.add(200, note_off(0, 60, 0)).unwrap();
+        store.add(1000, [0xB0, 0x40, 0x00]).unwrap();
+        store.add(1500, [0xB0, 0x40, 0x7F]).unwrap();
+        store.add(1600, note_on(0, 62, 100)).unwrap();
+        store.add(1700, note_off(0, 62, 0)).unwrap();
+
+        let note_offs = |options: &ExportOptions, s0, s1| -> Vec<(i64, MidiMessage)> {
+            let smf = build_smf(&store, s0, s1, 120., options).unwrap();
+            check_balanced(&smf)
+                .into_iter()
+                .filter(|(_, message)| {
+                    matches!(message, MidiMessage::NoteOff { .. } | MidiMessage::Controller { .. })
+                })
+                .collect()
+        };
+
+        let plain = note_offs(&ExportOptions::default(), 0, 2000);
+        assert_eq!(plain.len(), 5);
+        assert!(plain.contains(&(192, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(0) })));
+
+        let baked = ExportOptions {
+            bake_pedal: true,
+            ..Default::default()
+        };
+        let offs = note_offs(&baked, 0, 2000);
+        // Pedal events are left out, the first note sounds until the pedal goes up and the
+        // second one until the end of the selection
+        assert_eq!(
+            offs,
+            vec![
+                (960, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(0) }),
+                (1920, MidiMessage::NoteOff { key: u7::new(62), vel: u7::new(0) }),
+            ]
+        );
+
+        // Notes released before the selection but still sounding are included
+        let offs = note_offs(&baked, 500, 1200);
+        assert_eq!(offs, vec![(480, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(0) })]);
+        assert!(build_smf(&store, 500, 1200, 120., &ExportOptions::default()).is_none());
+    }
+
+    #[test]
+    fn test_recent_exports() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        for (on, off, key) in [(0, 500, 60), (1000, 1500, 62), (2000, 2500, 64)] {
+            store.add(on, note_on(0, key, 100)).unwrap();
+            store.add(off, note_off(0, key, 0)).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let library = ExportLibrary::new(dir.path().to_path_buf(), "clip", Default::default());
+        let mut transfers = MidiTransfers::new(
+            Arc::new(RwLock::new(store)),
+            ExportOptions::default(),
+            Some(library),
+        );
+        let transport = TransportInfo::default();
+
+        let path = transfers.new_selection(0.0, 2.0, &transport).unwrap().to_path_buf();
+        assert_eq!(path, dir.path().join("clip.mid"));
+        assert!(transfers.new_selection(2.8, 2.9, &transport).is_none());
+        transfers.new_selection(1.25, 3.0, &transport).unwrap();
+
+        // The latest export comes first, notes are clipped to the selection
+        let recent = transfers.recent();
+        assert_eq!(recent.len(), 2);
+        assert_eq!((recent[0].t0, recent[0].t1), (1.25, 3.0));
+        assert_eq!(recent[0].notes, vec![(0.0, 0.25 / 1.75, 62), (0.75 / 1.75, 1.25 / 1.75, 64)]);
+        assert_eq!(recent[0].tempo, FALLBACK_TEMPO);
+        assert_eq!(recent[1].path, path);
+        assert_eq!(recent[1].notes, vec![(0.0, 0.25, 60), (0.5, 0.75, 62)]);
+
+        // Picked exports are handed to the note view once
+        transfers.highlight(1);
+        assert_eq!(transfers.take_highlight().map(|recent| recent.t0), Some(0.0));
+        assert!(transfers.take_highlight().is_none());
+
+        // Exports whose files are gone drop out of the list
+        std::fs::remove_file(&path).unwrap();
+        assert!(transfers.prune_recent());
+        assert!(!transfers.prune_recent());
+        assert_eq!(transfers.recent().len(), 1);
+        transfers.new_selection(0.0, 1.0, &transport).unwrap();
+        let starts: Vec<f32> = transfers.recent().iter().map(|recent| recent.t0).collect();
+        assert_eq!(starts, vec![0.0, 1.25]);
+        std::fs::remove_file(&transfers.recent()[1].path).unwrap();
+        transfers.new_selection(2.0, 3.0, &transport).unwrap();
+        let starts: Vec<f32> = transfers.recent().iter().map(|recent| recent.t0).collect();
+        assert_eq!(starts, vec![2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_export_quantize() {
+        let mut store = MidiStore::new();
+        store.set_sample_rate(0, 1000.);
+        store.add_tempo(0, 120., (4, 4));
+        // A 4/4 bar followed by a 7/8 bar, 480 ticks are 500 samples
+        for (bar_number, t) in [(1, 0), (2, 2000), (3, 3750)] {
+            store.bars.push_back(Bar { bar_number, t });
+        }
+        for (on, off, key) in [(60, 400, 60), (560, 1040, 62), (1790, 2100, 64), (3700, 3900, 65)] {
+            store.add(on, note_on(0, key, 100)).unwrap();
+            store.add(off, note_off(0, key, 0)).unwrap();
+        }
+        let notes = |quantize: QuantizeOptions| {
+            let options = ExportOptions { quantize, ..Default::default() };
+            let smf = build_smf(&store, 0, 4500, 120., &options).unwrap();
+            let mut notes: Vec<(u8, i64, i64)> = Vec::new();
+            for (tick, message) in check_balanced(&smf) {
+                match message {
+                    MidiMessage::NoteOn { key, .. } => notes.push((key.as_int(), tick, tick)),
+                    MidiMessage::NoteOff { key, .. } => {
+                        notes.iter_mut().find(|note| note.0 == key.as_int()).unwrap().2 = tick
+                    }
+                    _ => {}
+                }
+            }
+            notes.sort();
+            notes
+        };
+
+        // Played: (58, 384), (538, 998), (1718, 2016), (3552, 3744), the bars are at 0, 1920
+        // and 3600, so the last note is closer to bar 3 than to the last quarter of bar 2
+        let quarter = QuantizeOptions {
+            grid: QuantizeGrid::Quarter,
+            strength: 1.,
+            ..Default::default()
+        };
+        assert_eq!(
+            notes(quarter),
+            vec![(60, 0, 326), (62, 480, 940), (64, 1920, 2218), (65, 3600, 3792)]
+        );
+        assert_eq!(
+            notes(QuantizeOptions { note_offs: NoteOffQuantize::Quantize, ..quarter }),
+            vec![(60, 0, 480), (62, 480, 960), (64, 1920, 1920 + 480), (65, 3600, 3600 + 480)]
+        );
+        assert_eq!(
+            notes(QuantizeOptions { note_offs: NoteOffQuantize::Leave, ..quarter }),
+            vec![(60, 0, 384), (62, 480, 998), (64, 1920, 2016), (65, 3600, 3744)]
+        );
+
+        // Half way towards the grid
+        assert_eq!(
+            notes(QuantizeOptions { strength: 0.5, ..quarter }),
+            vec![(60, 29, 355), (62, 509, 969), (64, 1819, 2117), (65, 3576, 3768)]
+        );
+
+        // Full swing delays the second eighth of every beat to three quarters of the beat, the
+        // third note is closer to the delayed eighth than to bar 2 then
+        let swung = QuantizeOptions {
+            grid: QuantizeGrid::Eighth,
+            swing: 1.,
+            ..quarter
+        };
+        let starts: Vec<i64> = notes(swung).iter().map(|note| note.1).collect();
+        assert_eq!(starts, vec![0, 480, 1800, 3600]);
+        let triplets = QuantizeOptions { grid: QuantizeGrid::EighthTriplet, ..quarter };
+        let starts: Vec<i64> = notes(triplets).iter().map(|note| note.1).collect();
+        assert_eq!(starts, vec![0, 480, 1760, 3520]);
+
+        // Quantizing is off by default and the capture stays as played
+        let played = notes(QuantizeOptions::default());
+        assert_eq!(played, vec![(60, 58, 384), (62, 538, 998), (64, 1718, 2016), (65, 3552, 3744)]);
     }
 }
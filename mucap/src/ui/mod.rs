@@ -1,29 +1,153 @@
-use nih_plug::nih_dbg;
+use nih_plug::{nih_dbg, nih_warn};
 use nih_plug::prelude::{AtomicF32, Editor};
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::widgets::*;
 use nih_plug_vizia::{ViziaState, ViziaTheming, assets, create_vizia_editor};
+use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 pub mod noteview;
 pub mod zoom_control;
 pub mod miditransfer;
+pub mod recent_exports;
 pub mod style;
 use noteview::NoteView;
 
 use crate::config::ConfigStore;
+use crate::journal::{self, OrphanedJournal};
 use crate::midistore::MidiStore;
+use crate::ui::miditransfer::MidiTransfers;
 use crate::ui::noteview::NoteViewEvent;
+use crate::ui::recent_exports::RecentExportsEvent;
 
 #[derive(Lens)]
 struct Data {
     store: Arc<RwLock<MidiStore>>,
     config: Arc<RwLock<ConfigStore>>,
     time: Arc<AtomicF32>,
+    /// Journals left behind by crashed sessions, the first one is offered for recovery.
+    orphans: Vec<OrphanedJournal>,
+    recovery: String,
+    show_recovery: bool,
+    /// A journal is being restored or exported in the background.
+    recovering: bool,
+    transfers: Arc<Mutex<MidiTransfers>>,
+    /// Changes whenever the list of recent exports did.
+    recent_generation: usize,
+    show_recent: bool,
 }
 
-impl Model for Data {}
+enum RecoveryEvent {
+    Restore,
+    Export,
+    Discard,
+    /// Restoring the journal in the background finished.
+    Restored(Result<(), String>),
+    /// Exporting the journal in the background finished, with the path of the file.
+    Exported(Result<PathBuf, String>),
+}
+
+impl Data {
+    /// Moves on to the next orphaned journal, hiding the recovery bar if there is none.
+    fn next_orphan(&mut self) {
+        if !self.orphans.is_empty() {
+            self.orphans.remove(0);
+        }
+        self.show_recovery = !self.orphans.is_empty();
+        self.recovery = self
+            .orphans
+            .first()
+            .map_or(String::new(), |orphan| orphan.describe());
+    }
+}
+
+impl Model for Data {
+    fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
+        event.map(|recent_event, _| match recent_event {
+            RecentExportsEvent::Exported => self.recent_generation += 1,
+            RecentExportsEvent::Missing => {
+                if self.transfers.lock().unwrap().prune_recent() {
+                    self.recent_generation += 1;
+                }
+            }
+            RecentExportsEvent::Toggle => {
+                self.show_recent = !self.show_recent;
+                if self.show_recent && self.transfers.lock().unwrap().prune_recent() {
+                    self.recent_generation += 1;
+                }
+            }
+            RecentExportsEvent::Highlight(idx) => self.transfers.lock().unwrap().highlight(*idx),
+            RecentExportsEvent::Copy(idx) => {
+                let mut transfers = self.transfers.lock().unwrap();
+                transfers.copy_recent(*idx);
+                if transfers.prune_recent() {
+                    self.recent_generation += 1;
+                }
+            }
+        });
+        event.map(|recovery_event, _| {
+            let Some(orphan) = self.orphans.first().cloned() else {
+                return;
+            };
+            let requested = matches!(
+                recovery_event,
+                RecoveryEvent::Restore | RecoveryEvent::Export | RecoveryEvent::Discard
+            );
+            // One journal at a time, it must not be discarded while it is read
+            if requested && self.recovering {
+                return;
+            }
+            // Long journals take a while, the editor keeps running meanwhile
+            match recovery_event {
+                RecoveryEvent::Restore => {
+                    self.recovering = true;
+                    self.recovery = "Restoring...".to_string();
+                    let store = self.store.clone();
+                    cx.spawn(move |cx| {
+                        let result = journal::restore(&orphan, &store).map_err(|e| e.to_string());
+                        let _ = cx.emit(RecoveryEvent::Restored(result));
+                    });
+                }
+                RecoveryEvent::Export => {
+                    self.recovering = true;
+                    self.recovery = "Exporting...".to_string();
+                    let options = self.config.read().unwrap().get_config().export_options();
+                    cx.spawn(move |cx| {
+                        let result = journal::export(&orphan, &options).map_err(|e| e.to_string());
+                        let _ = cx.emit(RecoveryEvent::Exported(result));
+                    });
+                }
+                RecoveryEvent::Restored(result) => {
+                    self.recovering = false;
+                    match result {
+                        Ok(()) => self.next_orphan(),
+                        Err(e) => {
+                            nih_warn!("Failed to restore journal {:?}: {}", &orphan.path, e);
+                            self.recovery = format!("Failed to restore: {}", e);
+                        }
+                    }
+                }
+                RecoveryEvent::Exported(result) => {
+                    self.recovering = false;
+                    match result {
+                        Ok(path) => self.recovery = format!("Exported to {}", path.display()),
+                        Err(e) => {
+                            nih_warn!("Failed to export journal {:?}: {}", &orphan.path, e);
+                            self.recovery = format!("Failed to export: {}", e);
+                        }
+                    }
+                }
+                RecoveryEvent::Discard => {
+                    if let Err(e) = journal::discard(&orphan) {
+                        nih_warn!("Failed to remove journal {:?}: {}", &orphan.path, e);
+                    }
+                    self.next_orphan();
+                }
+            }
+        });
+    }
+}
 
 pub(crate) fn default_state(scale_factor: f32) -> Arc<ViziaState> {
     ViziaState::new_with_default_scale_factor(|| (700, 200), scale_factor as f64)
@@ -36,46 +160,76 @@ pub(crate) fn create(
     time: Arc<AtomicF32>,
     debug_stop: Arc<AtomicBool>,
 ) -> Option<Box<dyn Editor>> {
+    // Outlives the editor window, so the recent exports are still listed after reopening it
+    let cfg = config.read().unwrap().get_config();
+    let transfers = Arc::new(Mutex::new(MidiTransfers::new(
+        store.clone(),
+        cfg.export_options(),
+        cfg.export_library(),
+    )));
+
     create_vizia_editor(editor_state, ViziaTheming::Custom, move |cx, _| {
         assets::register_noto_sans_light(cx);
         assets::register_noto_sans_thin(cx);
 
+        let orphans = journal::journal_dir()
+            .map(|dir| journal::orphaned(&dir))
+            .unwrap_or_default();
         Data {
             store: store.clone(),
             time: time.clone(),
             config: config.clone(),
+            recovery: orphans
+                .first()
+                .map_or(String::new(), |orphan| orphan.describe()),
+            show_recovery: !orphans.is_empty(),
+            recovering: false,
+            orphans,
+            transfers: transfers.clone(),
+            recent_generation: 0,
+            show_recent: false,
         }
         .build(cx);
 
         VStack::new(cx, |cx| {
-            /*Label::new(cx, "Drag")
-                .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
-                .font_weight(FontWeightKeyword::Thin)
-                .font_size(30.0)
-                .height(Pixels(50.0))
-                .child_top(Stretch(1.0))
-                .child_bottom(Pixels(0.0))
-                .on_drag(|cx| {
-                    cx.set_drop_data(DropData::File("/home/jan/test.mid".into()));
-                    nih_dbg!(
-                        "Drag started: {}, {}",
-                        cx.is_draggable(),
-                        cx.has_drop_data()
-                    );
-                });
-            Label::new(cx, "Drop")
-                .font_family(vec![FamilyOwned::Name(String::from(assets::NOTO_SANS))])
-                .font_weight(FontWeightKeyword::Thin)
-                .font_size(30.0)
-                .height(Pixels(50.0))
-                .child_top(Stretch(1.0))
-                .child_bottom(Pixels(0.0))
-                .on_drop(|cx, data| {
-                    nih_dbg!("Drop started: {:?}", data);
-                });*/
-            NoteView::new(cx, store.clone(), config.clone(), time.clone(), debug_stop.clone())
+            // Offers to recover captures of crashed sessions
+            HStack::new(cx, |cx| {
+                Label::new(cx, Data::recovery).width(Stretch(1.0));
+                Button::new(
+                    cx,
+                    |cx| cx.emit(RecoveryEvent::Restore),
+                    |cx| Label::new(cx, "Restore"),
+                );
+                Button::new(
+                    cx,
+                    |cx| cx.emit(RecoveryEvent::Export),
+                    |cx| Label::new(cx, "Export .mid"),
+                );
+                Button::new(
+                    cx,
+                    |cx| cx.emit(RecoveryEvent::Discard),
+                    |cx| Label::new(cx, "Discard"),
+                );
+            })
+            .height(Auto)
+            .col_between(Pixels(4.0))
+            .child_space(Pixels(4.0))
+            .display(Data::show_recovery);
+            HStack::new(cx, |cx| {
+                NoteView::new(
+                    cx,
+                    store.clone(),
+                    config.clone(),
+                    transfers.clone(),
+                    time.clone(),
+                    debug_stop.clone(),
+                )
                 .width(Stretch(1.0))
                 .height(Stretch(1.0));
+                recent_exports::build(cx, Data::recent_generation, Data::show_recent, transfers.clone());
+            })
+            .width(Stretch(1.0))
+            .height(Stretch(1.0));
         })
         .width(Stretch(1.0))
         .height(Stretch(1.0));
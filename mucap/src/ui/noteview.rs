@@ -1,21 +1,33 @@
+use std::collections::HashMap;
 use std::num::NonZero;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 
+use crate::Samples;
 use crate::config::ConfigStore;
+use crate::midistore::ExpressionKind;
 use crate::midistore::MidiStore;
 use crate::midistore::Note;
 use crate::ui::zoom_control::ZoomControl;
-use nih_plug::nih_dbg;
+use midly::num::u7;
+use nih_plug::{nih_dbg, nih_warn};
 use nih_plug::prelude::AtomicF32;
 use nih_plug_vizia::vizia::prelude::*;
 use nih_plug_vizia::vizia::vg;
 
 use super::miditransfer::MidiTransfers;
+use super::recent_exports::RecentExportsEvent;
 use super::style::StyleColors;
 
+/// Size of the handle on top of a completed selection that drags the exported file.
+const HANDLE_SIZE: (f32, f32) = (48.0, 12.0);
+/// Distance the mouse has to move with the handle grabbed before the drag starts.
+const DRAG_THRESHOLD: f32 = 4.0;
+
 pub enum SelectionState {
     None,
     Selecting(f32),
@@ -92,6 +104,15 @@ pub enum VScrollMode {
     Pan,
 }
 
+/// Which length of the notes is drawn.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NoteLengthMode {
+    /// From key-down to key-up.
+    KeyUp,
+    /// Until the end of the sound, including the sustain pedal.
+    Sounding,
+}
+
 pub struct NoteView {
     store: Arc<RwLock<MidiStore>>,
     config: Arc<RwLock<ConfigStore>>,
@@ -101,9 +122,20 @@ pub struct NoteView {
     mouse_pos: Option<(f32, f32)>,
     selection: SelectionState,
     t_last_op: f32,
-    transfers: MidiTransfers,
+    transfers: Arc<Mutex<MidiTransfers>>,
     snap: SnapMode,
     vscroll: VScrollMode,
+    note_length: NoteLengthMode,
+    /// Shift of the ghost grid set by dragging, in samples.
+    ghost_shift: Samples,
+    /// Anchor of the ghost grid the shift applies to, see [`MidiStore::ghost_grid`].
+    ghost_anchor: Samples,
+    /// Sample under the cursor and shift when dragging the ghost grid started.
+    ghost_drag: Option<(Samples, Samples)>,
+    /// File exported from the completed selection, carried as drop data when dragging it out.
+    drag_file: Option<PathBuf>,
+    /// Position where the selection handle was grabbed, until the drag starts.
+    drag_grab: Option<(f32, f32)>,
     colors: StyleColors,
     resize_event: Option<(f32, f32)>,
     debug_stop: Arc<AtomicBool>,
@@ -114,9 +146,15 @@ impl NoteView {
         cx: &mut Context,
         store: Arc<RwLock<MidiStore>>,
         config: Arc<RwLock<ConfigStore>>,
+        transfers: Arc<Mutex<MidiTransfers>>,
         time: Arc<AtomicF32>,
         debug_stop: Arc<AtomicBool>,
     ) -> Handle<'_, Self> {
+        let cfg = config.read().unwrap().get_config();
+        transfers
+            .lock()
+            .unwrap()
+            .configure(cfg.export_options(), cfg.export_library());
         Self {
             store: store.clone(),
             config,
@@ -130,9 +168,19 @@ impl NoteView {
             mouse_pos: None,
             selection: SelectionState::None,
             t_last_op: 0.0,
-            transfers: MidiTransfers::new(store.clone()),
+            transfers,
             vscroll: VScrollMode::Zoom,
+            note_length: if cfg.show_sounding_length {
+                NoteLengthMode::Sounding
+            } else {
+                NoteLengthMode::KeyUp
+            },
             snap: SnapMode::Snapping,
+            ghost_shift: 0,
+            ghost_anchor: 0,
+            ghost_drag: None,
+            drag_file: None,
+            drag_grab: None,
             colors: StyleColors::default(),
             resize_event: None,
             debug_stop: debug_stop.clone(),
@@ -193,13 +241,64 @@ impl View for NoteView {
         canvas.fill_path(&path, &paint);
 
         let mut bar_path = vg::Path::new();
-        for bar in self.store.read().unwrap().bars.iter() {
-            let x = wnd.time_to_x(bar.t);
+        let store = self.store.read().unwrap();
+
+        // Shade where the sustain pedal is down
+        let mut pedal_path = vg::Path::new();
+        let open_pedals = store.open_pedals().map(|(_, t)| (store.time(t), t_now));
+        for (p0, p1) in store
+            .pedals_in_time(t0, t1)
+            .map(|pedal| (store.time(pedal.t_start), store.time(pedal.t_end)))
+            .chain(open_pedals)
+        {
+            let (x0, x1) = (wnd.time_to_x_coerced(p0), wnd.time_to_x_coerced(p1));
+            pedal_path.rect(x0, b.y, x1 - x0, b.h);
+        }
+        canvas.fill_path(&pedal_path, &vg::Paint::color(self.colors.pedal_region));
+
+        // Mark SysEx messages at the bottom of the view
+        let mut sysex_path = vg::Path::new();
+        for (time, _) in store.sysex_in_time(t0, t1) {
+            let x = wnd.time_to_x(store.time(time));
+            sysex_path.move_to(x - 4., b.y + b.h);
+            sysex_path.line_to(x + 4., b.y + b.h);
+            sysex_path.line_to(x, b.y + b.h - 6.);
+            sysex_path.close();
+        }
+        canvas.fill_path(&sysex_path, &vg::Paint::color(self.colors.sysex_marker));
+
+        // Mark where the transport jumped back to the loop start, with a notch at the top
+        let mut loop_path = vg::Path::new();
+        for pass in store.loop_passes_in_time(t0, t1) {
+            let x = wnd.time_to_x(store.time(pass.t_end));
+            loop_path.move_to(x, b.y);
+            loop_path.line_to(x, b.y + b.h);
+            loop_path.move_to(x - 5., b.y);
+            loop_path.line_to(x, b.y + 6.);
+            loop_path.line_to(x + 5., b.y);
+        }
+        canvas.stroke_path(
+            &loop_path,
+            &vg::Paint::color(self.colors.loop_boundary).with_line_width(1.5),
+        );
+
+        for bar in store.bars_in_time(t0, t1) {
+            let x = wnd.time_to_x(store.time(bar.t));
             if (x >= 0.0) && (x < b.w) {
                 bar_path.move_to(x, 0.);
                 bar_path.line_to(x, b.h);
             }
         }
+        let mut ghost_path = vg::Path::new();
+        let grid = store.ghost_grid().shifted(self.ghost_shift);
+        let (s0, s1) = (store.samples_at(t0 as f64), store.samples_at(t1 as f64));
+        for s in store.ghost_bars_in_samples(grid, s0, s1) {
+            let x = wnd.time_to_x(store.time(s));
+            ghost_path.move_to(x, 0.);
+            ghost_path.line_to(x, b.h);
+        }
+        // Release the store while selecting, snapping needs to lock it as well
+        drop(store);
         //let bar_paint = vg::Paint::color(vg::Color::rgb(128, 64, 12));
         if let Some((x, y)) = self.mouse_pos {
             let (w, h) = (b.w * 0.4, b.h * 0.8);
@@ -215,6 +314,17 @@ impl View for NoteView {
                 self.colors.bar_glow_dim,
             );
             canvas.stroke_path(&bar_path, &bar_paint);
+            let ghost_paint = vg::Paint::box_gradient(
+                x - w / 2.,
+                y - h / 2.,
+                w,
+                h,
+                h,
+                h,
+                self.colors.ghost_glow_bright,
+                self.colors.ghost_glow_dim,
+            );
+            canvas.stroke_path(&ghost_path, &ghost_paint);
         }
 
         let mut note_path = vg::Path::new();
@@ -251,16 +361,51 @@ impl View for NoteView {
             canvas.fill_path(&sel_path, &sel_fill);
         }
 
-        for note in self.store.read().unwrap().notes_in_time(t0, t1) {
-            if let Some(trnsf) = wnd.note_to_rect(note) {
-                note_path.rect(trnsf.x, trnsf.y + 4., trnsf.w, trnsf.h - 8.);
+        // Handle to drag the exported file onto a DAW track or into a file manager
+        if let Some(handle) = self.selection_handle(&wnd) {
+            let mut handle_path = vg::Path::new();
+            handle_path.rounded_rect(handle.x, handle.y, handle.w, handle.h, 3.0);
+            canvas.fill_path(&handle_path, &vg::Paint::color(self.colors.selection_handle));
+        }
+
+        let store = self.store.read().unwrap();
+        match self.note_length {
+            NoteLengthMode::KeyUp => {
+                for note in store.notes_in_time(t0, t1) {
+                    if let Some(trnsf) = wnd.note_to_rect(note, &store) {
+                        note_path.rect(trnsf.x, trnsf.y + 4., trnsf.w, trnsf.h - 8.);
+                    }
+                }
+            }
+            NoteLengthMode::Sounding => {
+                for note in store.notes_sounding_in_time(t0, t1) {
+                    if let Some(trnsf) = wnd.sounding_note_to_rect(note, &store, t_now) {
+                        note_path.rect(trnsf.x, trnsf.y + 4., trnsf.w, trnsf.h - 8.);
+                    }
+                }
             }
         }
-        for note in self.store.read().unwrap().in_flight.iter() {
-            if let Some(trnsf) = wnd.incomplete_note_to_rect(note, t_now) {
+        for note in store.in_flight.iter() {
+            if let Some(trnsf) = wnd.incomplete_note_to_rect(note, &store, t_now) {
                 note_path.rect(trnsf.x, trnsf.y + 4., trnsf.w, trnsf.h - 8.);
             }
         }
+
+        // Per-note expressions are drawn as curves over their notes, tuning as pitch glides
+        let mut expression_path = vg::Path::new();
+        let mut last_points: HashMap<(usize, ExpressionKind), (f32, f32)> = HashMap::new();
+        for expression in store.expressions_in_time(t0, t1) {
+            let key = expression.key.as_int() as f32;
+            let y = match expression.kind {
+                ExpressionKind::Tuning => key + expression.value,
+                kind => key - 0.5 + kind.normalized(expression.value),
+            };
+            let point = wnd.note_to_phys(store.time(expression.time), y);
+            if let Some((x, y)) = last_points.insert((expression.idx_on, expression.kind), point) {
+                expression_path.move_to(x, y);
+                expression_path.line_to(point.0, point.1);
+            }
+        }
         let note_paint = if let Some((sel_t0, sel_t1)) = sel {
             let (sel_x0, sel_x1) = (wnd.time_to_x_coerced(sel_t0), wnd.time_to_x_coerced(sel_t1));
             let c0 = (sel_x0 - b.x) / b.w;
@@ -283,9 +428,14 @@ impl View for NoteView {
         } else {
             vg::Paint::color(self.colors.note_unselected)
         };
+        drop(store);
         let rim_paint = vg::Paint::color(self.colors.note_rim).with_line_width(1.0);
         canvas.fill_path(&note_path, &note_paint);
         canvas.stroke_path(&note_path, &rim_paint);
+        canvas.stroke_path(
+            &expression_path,
+            &vg::Paint::color(self.colors.expression_curve).with_line_width(1.5),
+        );
 
         let mut pos_bar = vg::Path::new();
         /*pos_bar.move_to(self.time.load(Ordering::Relaxed), 0.);
@@ -316,6 +466,13 @@ impl View for NoteView {
         }
 
         canvas.fill_path(&pos_bar, &bar_paint);
+
+        // Warn if the audio thread had to drop events, the capture has gaps then
+        if self.store.read().unwrap().dropped_events() > 0 {
+            let mut warning = vg::Path::new();
+            warning.rect(b.x, b.y, b.w, 3.0);
+            canvas.fill_path(&warning, &vg::Paint::color(self.colors.dropped_warning));
+        }
     }
 
     fn event(&mut self, cx: &mut EventContext, event: &mut Event) {
@@ -355,6 +512,24 @@ impl View for NoteView {
             }
             WindowEvent::MouseMove(x, y) => {
                 self.mouse_pos = Some((*x, *y));
+                if let (Some((s_start, shift)), Ok(window)) =
+                    (self.ghost_drag, self.note_window.read())
+                {
+                    let s = self.store.read().unwrap().samples_at(window.x_to_time(*x) as f64);
+                    self.ghost_shift = shift + s - s_start;
+                }
+                match (self.drag_grab, &self.drag_file) {
+                    (Some((x0, y0)), Some(path)) if (x - x0).hypot(y - y0) > DRAG_THRESHOLD => {
+                        if path.exists() {
+                            nih_dbg!("Dragging {:?}", path);
+                            cx.set_drop_data(DropData::File(path.clone()));
+                        } else {
+                            nih_warn!("Exported file no longer exists: {:?}", path);
+                        }
+                        self.drag_grab = None;
+                    }
+                    _ => {}
+                }
             }
             WindowEvent::MouseLeave => {
                 self.mouse_pos = None;
@@ -362,6 +537,9 @@ impl View for NoteView {
             WindowEvent::MouseDown(button) => {
                 let mouse_x = cx.mouse().cursorx;
                 match *button {
+                    MouseButton::Left if self.handle_at(mouse_x, cx.mouse().cursory) => {
+                        self.drag_grab = Some((mouse_x, cx.mouse().cursory));
+                    }
                     MouseButton::Left => {
                         self.selection = if let Ok(window) = self.note_window.read() {
                             SelectionState::Selecting(window.x_to_time(self.snap(mouse_x)))
@@ -369,14 +547,28 @@ impl View for NoteView {
                             SelectionState::None
                         }
                     }
+                    MouseButton::Right => {
+                        if let Ok(window) = self.note_window.read() {
+                            let t = window.x_to_time(mouse_x) as f64;
+                            let s = self.store.read().unwrap().samples_at(t);
+                            self.ghost_drag = Some((s, self.ghost_shift));
+                        }
+                    }
                     _ => {}
                 }
             }
             WindowEvent::MouseUp(button) => {
+                if *button == MouseButton::Right {
+                    self.ghost_drag = None;
+                }
+                if *button == MouseButton::Left {
+                    self.drag_grab = None;
+                }
                 let mouse_x = cx.mouse().cursorx;
                 match (*button, &self.selection, self.note_window.read()) {
                     (MouseButton::Left, SelectionState::Selecting(t0), Ok(window)) => {
                         let t1 = window.x_to_time(self.snap(mouse_x));
+                        drop(window);
                         self.selection = if (*t0 - t1).abs() > 0.02 {
                             if *t0 < t1 {
                                 SelectionState::Selected(*t0, t1)
@@ -387,15 +579,30 @@ impl View for NoteView {
                             SelectionState::None
                         };
                         if let SelectionState::Selected(t0, t1) = self.selection {
-                            let store = self.store.read().unwrap();
-                            nih_dbg!("Pre-Export transport: {:?}", &store.transport);
-                            self.transfers.new_selection(t0, t1, &store.transport);
+                            self.export(cx, t0, t1);
                             self.t_last_op = t_now;
                         }
                     }
                     _ => {}
                 }
             }
+            WindowEvent::MouseDoubleClick(MouseButton::Left) => {
+                // Selects exactly the loop pass under the cursor, starting at the loop start
+                let t = match self.note_window.read() {
+                    Ok(window) => window.x_to_time(cx.mouse().cursorx),
+                    Err(_) => return,
+                };
+                let pass = self.store.read().unwrap().loop_pass_at(t).copied();
+                if let Some(pass) = pass {
+                    let store = self.store.read().unwrap();
+                    let (t0, t1) = (store.time(pass.t_start), store.time(pass.t_end));
+                    drop(store);
+                    nih_dbg!("Selecting loop pass {}", pass.number);
+                    self.selection = SelectionState::Selected(t0, t1);
+                    self.export(cx, t0, t1);
+                    self.t_last_op = t_now;
+                }
+            }
             WindowEvent::KeyDown(code, key) => {
                 nih_dbg!("Key Down: {:?}, {:?}", code, key);
                 match (key, code) {
@@ -403,6 +610,16 @@ impl View for NoteView {
                         self.snap = SnapMode::Off;
                         self.vscroll = VScrollMode::Pan;
                     }
+                    (_, Code::KeyP) => {
+                        self.note_length = match self.note_length {
+                            NoteLengthMode::KeyUp => NoteLengthMode::Sounding,
+                            NoteLengthMode::Sounding => NoteLengthMode::KeyUp,
+                        };
+                        let mut cstore = self.config.write().unwrap();
+                        let mut cfg = cstore.get_config();
+                        cfg.show_sounding_length = self.note_length == NoteLengthMode::Sounding;
+                        cstore.set_config(&cfg);
+                    }
                     (_, Code::KeyD) => {
                         self.debug_stop.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |val| Some(!val)).unwrap();
                     }
@@ -454,7 +671,36 @@ impl NoteView {
             }
         }
 
-        self.zoom_control.update_time((0.0, t_now + 30.0));
+        // Show the recent export picked in the panel as the selection
+        let highlight = self
+            .transfers
+            .lock()
+            .unwrap()
+            .take_highlight()
+            .map(|recent| (recent.t0, recent.t1, recent.path.clone()));
+        if let Some((t0, t1, path)) = highlight {
+            let margin = 0.1 * (t1 - t0);
+            self.selection = SelectionState::Selected(t0, t1);
+            self.drag_file = Some(path);
+            self.zoom_control.set_range((t0 - margin, t1 + margin));
+            self.t_last_op = t_now;
+        }
+
+        // Captured bars move the ghost grid, the shift only applied to the previous one
+        let anchor = self.store.read().unwrap().ghost_grid().anchor;
+        if anchor != self.ghost_anchor {
+            self.ghost_anchor = anchor;
+            self.ghost_shift = 0;
+        }
+
+        // Captures restored from the project state live before the start of the session
+        let t_min = self
+            .store
+            .read()
+            .unwrap()
+            .time_range()
+            .map_or(0.0, |(t0, _)| t0.min(0.0));
+        self.zoom_control.update_time((t_min, t_now + 30.0));
         self.zoom_control.update(1. / 60.);
 
         // Was the window resized, store the new scale_factor in the config
@@ -469,6 +715,47 @@ impl NoteView {
         }
     }
 
+    /// Exports the selection from `t0` to `t1` in seconds.
+    fn export(&mut self, cx: &mut EventContext, t0: f32, t1: f32) {
+        let store = self.store.read().unwrap();
+        nih_dbg!("Pre-Export transport: {:?}", &store.transport);
+        self.drag_file = self
+            .transfers
+            .lock()
+            .unwrap()
+            .new_selection(t0, t1, &store.transport)
+            .map(Path::to_path_buf);
+        cx.emit(RecentExportsEvent::Exported);
+    }
+
+    /// Rectangle of the handle on top of the completed selection, if it was exported.
+    fn selection_handle(&self, wnd: &NoteWindow) -> Option<BoundingBox> {
+        let SelectionState::Selected(t0, t1) = self.selection else {
+            return None;
+        };
+        self.drag_file.as_ref()?;
+        let (x0, x1) = (wnd.time_to_x_coerced(t0), wnd.time_to_x_coerced(t1));
+        let w = HANDLE_SIZE.0.min(x1 - x0);
+        if w < 1.0 {
+            return None;
+        }
+        Some(BoundingBox {
+            x: 0.5 * (x0 + x1 - w),
+            y: wnd.bounds.y,
+            w,
+            h: HANDLE_SIZE.1,
+        })
+    }
+
+    fn handle_at(&self, x: f32, y: f32) -> bool {
+        let Ok(wnd) = self.note_window.read() else {
+            return false;
+        };
+        self.selection_handle(&wnd).is_some_and(|handle| {
+            (handle.x..handle.x + handle.w).contains(&x) && (handle.y..handle.y + handle.h).contains(&y)
+        })
+    }
+
     fn snap(&self, x: f32) -> f32 {
         match self.snap {
             SnapMode::Off => return x,
@@ -477,10 +764,22 @@ impl NoteView {
 
         let wnd = self.note_window.read().unwrap();
         let tx = wnd.x_to_time(x);
-        if let Some(bar) = self.store.read().unwrap().nearest_bar(tx, 1) {
+        let store = self.store.read().unwrap();
+        let grid = store.ghost_grid().shifted(self.ghost_shift);
+        let (s, bar_length) = (store.samples_at(tx as f64), grid.bar_length as Samples);
+        let ghost_bars = store
+            .ghost_bars_in_samples(grid, s - bar_length, s + bar_length)
+            .map(|s| store.time(s));
+        let nearest = store
+            .nearest_bar(tx, 1)
+            .map(|bar| store.time(bar.t))
+            .into_iter()
+            .chain(ghost_bars)
+            .min_by(|t1, t2| (tx - t1).abs().total_cmp(&(tx - t2).abs()));
+        if let Some(bar_t) = nearest {
             let total = wnd.visible_time.1 - wnd.visible_time.0;
             let max_snap = total * 0.02;
-            let t = if (tx - bar.t).abs() < max_snap { bar.t } else { tx };
+            let t = if (tx - bar_t).abs() < max_snap { bar_t } else { tx };
             wnd.time_to_x(t)
         } else {
             x
@@ -521,8 +820,12 @@ impl NoteWindow {
         self.note_to_phys_coerced(time, 0.).0
     }
 
-    pub fn note_to_rect(&self, note: &Note) -> Option<BoundingBox> {
-        let (t0, t1, key) = (note.t_start, note.t_end, note.key);
+    pub fn note_to_rect(&self, note: &Note, store: &MidiStore) -> Option<BoundingBox> {
+        let (t0, t1) = store.note_time(note);
+        self.span_to_rect(t0, t1, note.key)
+    }
+
+    fn span_to_rect(&self, t0: f32, t1: f32, key: u7) -> Option<BoundingBox> {
         if (t0 > self.visible_time.1)
             || (t1 < self.visible_time.0)
             || (key < self.note_range.0)
@@ -539,10 +842,14 @@ impl NoteWindow {
         Some(BoundingBox::from_min_max(tl.0, tl.1, br.0, br.1))
     }
 
-    pub fn incomplete_note_to_rect(&self, note: &Note, t_now: f32) -> Option<BoundingBox> {
-        let mut n = note.clone();
-        n.t_end = t_now;
-        self.note_to_rect(&n)
+    /// Rectangle of a note until the end of its sound, notes still held by the pedal end at `t_now`.
+    pub fn sounding_note_to_rect(&self, note: &Note, store: &MidiStore, t_now: f32) -> Option<BoundingBox> {
+        let t1 = note.sounding_end.map_or(t_now, |end| store.time(end));
+        self.span_to_rect(store.time(note.t_start), t1, note.key)
+    }
+
+    pub fn incomplete_note_to_rect(&self, note: &Note, store: &MidiStore, t_now: f32) -> Option<BoundingBox> {
+        self.span_to_rect(store.time(note.t_start), t_now, note.key)
     }
 
     pub fn x_to_time(&self, x: f32) -> f32 {
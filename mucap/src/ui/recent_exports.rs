@@ -0,0 +1,141 @@
+//! Collapsible panel listing the recent exports of [`MidiTransfers`].
+//!
+//! Clicking an entry shows its selection in the note view, from the panel the clip can be copied
+//! to the clipboard again or dragged out.
+
+use std::sync::{Arc, Mutex};
+
+use nih_plug_vizia::vizia::prelude::*;
+use nih_plug_vizia::vizia::vg;
+
+use super::miditransfer::{MidiTransfers, RecentExport};
+use super::style::StyleColors;
+
+pub enum RecentExportsEvent {
+    /// A selection was exported, the list needs to be rebuilt.
+    Exported,
+    /// The file of a recent export is gone, the list needs to be pruned.
+    Missing,
+    Toggle,
+    Highlight(usize),
+    Copy(usize),
+}
+
+/// Builds the panel, `generation` changes whenever the list of recent exports did.
+pub fn build<G, E>(cx: &mut Context, generation: G, expanded: E, transfers: Arc<Mutex<MidiTransfers>>)
+where
+    G: Lens<Target = usize>,
+    E: Lens<Target = bool>,
+{
+    VStack::new(cx, |cx| {
+        Button::new(
+            cx,
+            |cx| cx.emit(RecentExportsEvent::Toggle),
+            |cx| Label::new(cx, "Recent exports"),
+        );
+        ScrollView::new(cx, 0.0, 0.0, false, true, move |cx| {
+            Binding::new(cx, generation, move |cx, _| {
+                let transfers = transfers.lock().unwrap();
+                for (idx, recent) in transfers.recent().iter().enumerate() {
+                    entry(cx, idx, recent);
+                }
+            });
+        })
+        .width(Pixels(280.0))
+        .display(expanded);
+    })
+    .width(Auto)
+    .row_between(Pixels(4.0))
+    .child_space(Pixels(4.0));
+}
+
+fn entry(cx: &mut Context, idx: usize, recent: &RecentExport) {
+    let bars = recent
+        .bars
+        .map_or(String::from("No bars"), |(first, last)| format!("Bars {}-{}", first, last));
+    let description = format!(
+        "{}, {} notes, {} BPM",
+        bars,
+        recent.notes.len(),
+        (recent.tempo * 10.).round() / 10.
+    );
+    let path = recent.path.clone();
+
+    HStack::new(cx, move |cx| {
+        ClipPreview::new(cx, recent.notes.clone())
+            .width(Pixels(64.0))
+            .height(Pixels(24.0));
+        Label::new(cx, description).width(Stretch(1.0));
+        Button::new(
+            cx,
+            move |cx| cx.emit(RecentExportsEvent::Copy(idx)),
+            |cx| Label::new(cx, "Copy"),
+        );
+        Label::new(cx, "Drag").on_drag(move |cx| {
+            // The cleanup of another instance sharing the export folder may have removed it
+            if path.exists() {
+                cx.set_drop_data(DropData::File(path.clone()));
+            } else {
+                cx.emit(RecentExportsEvent::Missing);
+            }
+        });
+    })
+    .height(Auto)
+    .col_between(Pixels(4.0))
+    .child_top(Stretch(1.0))
+    .child_bottom(Stretch(1.0))
+    .on_press(move |cx| cx.emit(RecentExportsEvent::Highlight(idx)));
+}
+
+/// Mini piano roll of an exported clip.
+pub struct ClipPreview {
+    /// Notes as (start, end, key), times from 0 to 1 relative to the clip length.
+    notes: Vec<(f32, f32, u8)>,
+    colors: StyleColors,
+}
+
+impl ClipPreview {
+    pub fn new(cx: &mut Context, notes: Vec<(f32, f32, u8)>) -> Handle<'_, Self> {
+        Self {
+            notes,
+            colors: StyleColors::default(),
+        }
+        .build(cx, |_| {})
+    }
+}
+
+impl View for ClipPreview {
+    fn element(&self) -> Option<&'static str> {
+        Some("clip-preview")
+    }
+
+    fn draw(&self, cx: &mut DrawContext, canvas: &mut Canvas) {
+        let b = cx.bounds();
+        if b.w == 0.0 || b.h == 0.0 {
+            return;
+        }
+
+        let mut path = vg::Path::new();
+        path.rect(b.x, b.y, b.w, b.h);
+        canvas.fill_path(&path, &vg::Paint::color(self.colors.bg_dark));
+
+        // Keys are spread over the height, at least an octave so single notes do not fill it
+        let (Some(low), Some(high)) = (
+            self.notes.iter().map(|(_, _, key)| *key).min(),
+            self.notes.iter().map(|(_, _, key)| *key).max(),
+        ) else {
+            return;
+        };
+        let keys = (high - low + 1).max(12) as f32;
+        let center = 0.5 * (low as f32 + high as f32);
+        let key_h = b.h / keys;
+
+        let mut note_path = vg::Path::new();
+        for (start, end, key) in &self.notes {
+            let x = b.x + start * b.w;
+            let y = b.y + (0.5 * keys - (*key as f32 - center) - 0.5) * key_h;
+            note_path.rect(x, y, ((end - start) * b.w).max(1.0), key_h.max(1.0));
+        }
+        canvas.fill_path(&note_path, &vg::Paint::color(self.colors.note_selected_bright));
+    }
+}
@@ -54,6 +54,28 @@ pub struct StyleColors {
 
     // Cursor
     pub cursor: vg::Color,
+
+    // Warning stripe shown when the capture is incomplete
+    pub dropped_warning: vg::Color,
+
+    // Shading where the sustain pedal is down
+    pub pedal_region: vg::Color,
+
+    // Markers for SysEx messages
+    pub sysex_marker: vg::Color,
+
+    // Curves of per-note expressions
+    pub expression_curve: vg::Color,
+
+    // Boundaries between passes of a looping transport
+    pub loop_boundary: vg::Color,
+
+    // Glow of the bars extrapolated while the transport was stopped
+    pub ghost_glow_bright: vg::Color,
+    pub ghost_glow_dim: vg::Color,
+
+    // Handle for dragging the exported selection out of the view
+    pub selection_handle: vg::Color,
 }
 
 impl StyleColors {
@@ -90,6 +112,14 @@ impl StyleColors {
             playhead_opaque: vg::Color::rgba(92, 92, 128, 128),
             playhead_base: vg::Color::rgba(92, 92, 128, 255),
             cursor: vg::Color::rgba(156, 156, 156, 172),
+            dropped_warning: vg::Color::rgba(220, 32, 32, 200),
+            pedal_region: vg::Color::rgba(120, 140, 220, 28),
+            sysex_marker: vg::Color::rgba(230, 180, 60, 200),
+            expression_curve: vg::Color::rgba(120, 220, 200, 200),
+            loop_boundary: vg::Color::rgba(200, 90, 220, 200),
+            ghost_glow_bright: vg::Color::rgba(128, 64, 12, 110),
+            ghost_glow_dim: vg::Color::rgba(128, 64, 12, 0),
+            selection_handle: vg::Color::rgba(64, 255, 16, 140),
         }
     }
 
@@ -133,6 +163,16 @@ impl StyleColors {
 
             // Soft pastel mauve cursor for visibility and variety
             cursor: vg::Color::rgba(180, 140, 160, 220),
+
+            // Muted red warning stripe, still clearly visible on white
+            dropped_warning: vg::Color::rgba(230, 90, 90, 220),
+            pedal_region: vg::Color::rgba(60, 90, 200, 24),
+            sysex_marker: vg::Color::rgba(200, 130, 20, 220),
+            expression_curve: vg::Color::rgba(20, 140, 120, 220),
+            loop_boundary: vg::Color::rgba(150, 60, 170, 220),
+            ghost_glow_bright: vg::Color::rgba(240, 190, 130, 90),
+            ghost_glow_dim: vg::Color::rgba(240, 190, 130, 0),
+            selection_handle: vg::Color::rgba(90, 180, 140, 180),
         }
     }
 }